@@ -25,13 +25,15 @@ enum ParseAction {
     Descend(ParseNode),
 }
 
-/** Build the parse tree. */
+/** Build the parse tree.
+ *
+ * Returns an error if the matchers conflict with an instruction already in the tree. */
 fn build_parse_tree(
     parse_tree: &mut ParseNode,
     matchers: &[(&str, &str)],
     first_field: &str,
     variant: Rc<Variant>,
-) {
+) -> Result<(), &'static str> {
     let mut node = parse_tree;
     let mut prev_value = {
         let (field, value) = matchers[0];
@@ -51,11 +53,13 @@ fn build_parse_tree(
             })
         }) {
             ParseAction::Descend(ref mut next_node) => {
-                assert_eq!(next_node.field, next_field, "parser field order must match");
+                if next_node.field != next_field {
+                    return Err("parser field order must match");
+                }
                 node = next_node;
             }
             ParseAction::Finish(_) => {
-                panic!("parser tried to descend into existing match");
+                return Err("parser tried to descend into existing match");
             }
         }
         prev_value = next_value.to_owned();
@@ -63,43 +67,172 @@ fn build_parse_tree(
     match node.actions.entry(prev_value) {
         Entry::Vacant(entry) => {
             entry.insert(ParseAction::Finish(variant));
+            Ok(())
         }
-        Entry::Occupied(_) => {
-            panic!("conflicting field value in parser");
+        Entry::Occupied(_) => Err("conflicting field value in parser"),
+    }
+}
+
+/** Add an instruction to the parse tree, panicking if it conflicts.
+ *
+ * On conflict, the tree is first dumped as described at `dump_parse_tree`, with the matchers of
+ * the conflicting instruction highlighted. */
+fn add_to_parse_tree(
+    parse_tree: &mut ParseNode,
+    matchers: &[(&str, &str)],
+    first_field: &str,
+    variant: Rc<Variant>,
+    dot_name: &str,
+) {
+    let method = variant.method.clone();
+    if let Err(msg) = build_parse_tree(parse_tree, matchers, first_field, variant) {
+        dump_parse_tree(parse_tree, dot_name, Some((matchers, &method)));
+        panic!("{} for instruction {}", msg, method);
+    }
+}
+
+/** Write the parse tree as a graphviz diagram, if `RVSIM_PARSE_TREE_DOT` is set.
+ *
+ * The variable names a directory, relative to the package root, where `dot_name` is created.
+ * Nodes are identified by the matchers leading up to them, so the matchers of a conflicting
+ * instruction, drawn in red, share the nodes where they agree with the tree, and branch off where
+ * a field value conflicts or the field order diverges. */
+fn dump_parse_tree(
+    parse_tree: &ParseNode,
+    dot_name: &str,
+    conflict: Option<(&[(&str, &str)], &str)>,
+) {
+    let dir = match env::var_os("RVSIM_PARSE_TREE_DOT") {
+        Some(dir) => PathBuf::from(dir),
+        None => return,
+    };
+
+    fn node_dot(node: &ParseNode, prefix: &str, out: &mut String) {
+        let id = format!("{}{}", prefix, node.field);
+        writeln!(out, "    \"{}\" [label=\"{}\"];", id, node.field).unwrap();
+        let mut items = node.actions.iter().collect::<Vec<_>>();
+        items.sort_by_key(|(k, _)| *k);
+        for (value, action) in items {
+            let prefix = format!("{}{}={} ", prefix, node.field, value);
+            let child = match action {
+                ParseAction::Descend(ref child) => {
+                    node_dot(child, &prefix, out);
+                    format!("{}{}", prefix, child.field)
+                }
+                ParseAction::Finish(ref variant) => {
+                    let child = format!("{}{}", prefix, variant.method);
+                    writeln!(
+                        out,
+                        "    \"{}\" [label=\"{}\", shape=box];",
+                        child, variant.method
+                    )
+                    .unwrap();
+                    child
+                }
+            };
+            writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                id, child, value
+            )
+            .unwrap();
         }
     }
+
+    let mut out = String::from("digraph parse_tree {\n");
+    node_dot(parse_tree, "", &mut out);
+    if let Some((matchers, method)) = conflict {
+        let mut prefix = String::new();
+        for (i, &(field, value)) in matchers.iter().enumerate() {
+            let id = format!("{}{}", prefix, field);
+            writeln!(out, "    \"{}\" [label=\"{}\", color=red];", id, field).unwrap();
+            prefix = format!("{}{}={} ", prefix, field, value);
+            let next = match matchers.get(i + 1) {
+                Some(&(next_field, _)) => format!("{}{}", prefix, next_field),
+                None => {
+                    let leaf = format!("{}{}", prefix, method);
+                    writeln!(
+                        out,
+                        "    \"{}\" [label=\"{}\", shape=box, color=red];",
+                        leaf, method
+                    )
+                    .unwrap();
+                    leaf
+                }
+            };
+            writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{}\", color=red];",
+                id, next, value
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("}\n");
+
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(dot_name), out).unwrap();
+}
+
+/** Determine the register file of an instruction argument.
+ *
+ * Returns `None` if the argument is not a register. Floating-point instructions use `f` registers,
+ * except for the integer side of loads, stores, moves, conversions, comparisons and `fclass`. */
+fn reg_file(method: &str, arg: &str) -> Option<&'static str> {
+    if !matches!(arg, "rd" | "rs1" | "rs2" | "rs3") {
+        return None;
+    }
+    let parts = method.split('_').collect::<Vec<_>>();
+    let operand = parts.get(if arg == "rd" { 1 } else { 2 }).copied();
+    let is_int = match parts[0] {
+        // Address register of loads and stores.
+        "flw" | "fld" | "fsw" | "fsd" => arg == "rs1",
+        // Moves and conversions are named `<dest format>_<source format>`. Moves use `x` for
+        // integers, while conversions use `w` and `wu`.
+        "fmv" => operand == Some("x"),
+        "fcvt" => matches!(operand, Some("w" | "wu")),
+        // Comparisons and classification produce an integer.
+        "feq" | "flt" | "fle" | "fclass" => arg == "rd",
+        op => !op.starts_with('f') || op == "fence",
+    };
+    Some(if is_int { "X" } else { "F" })
 }
 
-// Struct for selectively skipping rv32fd opcodes if rv32fd feature is disabled
+/** Selectively skips lines in blocks for extensions whose feature is disabled.
+ *
+ * A block is opened with `//[feature{` and closed with `//]feature}`, where `feature` is the
+ * name of a Cargo feature. Blocks may be nested, in which case lines are skipped if any of the
+ * enclosing features is disabled. The marker lines themselves are always skipped. */
 struct SkipDisabled {
-    #[cfg(not(feature = "rv32fd"))]
-    only_rv32fd: bool,
+    // name, enabled
+    blocks: Vec<(String, bool)>,
 }
 
 impl SkipDisabled {
     pub fn new() -> Self {
-        Self {
-            #[cfg(not(feature = "rv32fd"))]
-            only_rv32fd: false,
-        }
+        Self { blocks: vec![] }
     }
 
-    #[cfg(not(feature = "rv32fd"))]
     pub fn do_skip(&mut self, line: &str) -> bool {
-        if line.starts_with("//f{") {
-            self.only_rv32fd = true;
-            true
-        } else if line.starts_with("//f}") {
-            self.only_rv32fd = false;
+        if let Some(name) = line.strip_prefix("//[").and_then(|s| s.strip_suffix('{')) {
+            let var = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+            self.blocks
+                .push((name.to_owned(), env::var_os(var).is_some()));
             true
+        } else if let Some(name) = line.strip_prefix("//]").and_then(|s| s.strip_suffix('}')) {
+            match self.blocks.pop() {
+                Some((open, _)) if open == name => true,
+                _ => panic!("unbalanced extension block end: {}", name),
+            }
         } else {
-            self.only_rv32fd
+            self.blocks.iter().any(|&(_, enabled)| !enabled)
         }
     }
 
-    #[cfg(feature = "rv32fd")]
-    pub fn do_skip(&mut self, _line: &str) -> bool {
-        false
+    pub fn finish(&self) {
+        if let Some((name, _)) = self.blocks.last() {
+            panic!("unterminated extension block: {}", name);
+        }
     }
 }
 
@@ -158,7 +291,13 @@ pub fn build() {
                 .map(|s| s.split_once('=').unwrap())
                 .collect::<Vec<_>>();
 
-            build_parse_tree(&mut parse_tree, &matchers, "opcode", variant);
+            add_to_parse_tree(
+                &mut parse_tree,
+                &matchers,
+                "opcode",
+                variant,
+                "parse_tree.dot",
+            );
         }
 
         // Parsing for decompression
@@ -202,30 +341,246 @@ pub fn build() {
                 args,
             });
 
-            build_parse_tree(&mut parse_tree_c, &matchers, "cquad", variant);
+            add_to_parse_tree(
+                &mut parse_tree_c,
+                &matchers,
+                "cquad",
+                variant,
+                "parse_tree_c.dot",
+            );
         }
 
         prev = line;
     }
+    skipper.finish();
+    dump_parse_tree(&parse_tree, "parse_tree.dot", None);
+    dump_parse_tree(&parse_tree_c, "parse_tree_c.dot", None);
+
+    // Check every variant can be decoded, and every compressed instruction decompresses to a
+    // known variant. Dispatch has no wildcard arm, so the compiler already checks every variant
+    // has a method.
+    fn collect_finished<'a>(node: &'a ParseNode, out: &mut Vec<&'a Variant>) {
+        for action in node.actions.values() {
+            match action {
+                ParseAction::Descend(ref child) => collect_finished(child, out),
+                ParseAction::Finish(ref variant) => out.push(variant),
+            }
+        }
+    }
+    let mut decoded = vec![];
+    collect_finished(&parse_tree, &mut decoded);
+    for variant in &variants {
+        assert!(
+            decoded.iter().any(|v| v.name == variant.name),
+            "instruction {} cannot be decoded",
+            variant.method
+        );
+    }
+    let mut decompressed = vec![];
+    collect_finished(&parse_tree_c, &mut decompressed);
+    for variant in decompressed {
+        assert!(
+            variant.method == "illegal" || variants.iter().any(|v| v.name == variant.name),
+            "compressed instruction decompresses to unknown instruction {}",
+            variant.method
+        );
+    }
 
     // Generate `Op` variants source code.
     let mut variants_src = String::new();
     for variant in &variants {
-        let &Variant {
-            ref name, ref args, ..
-        } = &**variant;
+        let Variant { name, args, .. } = &**variant;
         if args.is_empty() {
             writeln!(variants_src, "    {},", name).unwrap();
         } else {
             let field_src = args
                 .iter()
-                .map(|&(ref name, _, ref typ)| format!("{}: {}", name, typ))
+                .map(|(name, _, typ)| format!("{}: {}", name, typ))
                 .collect::<Vec<_>>()
                 .join(", ");
             writeln!(variants_src, "    {} {{ {} }},", name, field_src).unwrap();
         }
     }
 
+    // Generate `OpKind` variants and `Op::kind` source code.
+    let mut kinds_src = String::new();
+    let mut kind_src = String::new();
+    for variant in &variants {
+        let Variant { name, args, .. } = &**variant;
+        let pattern = if args.is_empty() { "" } else { " { .. }" };
+        writeln!(kinds_src, "    {},", name).unwrap();
+        writeln!(
+            kind_src,
+            "            Op::{}{} => OpKind::{},",
+            name, pattern, name
+        )
+        .unwrap();
+    }
+
+    // Generate `Op::name` source code.
+    let mut names_src = String::new();
+    for variant in &variants {
+        let Variant { name, method, args } = &**variant;
+        let pattern = if args.is_empty() { "" } else { " { .. }" };
+        writeln!(
+            names_src,
+            "            Op::{}{} => \"{}\",",
+            name,
+            pattern,
+            method.replace('_', ".")
+        )
+        .unwrap();
+    }
+
+    // Generate `Op::reads` and `Op::writes` source code.
+    let mut reads_src = String::new();
+    let mut writes_src = String::new();
+    for variant in &variants {
+        let Variant { name, method, args } = &**variant;
+        let regs = args
+            .iter()
+            .filter_map(|(arg, _, _)| reg_file(method, arg).map(|file| (arg.as_str(), file)))
+            .collect::<Vec<_>>();
+        let bind = |args: &[&(&str, &str)]| {
+            if args.is_empty() {
+                if variant.args.is_empty() {
+                    "".to_owned()
+                } else {
+                    " { .. }".to_owned()
+                }
+            } else {
+                let names = args.iter().map(|(arg, _)| *arg).collect::<Vec<_>>();
+                format!(" {{ {}, .. }}", names.join(", "))
+            }
+        };
+        let reads = regs
+            .iter()
+            .filter(|(arg, _)| *arg != "rd")
+            .collect::<Vec<_>>();
+        // Reads are padded to a fixed size, so `Op::reads` does not allocate.
+        assert!(
+            reads.len() <= 3,
+            "instruction {} reads too many registers",
+            method
+        );
+        let reads_list = reads
+            .iter()
+            .map(|(arg, file)| format!("Some(Reg::{}({}))", file, arg))
+            .chain(std::iter::repeat("None".to_owned()))
+            .take(3)
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            reads_src,
+            "            Op::{}{} => [{}],",
+            name,
+            bind(&reads),
+            reads_list
+        )
+        .unwrap();
+        let writes = regs
+            .iter()
+            .filter(|(arg, _)| *arg == "rd")
+            .collect::<Vec<_>>();
+        let writes_value = match writes.first() {
+            Some((arg, file)) => format!("Some(Reg::{}({}))", file, arg),
+            None => "None".to_owned(),
+        };
+        writeln!(
+            writes_src,
+            "            Op::{}{} => {},",
+            name,
+            bind(&writes),
+            writes_value
+        )
+        .unwrap();
+    }
+
+    // Generate `Display` source code for `Op`.
+    let mut display_src = String::new();
+    for variant in &variants {
+        let Variant { name, method, args } = &**variant;
+        let names = args
+            .iter()
+            .map(|(arg, _, _)| arg.as_str())
+            .collect::<Vec<_>>();
+        let has = |arg: &str| names.contains(&arg);
+        let reg = |arg: &str| format!("Reg::{}({})", reg_file(method, arg).unwrap(), arg);
+
+        // Build a format string and its arguments, starting with the mnemonic.
+        let mut fmt = method.replace('_', ".");
+        let mut exprs = vec![];
+        if has("aq") {
+            fmt.push_str("{}");
+            exprs.push("aqrl(aq, rl)".to_owned());
+        }
+        let operands: Vec<(&str, String)> = if has("s_imm") {
+            vec![(
+                "{}, {}({})",
+                format!("{}, s_imm, {}", reg("rs2"), reg("rs1")),
+            )]
+        } else if (has("i_imm") && (method.starts_with("fl") || method.starts_with('l')))
+            || method == "jalr"
+        {
+            vec![(
+                "{}, {}({})",
+                format!("{}, i_imm, {}", reg("rd"), reg("rs1")),
+            )]
+        } else if method == "lr_w" {
+            vec![("{}, ({})", format!("{}, {}", reg("rd"), reg("rs1")))]
+        } else if has("aq") {
+            let regs = format!("{}, {}, {}", reg("rd"), reg("rs2"), reg("rs1"));
+            vec![("{}, {}, ({})", regs)]
+        } else if method == "fence" {
+            vec![("{}, {}", "fence_set(pred), fence_set(succ)".to_owned())]
+        } else if has("csr") {
+            // The CSR comes before the source operand in assembler syntax.
+            let src = if has("zimm") {
+                "zimm".to_owned()
+            } else {
+                reg("rs1")
+            };
+            vec![("{}", reg("rd")), ("{:#x}", "csr".to_owned()), ("{}", src)]
+        } else {
+            names
+                .iter()
+                .map(|&arg| match arg {
+                    "rm" => ("{}", "rm_operand(rm)".to_owned()),
+                    "u_imm" => ("{:#x}", "(u_imm as u32) >> 12".to_owned()),
+                    _ if reg_file(method, arg).is_some() => ("{}", reg(arg)),
+                    _ => ("{}", arg.to_owned()),
+                })
+                .collect()
+        };
+        for (i, (op_fmt, expr)) in operands.iter().enumerate() {
+            // The rounding mode operand includes its own separator.
+            let sep = match (i, *op_fmt, expr.as_str()) {
+                (_, _, "rm_operand(rm)") => "",
+                (0, _, _) => " ",
+                _ => ", ",
+            };
+            fmt.push_str(sep);
+            fmt.push_str(op_fmt);
+            exprs.push(expr.clone());
+        }
+
+        let pattern = if names.is_empty() {
+            "".to_owned()
+        } else {
+            format!(" {{ {} }}", names.join(", "))
+        };
+        let exprs = exprs
+            .iter()
+            .map(|expr| format!(", {}", expr))
+            .collect::<String>();
+        writeln!(
+            display_src,
+            "            Op::{}{} => write!(f, \"{}\"{}),",
+            name, pattern, fmt, exprs
+        )
+        .unwrap();
+    }
+
     // Generate `Op::parse` source code.
     fn node_parse_src(node: &ParseNode, indent: usize) -> String {
         let spaces = " ".repeat(indent);
@@ -250,7 +605,7 @@ pub fn build() {
                         src.push_str(&format!("{}        None\n", spaces));
                     } else {
                         src.push_str(&format!("{}        Some(Op::{} {{\n", spaces, variant.name));
-                        for &(ref name, ref extract, _) in &variant.args {
+                        for (name, extract, _) in &variant.args {
                             src.push_str(&format!(
                                 "{}            {}: {}(instr),\n",
                                 spaces, name, extract
@@ -277,14 +632,10 @@ pub fn build() {
     let mut dispatch_src = String::new();
     let spaces = " ".repeat(12);
     for variant in &variants {
-        let &Variant {
-            ref name,
-            ref method,
-            ref args,
-        } = &**variant;
+        let Variant { name, method, args } = &**variant;
         let params = args
             .iter()
-            .map(|&(ref name, _, _)| name.as_str())
+            .map(|(name, _, _)| name.as_str())
             .collect::<Vec<_>>()
             .join(", ");
         let pattern = if params.is_empty() {
@@ -309,6 +660,12 @@ pub fn build() {
             "//% variants" => file.write_all(variants_src.as_bytes()),
             "//% parse" => file.write_all(parse_src.as_bytes()),
             "//% parse_c" => file.write_all(parse_c_src.as_bytes()),
+            "//% names" => file.write_all(names_src.as_bytes()),
+            "//% kinds" => file.write_all(kinds_src.as_bytes()),
+            "//% kind" => file.write_all(kind_src.as_bytes()),
+            "//% reads" => file.write_all(reads_src.as_bytes()),
+            "//% writes" => file.write_all(writes_src.as_bytes()),
+            "//% display" => file.write_all(display_src.as_bytes()),
             _ => writeln!(file, "{}", line),
         }
         .unwrap();
@@ -332,4 +689,5 @@ pub fn build() {
         }
         .unwrap();
     }
+    skipper.finish();
 }
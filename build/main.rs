@@ -6,6 +6,13 @@ mod cpu;
 mod softfloat;
 
 fn main() {
+    // The `cc` crate emits `rerun-if-env-changed`, which disables the default of rerunning when
+    // any file in the package changes, so list our inputs explicitly.
+    println!("cargo:rerun-if-changed=build");
+    println!("cargo:rerun-if-changed=src/cpu/interp.in.rs");
+    println!("cargo:rerun-if-changed=src/cpu/op.in.rs");
+    println!("cargo:rerun-if-env-changed=RVSIM_PARSE_TREE_DOT");
+
     cpu::build();
     #[cfg(feature = "rv32fd")]
     softfloat::build();
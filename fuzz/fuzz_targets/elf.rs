@@ -0,0 +1,20 @@
+//! Feed arbitrary bytes to the ELF parser and loaders, which must reject bad input without
+//! panicking. `tests/elf.rs` has a deterministic version of this as a regression check.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rvsim::elf;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = vec![0u8; 0x10_0000];
+    let mut mem = &mut buf[..];
+    let _ = elf::load_streaming(Cursor::new(data), &mut mem);
+
+    if let Ok(elf) = elf::Elf32::parse(data) {
+        let _ = elf.load_into(&mut mem, 0, true);
+        let _ = elf::apply_relocations(&elf, &mut mem, 0);
+        let _ = elf.symbolize(0);
+    }
+});
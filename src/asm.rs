@@ -0,0 +1,427 @@
+//! A small builder for assembling rv32i programs without an external toolchain.
+//!
+//! This is mostly useful in tests, to construct programs in readable Rust:
+//!
+//! ```
+//! use rvsim::asm::AsmBuilder;
+//! use rvsim::{CpuError, CpuState, Interp, SimpleClock};
+//!
+//! let mut asm = AsmBuilder::new();
+//! asm.addi(10, 0, 0) // a0 = 0
+//!     .addi(11, 0, 5) // a1 = 5
+//!     .label("loop")
+//!     .add(10, 10, 11) // a0 += a1
+//!     .addi(11, 11, -1) // a1 -= 1
+//!     .bne(11, 0, "loop")
+//!     .ebreak();
+//! let mut dram = asm.build().unwrap();
+//! let mut mem = &mut dram[..];
+//!
+//! let mut state = CpuState::new(0);
+//! let mut clock = SimpleClock::new();
+//! let (err, _) = Interp::new(&mut state, &mut mem, &mut clock).run();
+//! assert_eq!(err, CpuError::Ebreak);
+//! assert_eq!(state.x[10], 15);
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// An error resolving labels, returned by `AsmBuilder::build`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AsmError {
+    /// A branch or jump refers to a label that was never defined.
+    UndefinedLabel(String),
+    /// A label was defined more than once.
+    DuplicateLabel(String),
+    /// A branch or jump target is too far away to encode.
+    OutOfRange {
+        /// The target label.
+        label: String,
+        /// Offset of the label relative to the instruction, in bytes.
+        offset: i32,
+    },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsmError::UndefinedLabel(ref label) => write!(f, "undefined label {:?}", label),
+            AsmError::DuplicateLabel(ref label) => write!(f, "duplicate label {:?}", label),
+            AsmError::OutOfRange { ref label, offset } => {
+                write!(f, "label {:?} out of range (offset {})", label, offset)
+            }
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+/// The kind of label reference, which determines how the offset is encoded.
+#[derive(Clone, Copy, Debug)]
+enum Fixup {
+    /// A B-type branch, with a 13-bit offset.
+    Branch,
+    /// A J-type jump, with a 21-bit offset.
+    Jump,
+}
+
+/// Builds an rv32i program, one instruction at a time.
+///
+/// Registers are given by index, so `10` is `a0`. Instruction methods return the builder, so calls
+/// can be chained. Branches and jumps refer to labels by name, which may be defined before or after
+/// the reference. Labels are resolved by `build`.
+///
+/// Immediates are checked when the instruction is added, and the method panics if an immediate or
+/// register index does not fit the encoding.
+#[derive(Clone, Debug, Default)]
+pub struct AsmBuilder {
+    words: Vec<u32>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<(usize, Fixup, String)>,
+    duplicate: Option<String>,
+}
+
+fn reg(r: usize) -> u32 {
+    assert!(r < 32, "register index out of range: {}", r);
+    r as u32
+}
+
+fn imm12(imm: i32) -> u32 {
+    assert!(
+        (-2048..2048).contains(&imm),
+        "immediate out of range: {}",
+        imm
+    );
+    (imm as u32) & 0xfff
+}
+
+fn shamt(shamt: u32) -> u32 {
+    assert!(shamt < 32, "shift amount out of range: {}", shamt);
+    shamt
+}
+
+fn b_imm(offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 12) & 1) << 31
+        | ((imm >> 5) & 0x3f) << 25
+        | ((imm >> 1) & 0xf) << 8
+        | ((imm >> 11) & 1) << 7
+}
+
+fn j_imm(offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 20) & 1) << 31
+        | ((imm >> 1) & 0x3ff) << 21
+        | ((imm >> 11) & 1) << 20
+        | ((imm >> 12) & 0xff) << 12
+}
+
+impl AsmBuilder {
+    /// Create an empty program.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current offset in bytes from the start of the program.
+    pub fn offset(&self) -> u32 {
+        4 * self.words.len() as u32
+    }
+
+    /// Define a label at the current offset.
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        if self
+            .labels
+            .insert(name.to_owned(), self.words.len())
+            .is_some()
+        {
+            self.duplicate.get_or_insert_with(|| name.to_owned());
+        }
+        self
+    }
+
+    /// Append a raw instruction word.
+    pub fn word(&mut self, word: u32) -> &mut Self {
+        self.words.push(word);
+        self
+    }
+
+    /// Resolve labels, and return the program as little-endian bytes.
+    pub fn build(&self) -> Result<Vec<u8>, AsmError> {
+        if let Some(ref label) = self.duplicate {
+            return Err(AsmError::DuplicateLabel(label.clone()));
+        }
+        let mut words = self.words.clone();
+        for &(index, kind, ref label) in &self.fixups {
+            let target = *self
+                .labels
+                .get(label)
+                .ok_or_else(|| AsmError::UndefinedLabel(label.clone()))?;
+            let offset = 4 * (target as i32 - index as i32);
+            let (bits, imm) = match kind {
+                Fixup::Branch => (13, b_imm(offset)),
+                Fixup::Jump => (21, j_imm(offset)),
+            };
+            if offset < -(1 << (bits - 1)) || offset >= 1 << (bits - 1) {
+                return Err(AsmError::OutOfRange {
+                    label: label.clone(),
+                    offset,
+                });
+            }
+            words[index] |= imm;
+        }
+        Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    fn r_type(&mut self, funct7: u32, rs2: usize, rs1: usize, funct3: u32, rd: usize) -> &mut Self {
+        self.word(
+            funct7 << 25 | reg(rs2) << 20 | reg(rs1) << 15 | funct3 << 12 | reg(rd) << 7 | 0x33,
+        )
+    }
+
+    fn i_type(&mut self, imm: u32, rs1: usize, funct3: u32, rd: usize, opcode: u32) -> &mut Self {
+        self.word(imm << 20 | reg(rs1) << 15 | funct3 << 12 | reg(rd) << 7 | opcode)
+    }
+
+    fn s_type(&mut self, offset: i32, rs2: usize, rs1: usize, funct3: u32) -> &mut Self {
+        let imm = imm12(offset);
+        self.word(
+            (imm >> 5) << 25
+                | reg(rs2) << 20
+                | reg(rs1) << 15
+                | funct3 << 12
+                | (imm & 0x1f) << 7
+                | 0x23,
+        )
+    }
+
+    fn branch(&mut self, funct3: u32, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        let index = self.words.len();
+        self.fixups.push((index, Fixup::Branch, label.to_owned()));
+        self.word(reg(rs2) << 20 | reg(rs1) << 15 | funct3 << 12 | 0x63)
+    }
+
+    /// `lui rd, imm`, where `imm` is the upper 20 bits.
+    pub fn lui(&mut self, rd: usize, imm: u32) -> &mut Self {
+        assert!(imm < 1 << 20, "immediate out of range: {:#x}", imm);
+        self.word(imm << 12 | reg(rd) << 7 | 0x37)
+    }
+
+    /// `auipc rd, imm`, where `imm` is the upper 20 bits.
+    pub fn auipc(&mut self, rd: usize, imm: u32) -> &mut Self {
+        assert!(imm < 1 << 20, "immediate out of range: {:#x}", imm);
+        self.word(imm << 12 | reg(rd) << 7 | 0x17)
+    }
+
+    /// `jal rd, label`
+    pub fn jal(&mut self, rd: usize, label: &str) -> &mut Self {
+        let index = self.words.len();
+        self.fixups.push((index, Fixup::Jump, label.to_owned()));
+        self.word(reg(rd) << 7 | 0x6f)
+    }
+
+    /// `jalr rd, offset(rs1)`
+    pub fn jalr(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b000, rd, 0x67)
+    }
+
+    /// `beq rs1, rs2, label`
+    pub fn beq(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b000, rs1, rs2, label)
+    }
+
+    /// `bne rs1, rs2, label`
+    pub fn bne(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b001, rs1, rs2, label)
+    }
+
+    /// `blt rs1, rs2, label`
+    pub fn blt(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b100, rs1, rs2, label)
+    }
+
+    /// `bge rs1, rs2, label`
+    pub fn bge(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b101, rs1, rs2, label)
+    }
+
+    /// `bltu rs1, rs2, label`
+    pub fn bltu(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b110, rs1, rs2, label)
+    }
+
+    /// `bgeu rs1, rs2, label`
+    pub fn bgeu(&mut self, rs1: usize, rs2: usize, label: &str) -> &mut Self {
+        self.branch(0b111, rs1, rs2, label)
+    }
+
+    /// `lb rd, offset(rs1)`
+    pub fn lb(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b000, rd, 0x03)
+    }
+
+    /// `lh rd, offset(rs1)`
+    pub fn lh(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b001, rd, 0x03)
+    }
+
+    /// `lw rd, offset(rs1)`
+    pub fn lw(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b010, rd, 0x03)
+    }
+
+    /// `lbu rd, offset(rs1)`
+    pub fn lbu(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b100, rd, 0x03)
+    }
+
+    /// `lhu rd, offset(rs1)`
+    pub fn lhu(&mut self, rd: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.i_type(imm12(offset), rs1, 0b101, rd, 0x03)
+    }
+
+    /// `sb rs2, offset(rs1)`
+    pub fn sb(&mut self, rs2: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.s_type(offset, rs2, rs1, 0b000)
+    }
+
+    /// `sh rs2, offset(rs1)`
+    pub fn sh(&mut self, rs2: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.s_type(offset, rs2, rs1, 0b001)
+    }
+
+    /// `sw rs2, offset(rs1)`
+    pub fn sw(&mut self, rs2: usize, rs1: usize, offset: i32) -> &mut Self {
+        self.s_type(offset, rs2, rs1, 0b010)
+    }
+
+    /// `addi rd, rs1, imm`
+    pub fn addi(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b000, rd, 0x13)
+    }
+
+    /// `slti rd, rs1, imm`
+    pub fn slti(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b010, rd, 0x13)
+    }
+
+    /// `sltiu rd, rs1, imm`
+    pub fn sltiu(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b011, rd, 0x13)
+    }
+
+    /// `xori rd, rs1, imm`
+    pub fn xori(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b100, rd, 0x13)
+    }
+
+    /// `ori rd, rs1, imm`
+    pub fn ori(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b110, rd, 0x13)
+    }
+
+    /// `andi rd, rs1, imm`
+    pub fn andi(&mut self, rd: usize, rs1: usize, imm: i32) -> &mut Self {
+        self.i_type(imm12(imm), rs1, 0b111, rd, 0x13)
+    }
+
+    /// `slli rd, rs1, shamt`
+    pub fn slli(&mut self, rd: usize, rs1: usize, shamt: u32) -> &mut Self {
+        self.i_type(self::shamt(shamt), rs1, 0b001, rd, 0x13)
+    }
+
+    /// `srli rd, rs1, shamt`
+    pub fn srli(&mut self, rd: usize, rs1: usize, shamt: u32) -> &mut Self {
+        self.i_type(self::shamt(shamt), rs1, 0b101, rd, 0x13)
+    }
+
+    /// `srai rd, rs1, shamt`
+    pub fn srai(&mut self, rd: usize, rs1: usize, shamt: u32) -> &mut Self {
+        self.i_type(0x400 | self::shamt(shamt), rs1, 0b101, rd, 0x13)
+    }
+
+    /// `add rd, rs1, rs2`
+    pub fn add(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b000, rd)
+    }
+
+    /// `sub rd, rs1, rs2`
+    pub fn sub(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b010_0000, rs2, rs1, 0b000, rd)
+    }
+
+    /// `sll rd, rs1, rs2`
+    pub fn sll(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b001, rd)
+    }
+
+    /// `slt rd, rs1, rs2`
+    pub fn slt(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b010, rd)
+    }
+
+    /// `sltu rd, rs1, rs2`
+    pub fn sltu(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b011, rd)
+    }
+
+    /// `xor rd, rs1, rs2`
+    pub fn xor(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b100, rd)
+    }
+
+    /// `srl rd, rs1, rs2`
+    pub fn srl(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b101, rd)
+    }
+
+    /// `sra rd, rs1, rs2`
+    pub fn sra(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b010_0000, rs2, rs1, 0b101, rd)
+    }
+
+    /// `or rd, rs1, rs2`
+    pub fn or(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b110, rd)
+    }
+
+    /// `and rd, rs1, rs2`
+    pub fn and(&mut self, rd: usize, rs1: usize, rs2: usize) -> &mut Self {
+        self.r_type(0b000_0000, rs2, rs1, 0b111, rd)
+    }
+
+    /// `ecall`
+    pub fn ecall(&mut self) -> &mut Self {
+        self.word(0x0000_0073)
+    }
+
+    /// `ebreak`
+    pub fn ebreak(&mut self) -> &mut Self {
+        self.word(0x0010_0073)
+    }
+
+    /// `nop`, an alias for `addi x0, x0, 0`.
+    pub fn nop(&mut self) -> &mut Self {
+        self.addi(0, 0, 0)
+    }
+
+    /// `j label`, an alias for `jal x0, label`.
+    pub fn j(&mut self, label: &str) -> &mut Self {
+        self.jal(0, label)
+    }
+
+    /// `li rd, imm`, expanding to `addi`, or to `lui` followed by `addi` for large values.
+    pub fn li(&mut self, rd: usize, imm: i32) -> &mut Self {
+        if (-2048..2048).contains(&imm) {
+            return self.addi(rd, 0, imm);
+        }
+        let lo = (imm << 20) >> 20;
+        let hi = (imm.wrapping_sub(lo) as u32) >> 12;
+        self.lui(rd, hi);
+        if lo != 0 {
+            self.addi(rd, rd, lo);
+        }
+        self
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::cpu::interp::Interp;
+use crate::cpu::op::Op;
+use crate::cpu::types::{Clock, CpuError, CpuState, Memory};
+
+/// The result of a single `Interp::step`.
+pub type StepResult = Result<Op, (CpuError, Option<Op>)>;
+
+/// The first point where two interpreters disagree, returned by `compare_run`.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Address of the instruction after which the interpreters diverged.
+    pub pc: u32,
+    /// Result of the step of the first interpreter.
+    pub a_result: StepResult,
+    /// Result of the step of the second interpreter.
+    pub b_result: StepResult,
+    /// State of the first interpreter after the step.
+    pub a: CpuState,
+    /// State of the second interpreter after the step.
+    pub b: CpuState,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "divergence after instruction at {:#x}", self.pc)?;
+        if self.a_result != self.b_result {
+            write!(f, ", result {:?} != {:?}", self.a_result, self.b_result)?;
+        }
+        if self.a.pc != self.b.pc {
+            write!(f, ", pc {:#x} != {:#x}", self.a.pc, self.b.pc)?;
+        }
+        for (i, (a, b)) in self.a.x.iter().zip(&self.b.x).enumerate() {
+            if a != b {
+                write!(f, ", x{} {:#x} != {:#x}", i, a, b)?;
+            }
+        }
+        #[cfg(feature = "rv32fd")]
+        for (i, (a, b)) in self.a.f.iter().zip(&self.b.f).enumerate() {
+            if a != b {
+                write!(f, ", f{} {:#x} != {:#x}", i, a.0, b.0)?;
+            }
+        }
+        if self.a.fcsr != self.b.fcsr {
+            write!(f, ", fcsr {:#x} != {:#x}", self.a.fcsr, self.b.fcsr)?;
+        }
+        if self.a.reservation != self.b.reservation {
+            write!(
+                f,
+                ", reservation {:x?} != {:x?}",
+                self.a.reservation, self.b.reservation
+            )?;
+        }
+        let (a, b) = (&self.a.machine, &self.b.machine);
+        let csrs = [
+            ("mstatus", a.mstatus, b.mstatus),
+            ("mie", a.mie, b.mie),
+            ("mip", a.mip, b.mip),
+            ("mtvec", a.mtvec, b.mtvec),
+            ("mscratch", a.mscratch, b.mscratch),
+            ("mepc", a.mepc, b.mepc),
+            ("mcause", a.mcause, b.mcause),
+            ("mtval", a.mtval, b.mtval),
+            #[cfg(feature = "big-endian")]
+            ("mstatush", a.mstatush, b.mstatush),
+            ("mcountinhibit", a.mcountinhibit, b.mcountinhibit),
+        ];
+        for (name, a, b) in csrs {
+            if a != b {
+                write!(f, ", {} {:#x} != {:#x}", name, a, b)?;
+            }
+        }
+        for (i, (a, b)) in a.mhpmevent.iter().zip(&b.mhpmevent).enumerate() {
+            if a != b {
+                write!(f, ", mhpmevent{} {:#x} != {:#x}", i + 3, a, b)?;
+            }
+        }
+        for (i, (a, b)) in a.mhpmcounter.iter().zip(&b.mhpmcounter).enumerate() {
+            if a != b {
+                write!(f, ", mhpmcounter{} {:#x} != {:#x}", i + 3, a, b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for Divergence {}
+
+/// Run two interpreters in lock-step, stopping at the first architectural divergence.
+///
+/// Both interpreters are stepped one instruction at a time, after which the step results and the
+/// complete `CpuState` are compared. This is useful to validate that two configurations, such as
+/// different memory implementations or feature sets, behave identically on the same program.
+///
+/// Returns `Ok` when both interpreters stop for the same reason in the same state. Memory is not
+/// compared, but any difference in loaded values shows up in the state. The `Divergence` is boxed,
+/// because it holds two complete states.
+pub fn compare_run<M1, C1, M2, C2>(
+    a: &mut Interp<M1, C1>,
+    b: &mut Interp<M2, C2>,
+) -> Result<(), Box<Divergence>>
+where
+    M1: Memory,
+    C1: Clock,
+    M2: Memory,
+    C2: Clock,
+{
+    loop {
+        let pc = a.state.pc;
+        let a_result = a.step();
+        let b_result = b.step();
+        if a_result != b_result || a.state != b.state {
+            return Err(Box::new(Divergence {
+                pc,
+                a_result,
+                b_result,
+                a: a.state.clone(),
+                b: b.state.clone(),
+            }));
+        }
+        if a_result.is_err() {
+            return Ok(());
+        }
+    }
+}
@@ -3,47 +3,562 @@
 // fields and values that should be matched on. In addition, the function argument names define
 // fields that should be captured in the `Op` enum variant. Both of these are matched by name to
 // functions defined in the `op` module.
-
-use crate::cpu::op::Op;
-use crate::cpu::types::{Clock, CpuError, CpuState, Memory, MemoryAccess};
+//
+// Instructions of optional extensions are wrapped in `//[feature{` and `//]feature}` lines, where
+// `feature` is the name of the Cargo feature enabling the extension. The build script skips these
+// blocks when the feature is disabled, so the instructions are not decoded at all.
+
+#[cfg(feature = "self-check")]
+use crate::cpu::op::Reg;
+use crate::cpu::op::{instr_len, Op};
+use crate::cpu::trace::TraceWriter;
+#[cfg(feature = "rv32a")]
+use crate::cpu::types::ScResult;
+use crate::cpu::types::{
+    AccessAlignmentStats, AccessCounts, Clock, CpuError, CpuState, CsrInfo, MachineCsrs, Memory,
+    MemoryAccess, MemoryValue, RegFile, RunError, Syscall,
+};
+use crate::cpu::write_log::{WriteLocation, WriteLog, WriteRecord};
+use crate::dev::EntropySource;
+use crate::elf::Elf32;
 #[cfg(feature = "rv32fd")]
 use crate::softfloat::{self as sf, Sf32, Sf64};
+#[cfg(feature = "timing")]
+use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::mem::size_of;
 #[cfg(feature = "rv32fd")]
 use std::num::FpCategory;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 type CpuExit = Result<(), CpuError>;
 
+/// Reverse the byte order of a value, for big-endian data accesses.
+#[cfg(feature = "big-endian")]
+fn swap_bytes<T: MemoryValue>(value: T) -> T {
+    let mut bytes = [0u8; 8];
+    let bytes = &mut bytes[..T::SIZE];
+    value.to_le_slice(bytes);
+    bytes.reverse();
+    T::from_le_slice(bytes)
+}
+
+/// Format an executed instruction for `Interp::trace_annotated`.
+fn annotate(elf: &Elf32, pc: u32, len: u32, op: &Op, next_pc: u32) -> String {
+    let symbol = |addr: u32| match elf.symbolize(addr) {
+        Some((name, 0)) => format!(" <{}>", name),
+        Some((name, offset)) => format!(" <{}+{:#x}>", name, offset),
+        None => String::new(),
+    };
+    let taken = next_pc != pc.wrapping_add(len);
+    let (target, note) = match *op {
+        Op::Beq { b_imm, .. }
+        | Op::Bne { b_imm, .. }
+        | Op::Blt { b_imm, .. }
+        | Op::Bge { b_imm, .. }
+        | Op::Bltu { b_imm, .. }
+        | Op::Bgeu { b_imm, .. } => {
+            let note = if taken { "taken" } else { "not taken" };
+            (Some(pc.wrapping_add(b_imm as u32)), Some(note.to_owned()))
+        }
+        Op::Jal { j_imm, .. } => (Some(pc.wrapping_add(j_imm as u32)), None),
+        Op::Jalr { .. } => (None, Some(format!("-> {:#x}{}", next_pc, symbol(next_pc)))),
+        _ => (None, None),
+    };
+
+    // Replace the relative offset, which is the last operand, with the target address.
+    let mut text = op.to_string();
+    if let Some(target) = target {
+        if let Some(pos) = text.rfind(", ") {
+            text.truncate(pos + 2);
+        }
+        text += &format!("{:#x}{}", target, symbol(target));
+    }
+    let mut line = format!("{:#x}{}: {}", pc, symbol(pc), text);
+    if let Some(note) = note {
+        line += &format!("  # {}", note);
+    }
+    line
+}
+
+/// CSRs with a single address implemented by `Interp::access_csr`, except `seed`.
+const FIXED_CSRS: &[(u32, &str)] = &[
+    (0x001, "fflags"),
+    (0x002, "frm"),
+    (0x003, "fcsr"),
+    (0x300, "mstatus"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    #[cfg(feature = "big-endian")]
+    (0x310, "mstatush"),
+    (0x320, "mcountinhibit"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0xC00, "cycle"),
+    (0xC01, "time"),
+    (0xC02, "instret"),
+    (0xC80, "cycleh"),
+    (0xC81, "timeh"),
+    (0xC82, "instreth"),
+];
+
+/// CSR ranges for performance-monitor counters 3 through 31 implemented by
+/// `Interp::access_csr`, with the base address and name prefix of each range.
+const HPM_CSRS: &[(u32, &str)] = &[
+    (0x323, "mhpmevent"),
+    (0xB03, "mhpmcounter"),
+    (0xB83, "mhpmcounter"),
+    (0xC03, "hpmcounter"),
+    (0xC83, "hpmcounter"),
+];
+
 enum CsrAccess<'a> {
     Read(&'a mut u32),
     Write(u32),
 }
 
+type PeriodicCallback<C, R> = Box<dyn FnMut(&CpuState<R>, &C)>;
+
+type FaultInjector = Box<dyn FnMut(&Op, u32, u32) -> u32>;
+
+type SfenceCallback = Box<dyn FnMut(Option<u32>, Option<u32>)>;
+
+/// A callback set with `Interp::set_periodic`.
+struct Periodic<C, R: RegFile> {
+    interval: u64,
+    remaining: u64,
+    callback: PeriodicCallback<C, R>,
+}
+
+/// A checkpoint of the virtual CPU, created with `Interp::snapshot`.
+///
+/// This holds a copy of the CPU state and clock, but not memory. See `Interp::snapshot`.
+#[derive(Clone, Debug)]
+pub struct MachineSnapshot<C, R: RegFile = [u32; 32]> {
+    /// The CPU state.
+    pub state: CpuState<R>,
+    /// The clock.
+    pub clock: C,
+    /// Size of the last instruction.
+    instsz: u32,
+}
+
+/// Everything about a retired instruction, returned by `Interp::step_traced`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetireInfo {
+    /// The executed instruction.
+    pub op: Op,
+    /// Address of the instruction.
+    pub pc: u32,
+    /// Size of the instruction in bytes (2 or 4).
+    pub size: u32,
+    /// Register and memory writes performed, with old and new values, in order.
+    pub writes: Vec<WriteRecord>,
+    /// Memory loads performed, as address, size in bytes and the zero-extended value, in order.
+    pub loads: Vec<(u32, u32, u64)>,
+}
+
 /// The interpeter.
 ///
 /// This struct simply combines a `CpuState`, `Memory` and `Clock`. An `Interp` instance can be
 /// fleeting, and doesn't need to be kept around if the virtual CPU is paused, for example.
-pub struct Interp<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> {
+pub struct Interp<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock, R: RegFile = [u32; 32]> {
     /// The CPU state.
-    pub state: &'s mut CpuState,
+    pub state: &'s mut CpuState<R>,
     /// The memory implementation.
     pub mem: &'m mut M,
     /// The clock implementation.
     pub clock: &'c mut C,
+    /// Entropy source for the `seed` CSR. When `None`, the CSR is not implemented.
+    pub entropy: Option<Box<dyn EntropySource>>,
+    /// Stop with `CpuError::StackMisaligned` when `sp` is not 16-byte aligned after an
+    /// instruction. Disabled by default.
+    ///
+    /// The alignment is required by the calling convention, not the architecture, so this is
+    /// only a debugging aid for code that is expected to follow the ABI.
+    pub check_stack_alignment: bool,
+    /// The lowest value of the stack pointer `x2` after any instruction, or `None` to disable
+    /// tracking.
+    ///
+    /// Enable tracking by setting this to the current stack pointer, usually the stack top. See
+    /// `max_stack_usage`.
+    pub min_sp: Option<u32>,
+    /// Survey mode, a diagnostic aid for finding unsupported instructions. Disabled when `None`.
+    ///
+    /// When enabled, an instruction that stops the virtual CPU with
+    /// `CpuError::IllegalInstruction` is instead recorded here as its address and raw bits (see
+    /// `last_fetch`), and skipped. Execution continues until some other stop reason, after which
+    /// this contains every unsupported encoding that was encountered.
+    ///
+    /// Skipping instructions produces incorrect execution, so results are only useful to learn
+    /// what a program needs. The clock is still notified of each skipped instruction through
+    /// `Clock::on_trap`.
+    pub survey: Option<Vec<(u32, u32)>>,
+    /// Log of register and memory writes, for reverse debugging. Disabled when `None`.
+    ///
+    /// When enabled, the old value of every register written and every store is read first, and
+    /// recorded together with the new value. Replaying the records in reverse undoes execution.
+    /// Reading the old value of a store is an extra `Memory` load that is not counted in
+    /// `access_counts`, which may have side-effects for memory-mapped devices.
+    pub write_log: Option<WriteLog>,
+    /// Binary trace of executed instructions, for replay and offline analysis. Disabled when
+    /// `None`.
+    ///
+    /// When enabled, a record with the PC, instruction and all register and memory writes is
+    /// written for every instruction executed. See `TraceWriter` for the format.
+    pub trace: Option<TraceWriter>,
+    /// Histogram of data access alignments, for cache analysis. Disabled when `None`.
+    pub alignment_stats: Option<AccessAlignmentStats>,
+    /// Addresses written by the guest, to detect execution of written memory. Disabled when
+    /// `None`.
+    ///
+    /// When enabled, every byte stored by an instruction is added to this set, and fetching an
+    /// instruction that overlaps the set stops the virtual CPU with
+    /// `CpuError::ExecuteAfterWrite`. This is a heuristic detector of self-modifying or injected
+    /// code. Memory modified outside the interpreter is not tracked, unless its addresses are
+    /// added to the set. Addresses can be removed to allow execution again, for example after the
+    /// guest flushes the instruction cache with `fence.i`.
+    pub written: Option<HashSet<u32>>,
+    /// Number of instructions executed between checks of the stop condition in
+    /// `run_with_timeout` and `run_cancellable`. Defaults to 1000.
+    ///
+    /// Lower values stop sooner after the condition is met, at the cost of checking more often.
+    pub check_interval: u32,
+    /// Number of recently executed instructions checked for loops, or `None` to disable loop
+    /// detection.
+    ///
+    /// When enabled, the addresses of instructions executed since the last change to a register or
+    /// memory are remembered, up to the given number. Executing one of them again means execution
+    /// cycles without making progress, and stops the virtual CPU with
+    /// `CpuError::SuspectedInfiniteLoop`. Every store counts as a change, but a register write
+    /// only if it changes the value. Entering a trap handler also resets detection.
+    ///
+    /// This is a heuristic for fuzzing and bringup. A loop polling a device without writing
+    /// anything, for example waiting for input, is reported as well.
+    pub loop_detection: Option<usize>,
+    /// Whether `ecall` traps to the machine-mode handler at `mtvec`. Defaults to `false`.
+    ///
+    /// When disabled, `ecall` stops the virtual CPU with `CpuError::Ecall`, so the host can
+    /// emulate the call. When enabled, it raises an environment-call exception instead, with
+    /// `mepc` pointing at the `ecall`, and the guest handler services it. The handler usually
+    /// advances `mepc` by 4 before returning with `mret`.
+    pub trap_ecall: bool,
+    /// Whether illegal instructions trap to the machine-mode handler at `mtvec`. Defaults to
+    /// `false`.
+    ///
+    /// When disabled, an illegal instruction stops the virtual CPU with
+    /// `CpuError::IllegalInstruction`. When enabled, it raises an illegal-instruction exception
+    /// instead, and the guest handler can emulate the instruction. `mepc` points at the
+    /// instruction, and `mtval` holds its bits. A handler that skips the instruction advances
+    /// `mepc` by its size before returning with `mret`, which is 4 bytes if the lowest two bits
+    /// of `mtval` are `11`, and 2 bytes otherwise. Survey mode takes precedence.
+    pub trap_illegal: bool,
+    /// Whether `fmin` and `fmax` follow the IEEE 754-2008 `minNum` and `maxNum` operations, as
+    /// in version 2.1 and earlier of the F extension. Defaults to `false`.
+    ///
+    /// By default, they follow the IEEE 754-2019 `minimumNumber` and `maximumNumber` operations,
+    /// adopted in version 2.2 of the F extension: a signaling NaN input is treated like a quiet
+    /// NaN, so the other input is returned. With the older semantics, a signaling NaN input
+    /// results in the canonical NaN. Both raise the invalid operation flag, and order `-0.0`
+    /// below `+0.0`.
+    #[cfg(feature = "rv32fd")]
+    pub legacy_fmin_fmax: bool,
+    /// Callback set with `set_periodic`, with its interval and the instructions left until the
+    /// next call.
+    periodic: Option<Periodic<C, R>>,
+    /// Callback set with `set_fault_injector`.
+    fault_injector: Option<FaultInjector>,
+    /// Callback set with `set_on_sfence`.
+    on_sfence: Option<SfenceCallback>,
+    /// The instruction being executed, if `fault_injector` is set.
+    current_op: Option<Op>,
     /// Size of the last instruction (2 or 4).
     instsz: u32,
+    /// Address and raw bits of the last fetched instruction.
+    last_fetch: Option<(u32, u32)>,
+    /// Address following the last executed instruction, if execution continued there.
+    fall_through_pc: Option<u32>,
+    /// Number of memory accesses performed.
+    access_counts: AccessCounts,
+    /// Addresses of instructions executed since the last state change, for `loop_detection`.
+    recent_pcs: VecDeque<u32>,
+    /// Whether the current instruction changed a register or memory.
+    state_changed: bool,
+    /// Loads performed by the current instruction, collected during `step_traced`.
+    loads: Option<Vec<(u32, u32, u64)>>,
+    /// Writes performed by the current instruction, collected during `step_traced`.
+    writes: Option<Vec<WriteRecord>>,
+    /// Outcome of the last `sc.w`.
+    #[cfg(feature = "rv32a")]
+    last_sc: Option<ScResult>,
+    /// Whether the reservation was cleared by `invalidate_reservation` since the last `lr.w`.
+    #[cfg(feature = "rv32a")]
+    invalidated: bool,
+    /// Cumulative time spent dispatching each instruction.
+    #[cfg(feature = "timing")]
+    timing: HashMap<&'static str, Duration>,
 }
 
-impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
+impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock, R: RegFile> Interp<'s, 'm, 'c, M, C, R> {
     /// Create a new interpreter.
-    pub fn new(state: &'s mut CpuState, mem: &'m mut M, clock: &'c mut C) -> Self {
+    pub fn new(state: &'s mut CpuState<R>, mem: &'m mut M, clock: &'c mut C) -> Self {
         Self {
             state,
             mem,
             clock,
+            entropy: None,
+            check_stack_alignment: false,
+            min_sp: None,
+            survey: None,
+            write_log: None,
+            trace: None,
+            alignment_stats: None,
+            written: None,
+            check_interval: 1000,
+            loop_detection: None,
+            trap_ecall: false,
+            trap_illegal: false,
+            #[cfg(feature = "rv32fd")]
+            legacy_fmin_fmax: false,
+            periodic: None,
+            fault_injector: None,
+            on_sfence: None,
+            current_op: None,
             instsz: 4,
+            last_fetch: None,
+            fall_through_pc: None,
+            access_counts: AccessCounts::default(),
+            recent_pcs: VecDeque::new(),
+            state_changed: false,
+            loads: None,
+            writes: None,
+            #[cfg(feature = "rv32a")]
+            last_sc: None,
+            #[cfg(feature = "rv32a")]
+            invalidated: false,
+            #[cfg(feature = "timing")]
+            timing: HashMap::new(),
         }
     }
 
+    /// Get a shared reference to the memory implementation.
+    pub fn memory(&self) -> &M {
+        self.mem
+    }
+
+    /// Get a mutable reference to the memory implementation.
+    pub fn memory_mut(&mut self) -> &mut M {
+        self.mem
+    }
+
+    /// Get a shared reference to the clock implementation.
+    pub fn clock(&self) -> &C {
+        self.clock
+    }
+
+    /// Get a mutable reference to the clock implementation.
+    pub fn clock_mut(&mut self) -> &mut C {
+        self.clock
+    }
+
+    /// The maximum number of bytes of stack used below `stack_top`, as tracked by `min_sp`.
+    ///
+    /// Returns 0 if tracking is disabled, or the stack pointer never went below `stack_top`.
+    pub fn max_stack_usage(&self, stack_top: u32) -> u32 {
+        self.min_sp
+            .map_or(0, |min_sp| stack_top.saturating_sub(min_sp))
+    }
+
+    /// The CSRs implemented by the interpreter, sorted by address.
+    ///
+    /// This depends on enabled features, and on `entropy` for the `seed` CSR. Accessing any other
+    /// CSR raises `CpuError::IllegalInstruction`, as does writing a CSR that is not writable.
+    pub fn supported_csrs(&self) -> Vec<CsrInfo> {
+        let fixed = FIXED_CSRS
+            .iter()
+            .map(|&(id, name)| (id, name.to_owned()))
+            .chain(self.entropy.as_ref().map(|_| (0x015, "seed".to_owned())));
+        let hpm = HPM_CSRS.iter().flat_map(|&(base, prefix)| {
+            let suffix = if base & 0x80 != 0 { "h" } else { "" };
+            (3..32).map(move |n| (base + n - 3, format!("{}{}{}", prefix, n, suffix)))
+        });
+        let mut csrs = fixed
+            .chain(hpm)
+            .map(|(id, name)| CsrInfo {
+                id,
+                name,
+                writable: id >> 10 != 0b11,
+            })
+            .collect::<Vec<_>>();
+        csrs.sort_by_key(|csr| csr.id);
+        csrs
+    }
+
+    /// Cumulative wall-clock time spent dispatching each instruction, keyed by mnemonic.
+    ///
+    /// This is a profiling aid for the interpreter itself, and is unrelated to the `Clock`. Only
+    /// instructions executed through this `Interp` instance are counted.
+    ///
+    /// Only available with the `timing` feature.
+    #[cfg(feature = "timing")]
+    pub fn timing_histogram(&self) -> &HashMap<&'static str, Duration> {
+        &self.timing
+    }
+
+    /// Address and raw bits of the instruction fetched by the last step.
+    ///
+    /// This is available for all stop reasons, including when the instruction could not be
+    /// parsed. Compressed instructions occupy only the lower 16 bits, and can be distinguished by
+    /// the lowest two bits not being `11`. Returns `None` if the instruction could not be fetched,
+    /// or nothing was stepped yet.
+    pub fn last_fetch(&self) -> Option<(u32, u32)> {
+        self.last_fetch
+    }
+
+    /// Number of memory accesses performed by this `Interp` instance.
+    pub fn access_counts(&self) -> AccessCounts {
+        self.access_counts
+    }
+
+    /// Clear the floating-point exception flags, run `f`, and return its result together with the
+    /// flags raised while it ran.
+    ///
+    /// Afterwards, the flags that were set before are restored, in addition to the ones raised, so
+    /// the guest observes the same accumulated flags as without this call. This is useful for
+    /// checking that a computation is exact, for example.
+    pub fn with_clean_fflags<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> (T, u32) {
+        let before = self.state.fflags();
+        self.state.clear_fflags();
+        let res = f(self);
+        let raised = self.state.fflags();
+        self.state.set_fflags(before | raised);
+        (res, raised)
+    }
+
+    /// Invalidate the reservation of the atomic extension, causing the next `sc.w` to fail.
+    ///
+    /// This should be called after modifying memory outside the interpreter. See
+    /// `CpuState::reservation`.
+    pub fn invalidate_reservation(&mut self) {
+        #[cfg(feature = "rv32a")]
+        {
+            self.invalidated |= self.state.reservation.is_some();
+        }
+        self.state.clear_reservation();
+    }
+
+    /// Outcome of the last `sc.w` executed by this `Interp` instance, explaining why it failed.
+    ///
+    /// Returns `None` if no `sc.w` completed yet. Only available with the `rv32a` feature.
+    #[cfg(feature = "rv32a")]
+    pub fn last_sc_result(&self) -> Option<ScResult> {
+        self.last_sc
+    }
+
+    /// Set a callback that is called every `interval` instructions.
+    ///
+    /// Instructions are counted as they are executed, including instructions that stop the
+    /// virtual CPU after executing, such as `ecall`. The callback is called with the
+    /// state and clock after the instruction. Unlike `Clock::check_quota`, this does not stop
+    /// execution, which makes it suitable for periodic work such as progress reporting.
+    ///
+    /// Replaces any previously set callback. Panics if `interval` is 0.
+    pub fn set_periodic<F>(&mut self, interval: u64, callback: F)
+    where
+        F: FnMut(&CpuState<R>, &C) + 'static,
+    {
+        assert!(interval > 0, "interval must not be 0");
+        self.periodic = Some(Periodic {
+            interval,
+            remaining: interval,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Remove the callback set with `set_periodic`.
+    pub fn clear_periodic(&mut self) {
+        self.periodic = None;
+    }
+
+    /// Set a callback that can corrupt the result of instructions, for fault injection.
+    ///
+    /// The callback is called with the instruction, the destination register and the computed
+    /// value, before every write to an integer register other than `x0`, and returns the value
+    /// to actually write. This models hardware faults such as single-event upsets, to test how
+    /// the guest copes with them. The callback can keep its own state to target a specific
+    /// instruction count or PC.
+    ///
+    /// This deliberately produces incorrect execution, and is only meant for fault-tolerance
+    /// research. The write log and trace record the corrupted value. Replaces any previously set
+    /// callback.
+    pub fn set_fault_injector<F>(&mut self, injector: F)
+    where
+        F: FnMut(&Op, u32, u32) -> u32 + 'static,
+    {
+        self.fault_injector = Some(Box::new(injector));
+    }
+
+    /// Remove the callback set with `set_fault_injector`.
+    pub fn clear_fault_injector(&mut self) {
+        self.fault_injector = None;
+        self.current_op = None;
+    }
+
+    /// Set a callback for `sfence.vma`, to flush a host-side address translation cache.
+    ///
+    /// The callback is called with the virtual address and the ASID operands of the instruction,
+    /// each `None` if the operand register is `x0`, meaning all addresses or all address spaces.
+    /// The interpreter itself does not translate addresses, so it has nothing to flush, and the
+    /// instruction otherwise executes as a no-op. Replaces any previously set callback.
+    pub fn set_on_sfence<F>(&mut self, callback: F)
+    where
+        F: FnMut(Option<u32>, Option<u32>) + 'static,
+    {
+        self.on_sfence = Some(Box::new(callback));
+    }
+
+    /// Remove the callback set with `set_on_sfence`.
+    pub fn clear_on_sfence(&mut self) {
+        self.on_sfence = None;
+    }
+
+    /// Create an in-memory checkpoint of the CPU state and clock, which can be restored with
+    /// `restore`.
+    ///
+    /// This is cheaper than serializing the state, which makes it suitable to checkpoint often,
+    /// such as between fuzzing iterations. Memory is not part of the snapshot, and must be
+    /// checkpointed separately if needed, for example using a copy-on-write `Memory`.
+    pub fn snapshot(&self) -> MachineSnapshot<C, R>
+    where
+        C: Clone,
+        R: Clone,
+    {
+        MachineSnapshot {
+            state: self.state.clone(),
+            clock: self.clock.clone(),
+            instsz: self.instsz,
+        }
+    }
+
+    /// Restore the CPU state and clock from a checkpoint created with `snapshot`.
+    ///
+    /// Memory is not restored. The snapshot is left intact, so it can be restored repeatedly.
+    pub fn restore(&mut self, snapshot: &MachineSnapshot<C, R>)
+    where
+        C: Clone,
+        R: Clone,
+    {
+        self.state.clone_from(&snapshot.state);
+        self.clock.clone_from(&snapshot.clock);
+        self.instsz = snapshot.instsz;
+        self.last_fetch = None;
+    }
+
     /// Run continuously until execution stops, starting at the current PC address.
     ///
     /// Returns the stop reason and the instruction that caused the virtual CPU to stop. The
@@ -56,71 +571,463 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
         }
     }
 
+    /// Run continuously until execution stops, or the wall-clock `timeout` expires.
+    ///
+    /// Elapsed time is only checked every `check_interval` instructions, so execution may
+    /// continue past the timeout for as long as it takes to execute that many instructions. When
+    /// the timeout expires, stops with `CpuError::Timeout`, and execution can be resumed.
+    ///
+    /// Otherwise, returns the stop reason and instruction like `run`.
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> (CpuError, Option<Op>) {
+        let start = Instant::now();
+        self.run_checked(|| start.elapsed() >= timeout, CpuError::Timeout)
+    }
+
+    /// Run continuously until execution stops, or `stop` is set, for example by another thread.
+    ///
+    /// The flag is only checked every `check_interval` instructions. When it is set, stops with
+    /// `CpuError::Cancelled`, and execution can be resumed. The flag is not cleared.
+    ///
+    /// Otherwise, returns the stop reason and instruction like `run`.
+    pub fn run_cancellable(&mut self, stop: &AtomicBool) -> (CpuError, Option<Op>) {
+        self.run_checked(|| stop.load(Ordering::Relaxed), CpuError::Cancelled)
+    }
+
+    /// Run continuously, stopping with `err` when `check` returns true. See `check_interval`.
+    fn run_checked<F: FnMut() -> bool>(
+        &mut self,
+        mut check: F,
+        err: CpuError,
+    ) -> (CpuError, Option<Op>) {
+        loop {
+            for _ in 0..self.check_interval.max(1) {
+                if let Err(err) = self.step() {
+                    return err;
+                }
+            }
+            if check() {
+                return (err, None);
+            }
+        }
+    }
+
+    /// Run continuously until execution stops, treating `Ebreak` as a clean finish.
+    ///
+    /// See `try_run_with`.
+    pub fn try_run(&mut self) -> Result<(), RunError> {
+        self.try_run_with(|cause| cause == CpuError::Ebreak)
+    }
+
+    /// Run continuously until execution stops, starting at the current PC address.
+    ///
+    /// The `finished` function decides which stop reasons are a clean finish, resulting in `Ok`.
+    /// Any other stop reason is returned as a `RunError`, which includes the address of the
+    /// instruction that caused the stop.
+    pub fn try_run_with<F: FnMut(CpuError) -> bool>(
+        &mut self,
+        mut finished: F,
+    ) -> Result<(), RunError> {
+        loop {
+            let pc = self.state.pc;
+            if let Err((cause, op)) = self.step() {
+                return if finished(cause) {
+                    Ok(())
+                } else {
+                    Err(RunError { cause, op, pc })
+                };
+            }
+        }
+    }
+
+    /// Run until the PC reaches the given address, as if a breakpoint were set there.
+    ///
+    /// Returns `Ok` without executing the instruction at `addr`. If execution stops before
+    /// reaching it, returns the stop reason as a `RunError`.
+    pub fn run_to(&mut self, addr: u32) -> Result<(), RunError> {
+        while self.state.pc != addr {
+            let pc = self.state.pc;
+            if let Err((cause, op)) = self.step() {
+                return Err(RunError { cause, op, pc });
+            }
+        }
+        Ok(())
+    }
+
+    /// Run until the PC reaches the address of the named symbol. See `run_to`.
+    ///
+    /// Returns an error if the symbol does not exist, or execution stops before reaching it.
+    pub fn run_to_symbol(&mut self, elf: &Elf32, name: &str) -> Result<(), String> {
+        let addr = elf
+            .symbol(name)
+            .ok_or_else(|| format!("symbol `{}` not found", name))?;
+        self.run_to(addr).map_err(|err| err.to_string())
+    }
+
+    /// Run until execution stops, like `run`, writing an annotated disassembly of every executed
+    /// instruction to `out`.
+    ///
+    /// Each line has the form `0x10044 <main+0x8>: beq a0, zero, 0x10058 <main+0x1c>  # taken`.
+    /// Addresses are resolved to the nearest preceding symbol of `elf`, see `Elf32::symbolize`.
+    /// Branch and jump targets are shown as addresses, branches are annotated with whether they
+    /// were taken, and `jalr` with where it went. Instructions that could not be fetched or parsed
+    /// are not written.
+    ///
+    /// Returns the stop reason, or an error if writing to `out` failed.
+    pub fn trace_annotated<W: Write>(
+        &mut self,
+        elf: &Elf32,
+        out: &mut W,
+    ) -> io::Result<(CpuError, Option<Op>)> {
+        loop {
+            let res = self.step();
+            let op = match res {
+                Ok(op) => Some(op),
+                Err((_, op)) => op,
+            };
+            if let (Some(op), Some((pc, bits))) = (op, self.last_fetch) {
+                let next_pc = self.state.pc;
+                writeln!(out, "{}", annotate(elf, pc, instr_len(bits), &op, next_pc))?;
+            }
+            if let Err(err) = res {
+                return Ok(err);
+            }
+        }
+    }
+
+    /// Run until the next `ecall`, and decode the system call from the registers.
+    ///
+    /// The PC is left after the `ecall`, so the host can emulate the system call, write the result
+    /// to `a0`, and resume execution. Any other stop reason is returned as the error, like `run`.
+    pub fn run_to_syscall(&mut self) -> Result<Syscall, (CpuError, Option<Op>)> {
+        match self.run() {
+            (CpuError::Ecall, _) => Ok(Syscall::from_state(self.state)),
+            err => Err(err),
+        }
+    }
+
     /// Step a single instruction, fetching it from the current PC address.
     ///
     /// Returns the parsed instruction that was executed. When the instruction stops the virtual
     /// CPU, additionally returns the stop reason. In the latter case, the instruction may be
     /// `None` if it failed to load or parse.
+    ///
+    /// In survey mode, illegal instructions are skipped, so a single step may fetch several
+    /// instructions. See `survey`. Similarly, with `trap_illegal`, a single step continues at the
+    /// trap handler.
     pub fn step(&mut self) -> Result<Op, (CpuError, Option<Op>)> {
+        loop {
+            match self.step_once() {
+                Err((CpuError::IllegalInstruction, op)) if self.survey.is_some() => {
+                    self.skip_illegal(op)
+                }
+                Err((CpuError::IllegalInstruction, _)) if self.trap_illegal => {
+                    self.trap_illegal_instruction()
+                }
+                res => {
+                    if let Err((err, _)) = res {
+                        self.notify_trap(err);
+                    }
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// Step a single instruction like `step`, returning everything about what it did.
+    ///
+    /// This collects the register and memory writes of the instruction, as would be recorded in
+    /// `write_log`, and the loads it performed. If `write_log` is enabled, the writes are also
+    /// added to it as usual. Otherwise, memory is not read before a store, so memory writes are
+    /// recorded with the new value as the old value.
+    pub fn step_traced(&mut self) -> Result<RetireInfo, (CpuError, Option<Op>)> {
+        self.loads = Some(Vec::new());
+        self.writes = Some(Vec::new());
+        let res = self.step();
+        let loads = self.loads.take().unwrap_or_default();
+        let writes = self.writes.take().unwrap_or_default();
+
+        let op = res?;
+        // In survey mode, illegal instructions may have been skipped, so use the last fetch.
+        let pc = self.last_fetch.map_or(self.state.pc, |(pc, _)| pc);
+        Ok(RetireInfo {
+            op,
+            pc,
+            size: self.instsz,
+            writes,
+            loads,
+        })
+    }
+
+    /// Step a single instruction like `step`, but leave state untouched if it fails fatally.
+    ///
+    /// If the instruction raises an error for which `CpuError::is_fatal` is true, `state` is
+    /// restored to exactly what it was before the step, including registers and `pc` written by a
+    /// partially executed jump or atomic instruction. This includes an interrupt taken before the
+    /// instruction. The exception is the `mhpmcounter` performance counters, which are not rolled
+    /// back. The step is also kept out of `write_log` and `trace`, and does not affect `min_sp`,
+    /// `loop_detection` or the `fell_through` flag of a following `CpuError::IllegalFetch`.
+    ///
+    /// Memory is not rolled back. A store that fails leaves memory unaltered, and no instruction
+    /// fails after completing a store, so this is only visible when accesses have side-effects:
+    /// a load from a device (MMIO) that succeeded before the instruction failed, for example an
+    /// atomic instruction whose store fails, cannot be undone. The `clock`, the performance
+    /// counters and statistics such as `access_counts` also still count the attempt.
+    pub fn step_atomic(&mut self) -> Result<Op, (CpuError, Option<Op>)> {
+        let mut x = [0; 32];
+        for (reg, value) in x.iter_mut().enumerate() {
+            *value = self.state.x.read(reg);
+        }
+        #[cfg(feature = "rv32fd")]
+        let f = self.state.f;
+        let pc = self.state.pc;
+        let fcsr = self.state.fcsr;
+        let reservation = self.state.reservation;
+        let machine = self.state.machine.clone();
+        #[cfg(feature = "rv32a")]
+        let (last_sc, invalidated) = (self.last_sc, self.invalidated);
+        let min_sp = self.min_sp;
+        let recent_pcs = self.recent_pcs.clone();
+        let fall_through_pc = self.fall_through_pc;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.hold();
+        }
+        // Only collect writes if logging, because the log makes stores read the old value.
+        let attempt = self.write_log.as_ref().map(|_| WriteLog::new(8));
+        let log = std::mem::replace(&mut self.write_log, attempt);
+
+        let res = self.step();
+
+        let attempt = std::mem::replace(&mut self.write_log, log);
+        let fatal = matches!(res, Err((err, _)) if err.is_fatal());
+        if let Some(trace) = self.trace.as_mut() {
+            trace.release(!fatal);
+        }
+        if fatal {
+            for (reg, &value) in x.iter().enumerate().skip(1) {
+                if self.state.x.read(reg) != value {
+                    self.state.x.write(reg, value);
+                }
+            }
+            #[cfg(feature = "rv32fd")]
+            {
+                self.state.f = f;
+            }
+            self.state.pc = pc;
+            self.state.fcsr = fcsr;
+            self.state.reservation = reservation;
+            let counters = self.state.machine.mhpmcounter;
+            self.state.machine = MachineCsrs {
+                mhpmcounter: counters,
+                ..machine
+            };
+            #[cfg(feature = "rv32a")]
+            {
+                self.last_sc = last_sc;
+                self.invalidated = invalidated;
+            }
+            self.min_sp = min_sp;
+            self.recent_pcs = recent_pcs;
+            self.fall_through_pc = fall_through_pc;
+        } else if let (Some(log), Some(attempt)) = (self.write_log.as_mut(), attempt) {
+            for &record in attempt.records() {
+                log.push(record);
+            }
+        }
+        res
+    }
+
+    /// Enter the trap handler for the illegal instruction that was just fetched.
+    fn trap_illegal_instruction(&mut self) {
+        let (pc, bits) = self.last_fetch.unwrap_or((self.state.pc, 0));
+        // Some instructions advance the PC before finding they are illegal.
+        self.state.pc = pc;
+        self.enter_trap(MachineCsrs::MCAUSE_ILLEGAL_INSTRUCTION, bits);
+    }
+
+    /// Record an illegal instruction in survey mode, and advance past it.
+    fn skip_illegal(&mut self, op: Option<Op>) {
+        if let (Some(survey), Some(fetch)) = (self.survey.as_mut(), self.last_fetch) {
+            survey.push(fetch);
+        }
+        // Instructions that failed to parse have not advanced the PC.
+        if op.is_none() {
+            self.state.pc = self.state.pc.wrapping_add(self.instsz);
+        }
+    }
+
+    /// Step a single instruction. See `step`.
+    fn step_once(&mut self) -> Result<Op, (CpuError, Option<Op>)> {
         // Increment counters.
         if !self.clock.check_quota() {
             return Err((CpuError::QuotaExceeded, None));
         }
 
-        let op = match {
-            #[cfg(feature = "rv32c")]
-            {
-                // Read the next instruction.
-                let mut instr_lo: u16 = 0;
-                if !self
-                    .mem
-                    .access(self.state.pc, MemoryAccess::Exec(&mut instr_lo))
-                {
-                    return Err((CpuError::IllegalFetch, None));
-                }
+        // Take the highest priority pending interrupt, continuing at the trap handler.
+        if let Some(code) = self.state.machine.pending_interrupt() {
+            self.enter_trap(MachineCsrs::MCAUSE_INTERRUPT | code, 0);
+        }
 
-                // Parse into an `Op`.
-                if (instr_lo & 3) == 3 {
-                    let mut instr_hi: u16 = 0;
-                    if !self
-                        .mem
-                        .access(self.state.pc + 2, MemoryAccess::Exec(&mut instr_hi))
-                    {
-                        return Err((CpuError::IllegalFetch, None));
-                    }
-                    self.instsz = 4;
-                    Op::parse((instr_hi as u32) << 16 | (instr_lo as u32))
-                } else {
-                    self.instsz = 2;
-                    Op::parse_c(instr_lo)
+        // Read the next instruction.
+        self.last_fetch = None;
+        #[cfg(feature = "rv32c")]
+        let instr = {
+            let mut instr_lo: u16 = 0;
+            if !self.mem_access(self.state.pc, MemoryAccess::Exec(&mut instr_lo)) {
+                return Err((self.illegal_fetch(), None));
+            }
+            if (instr_lo & 3) == 3 {
+                let mut instr_hi: u16 = 0;
+                let addr = self.state.pc.wrapping_add(2);
+                if !self.mem_access(addr, MemoryAccess::Exec(&mut instr_hi)) {
+                    let pc = self.state.pc;
+                    return Err((CpuError::TruncatedFetch { pc, addr }, None));
                 }
+                (instr_hi as u32) << 16 | (instr_lo as u32)
+            } else {
+                instr_lo as u32
+            }
+        };
+        #[cfg(not(feature = "rv32c"))]
+        let instr = {
+            let mut instr: u32 = 0;
+            if !self.mem_access(self.state.pc, MemoryAccess::Exec(&mut instr)) {
+                return Err((self.illegal_fetch(), None));
             }
+            instr
+        };
+
+        // Optionally check the instruction was not written by the guest.
+        if let Some(ref written) = self.written {
+            #[cfg(feature = "rv32c")]
+            let size = if (instr & 3) == 3 { 4 } else { 2 };
             #[cfg(not(feature = "rv32c"))]
+            let size = 4;
+            let pc = self.state.pc;
+            if let Some(addr) = (0..size)
+                .map(|i| pc.wrapping_add(i))
+                .find(|addr| written.contains(addr))
             {
-                // Read the next instruction.
-                let mut instr: u32 = 0;
-                if !self
-                    .mem
-                    .access(self.state.pc, MemoryAccess::Exec(&mut instr))
-                {
-                    return Err((CpuError::IllegalFetch, None));
-                }
-
-                // Parse into an `Op`.
-                Op::parse(instr)
+                self.last_fetch = Some((pc, instr));
+                return Err((CpuError::ExecuteAfterWrite { addr }, None));
             }
-        } {
+        }
+
+        self.execute(instr)
+    }
+
+    /// Execute the given instruction word at the current PC address, without fetching it.
+    ///
+    /// The word is decoded and executed as if it were fetched from memory at the PC, and the PC
+    /// advances as usual, so a jump redirects execution. Memory is not modified. This is useful to
+    /// interactively run an instruction, or to replay a single instruction from a trace.
+    ///
+    /// With the `rv32c` feature, the word is a compressed instruction if the lowest two bits are
+    /// not `11`, in which case the upper 16 bits are ignored.
+    ///
+    /// Unlike `step`, the clock quota is not checked, and survey mode does not apply. Results are
+    /// the same as for `step`.
+    pub fn inject_instruction(&mut self, word: u32) -> Result<Op, (CpuError, Option<Op>)> {
+        let res = self.execute(word);
+        if let Err((err, _)) = res {
+            self.notify_trap(err);
+        }
+        res
+    }
+
+    /// Build the error for a failed fetch at the current PC address.
+    fn illegal_fetch(&self) -> CpuError {
+        CpuError::IllegalFetch {
+            fell_through: self.fall_through_pc == Some(self.state.pc),
+        }
+    }
+
+    /// Decode and execute an instruction, which was fetched from the current PC address.
+    fn execute(&mut self, instr: u32) -> Result<Op, (CpuError, Option<Op>)> {
+        let pc = self.state.pc;
+        // Parse into an `Op`.
+        #[cfg(feature = "rv32c")]
+        let op = if (instr & 3) == 3 {
+            self.last_fetch = Some((self.state.pc, instr));
+            self.instsz = 4;
+            Op::parse(instr)
+        } else {
+            self.last_fetch = Some((self.state.pc, instr & 0xffff));
+            self.instsz = 2;
+            Op::parse_c(instr as u16)
+        };
+        #[cfg(not(feature = "rv32c"))]
+        let op = {
+            self.last_fetch = Some((self.state.pc, instr));
+            Op::parse(instr)
+        };
+        let op = match op {
             Some(op) => op,
             None => return Err((CpuError::IllegalInstruction, None)),
         };
 
         // Dispatch the instruction.
+        if self.fault_injector.is_some() {
+            self.current_op = Some(op);
+        }
+        self.state_changed = false;
+        #[cfg(feature = "self-check")]
+        let before = self.register_values();
+        #[cfg(feature = "timing")]
+        let start = Instant::now();
+        // There is no wildcard arm, so a variant without a method fails to compile.
         let res = match op {
             //% dispatch
         };
+        #[cfg(feature = "timing")]
+        {
+            *self.timing.entry(op.name()).or_default() += start.elapsed();
+        }
+        #[cfg(feature = "self-check")]
+        self.check_writes(&op, &before);
+
+        // Optionally track the deepest stack pointer.
+        if let Some(ref mut min_sp) = self.min_sp {
+            *min_sp = (*min_sp).min(self.state.x.read(2));
+        }
+
+        // Optionally check the stack pointer is aligned per the calling convention.
+        let res = match res {
+            Ok(()) if self.check_stack_alignment && !self.state.x.read(2).is_multiple_of(16) => {
+                Err(CpuError::StackMisaligned)
+            }
+            res => res,
+        };
+
+        // Optionally check for loops.
+        let res = match res {
+            Ok(()) if self.loop_detection.is_some() => self.check_loop(pc),
+            res => res,
+        };
+
+        // Optionally write the trace record.
+        if let Some(trace) = self.trace.as_mut() {
+            let instr = self.last_fetch.map_or(0, |(_, bits)| bits);
+            trace.finish(pc, instr, self.state.pc);
+        }
+
+        // Remember whether execution continued sequentially.
+        let next = pc.wrapping_add(self.instsz);
+        self.fall_through_pc = (self.state.pc == next).then_some(next);
 
         // Increment counters.
         self.clock.progress(&op);
+        self.state
+            .machine
+            .count_event(MachineCsrs::HPM_EVENT_INSTRET);
+
+        // Call the periodic callback.
+        if let Some(ref mut periodic) = self.periodic {
+            periodic.remaining -= 1;
+            if periodic.remaining == 0 {
+                periodic.remaining = periodic.interval;
+                (periodic.callback)(self.state, self.clock);
+            }
+        }
 
         // Attach the `Op` to the result.
         match res {
@@ -129,18 +1036,222 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
         }
     }
 
+    /// The values of all registers, for `check_writes`.
+    #[cfg(feature = "self-check")]
+    fn register_values(&self) -> Vec<(Reg, u64)> {
+        let x = (0..32).map(|i| (Reg::X(i), self.state.x.read(i) as u64));
+        #[cfg(feature = "rv32fd")]
+        let x = x.chain((0..32).map(|i| (Reg::F(i), self.state.f[i].0)));
+        x.collect()
+    }
+
+    /// Panic if the instruction modified a register other than the one declared by `Op::writes`.
+    #[cfg(feature = "self-check")]
+    fn check_writes(&self, op: &Op, before: &[(Reg, u64)]) {
+        let after = self.register_values();
+        for (&(reg, old), &(_, new)) in before.iter().zip(&after) {
+            if old != new && op.writes() != Some(reg) {
+                panic!(
+                    "self-check: `{}` ({:?}) modified {}, but declares writes {:?}",
+                    op,
+                    op,
+                    reg,
+                    op.writes()
+                );
+            }
+        }
+    }
+
+    /// Access memory, counting the access.
+    ///
+    /// A failed load or store is retried once if `Memory::handle_fault` fixes it up.
+    fn mem_access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        match access {
+            MemoryAccess::Load(dest) => {
+                self.access_counts.loads += 1;
+                if let Some(stats) = self.alignment_stats.as_mut() {
+                    stats.record(addr, T::SIZE as u32);
+                }
+                self.state.machine.count_event(MachineCsrs::HPM_EVENT_LOAD);
+                let ok = self.mem.access(addr, MemoryAccess::Load(&mut *dest))
+                    || (self.mem.handle_fault(addr, false)
+                        && self.mem.access(addr, MemoryAccess::Load(&mut *dest)));
+                #[cfg(feature = "big-endian")]
+                if ok && self.big_endian() {
+                    *dest = swap_bytes(*dest);
+                }
+                if let (true, Some(loads)) = (ok, self.loads.as_mut()) {
+                    let mut bytes = [0u8; 8];
+                    dest.to_le_slice(&mut bytes[..T::SIZE]);
+                    loads.push((addr, T::SIZE as u32, u64::from_le_bytes(bytes)));
+                }
+                ok
+            }
+            MemoryAccess::Store(value) => {
+                self.access_counts.stores += 1;
+                if let Some(stats) = self.alignment_stats.as_mut() {
+                    stats.record(addr, T::SIZE as u32);
+                }
+                self.state.machine.count_event(MachineCsrs::HPM_EVENT_STORE);
+                #[cfg(feature = "big-endian")]
+                let value = if self.big_endian() {
+                    swap_bytes(value)
+                } else {
+                    value
+                };
+                self.mem.access(addr, MemoryAccess::Store(value))
+                    || (self.mem.handle_fault(addr, true)
+                        && self.mem.access(addr, MemoryAccess::Store(value)))
+            }
+            MemoryAccess::Exec(dest) => {
+                self.access_counts.fetches += 1;
+                self.mem.access(addr, MemoryAccess::Exec(dest))
+            }
+        }
+    }
+
+    /// Whether data accesses are big-endian, as selected by `mstatush`.
+    #[cfg(feature = "big-endian")]
+    fn big_endian(&self) -> bool {
+        self.state.machine.mstatush & MachineCsrs::MSTATUSH_MBE != 0
+    }
+
+    /// Store a value in memory, recording it in the write log if enabled.
+    fn store<T: MemoryValue + Into<u64>>(&mut self, addr: u32, value: T) -> bool {
+        // If the location cannot be read, the old value is recorded as the new value.
+        let mut old = value;
+        if self.write_log.is_some() {
+            self.mem.access(addr, MemoryAccess::Load(&mut old));
+            #[cfg(feature = "big-endian")]
+            if self.big_endian() {
+                old = swap_bytes(old);
+            }
+        }
+        if !self.mem_access(addr, MemoryAccess::Store(value)) {
+            return false;
+        }
+        let size = size_of::<T>() as u32;
+        if let Some(ref mut written) = self.written {
+            written.extend((0..size).map(|i| addr.wrapping_add(i)));
+        }
+        self.log_write(WriteLocation::Mem { addr, size }, old.into(), value.into());
+        true
+    }
+
+    /// Pass a value written to integer register `rd` through the fault injector, if set.
+    fn inject_fault(&mut self, rd: usize, value: u32) -> u32 {
+        match (self.fault_injector.as_mut(), self.current_op.as_ref()) {
+            (Some(injector), Some(op)) => injector(op, rd as u32, value),
+            _ => value,
+        }
+    }
+
+    /// Whether register writes need to be passed to `log_write`.
+    fn logging_writes(&self) -> bool {
+        self.write_log.is_some()
+            || self.writes.is_some()
+            || self.trace.is_some()
+            || self.loop_detection.is_some()
+    }
+
+    /// Record a write in the write log and trace, if enabled.
+    fn log_write(&mut self, location: WriteLocation, old: u64, new: u64) {
+        self.state_changed |= old != new || matches!(location, WriteLocation::Mem { .. });
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push_write(location, new);
+        }
+        let record = WriteRecord {
+            pc: self.state.pc,
+            location,
+            old,
+            new,
+        };
+        if let Some(log) = self.write_log.as_mut() {
+            log.push(record);
+        }
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push(record);
+        }
+    }
+
+    /// Remember the executed instruction for `loop_detection`, and check whether it repeats.
+    fn check_loop(&mut self, pc: u32) -> CpuExit {
+        if self.state_changed {
+            self.recent_pcs.clear();
+        } else if self.recent_pcs.contains(&pc) {
+            self.recent_pcs.clear();
+            return Err(CpuError::SuspectedInfiniteLoop { pc });
+        }
+        let window = self.loop_detection.unwrap_or(0);
+        if window > 0 {
+            if self.recent_pcs.len() >= window {
+                self.recent_pcs.pop_front();
+            }
+            self.recent_pcs.push_back(pc);
+        }
+        Ok(())
+    }
+
+    /// Notify the clock of a trap that stops the virtual CPU.
+    ///
+    /// This is done only once the error is returned, because `step` may instead deliver an illegal
+    /// instruction to the guest, which notifies the clock in `enter_trap`.
+    fn notify_trap(&mut self, err: CpuError) {
+        if err != CpuError::QuotaExceeded {
+            self.clock.on_trap(err);
+        }
+    }
+
+    /// Enter the machine-mode trap handler at `mtvec`, saving the PC in `mepc`.
+    ///
+    /// Interrupts are disabled, and the reservation is cleared, because the handler runs in a
+    /// different context.
+    fn enter_trap(&mut self, cause: u32, tval: u32) {
+        let m = &mut self.state.machine;
+        let mpie = if m.mstatus & MachineCsrs::MSTATUS_MIE != 0 {
+            MachineCsrs::MSTATUS_MPIE
+        } else {
+            0
+        };
+        m.mstatus = (m.mstatus & !(MachineCsrs::MSTATUS_MIE | MachineCsrs::MSTATUS_MPIE)) | mpie;
+        m.mepc = self.state.pc;
+        m.mcause = cause;
+        m.mtval = tval;
+
+        let base = m.mtvec & !3;
+        let vectored = m.mtvec & 3 == 1 && cause & MachineCsrs::MCAUSE_INTERRUPT != 0;
+        self.state.pc = if vectored {
+            base.wrapping_add(4 * (cause & !MachineCsrs::MCAUSE_INTERRUPT))
+        } else {
+            base
+        };
+        self.invalidate_reservation();
+        self.recent_pcs.clear();
+        self.clock.on_trap_taken(cause);
+    }
+
     /// Read a value from or write a value to a CSR.
+    ///
+    /// Returns `false` if the CSR does not exist, or the access is not allowed. CSRs added here
+    /// must also be added to `FIXED_CSRS` or `HPM_CSRS`, for `supported_csrs`.
     fn access_csr(&mut self, id: u32, access: CsrAccess) -> bool {
+        // The top two bits of the address are `11` for read-only CSRs, such as the counters.
+        if let CsrAccess::Write(_) = access {
+            if id >> 10 == 0b11 {
+                return false;
+            }
+        }
+
         match id {
             0x001 => {
                 // fflags
                 match access {
                     CsrAccess::Read(dest) => {
-                        *dest = self.state.fcsr & 0x1f;
+                        *dest = self.state.fflags();
                         true
                     }
                     CsrAccess::Write(value) => {
-                        self.state.fcsr = (self.state.fcsr & 0xffff_ffe0) + (value & 0x1f);
+                        self.state.set_fflags(value);
                         true
                     }
                 }
@@ -149,11 +1260,11 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                 // frm
                 match access {
                     CsrAccess::Read(dest) => {
-                        *dest = (self.state.fcsr & 0xe0) >> 5;
+                        *dest = self.state.frm();
                         true
                     }
                     CsrAccess::Write(value) => {
-                        self.state.fcsr = (self.state.fcsr & 0xffff_ff1f) + ((value & 0x7) << 5);
+                        self.state.set_frm(value);
                         true
                     }
                 }
@@ -171,6 +1282,136 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                     }
                 }
             }
+            0x015 => {
+                // seed
+                match self.entropy {
+                    Some(ref mut source) => match access {
+                        CsrAccess::Read(dest) => {
+                            // OPST is always ES16, followed by 16 bits of entropy.
+                            *dest = 0b10 << 30 | (source.next_u32() & 0xffff);
+                            true
+                        }
+                        CsrAccess::Write(_) => true,
+                    },
+                    None => false,
+                }
+            }
+            0x300 => {
+                // mstatus
+                let m = &mut self.state.machine;
+                let mask = MachineCsrs::MSTATUS_MIE | MachineCsrs::MSTATUS_MPIE;
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = m.mstatus | MachineCsrs::MSTATUS_MPP;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        m.mstatus = value & mask;
+                        true
+                    }
+                }
+            }
+            #[cfg(feature = "big-endian")]
+            0x310 => {
+                // mstatush
+                let m = &mut self.state.machine;
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = m.mstatush;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        m.mstatush = value & MachineCsrs::MSTATUSH_MBE;
+                        true
+                    }
+                }
+            }
+            0x304 | 0x344 => {
+                // mie, mip
+                // Only the supervisor bits of `mip` are writable, the others are set by the host.
+                let m = &mut self.state.machine;
+                let (reg, mask) = if id == 0x304 {
+                    (&mut m.mie, 0xaaa)
+                } else {
+                    (&mut m.mip, 0x222)
+                };
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = *reg;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        *reg = (*reg & !mask) | (value & mask);
+                        true
+                    }
+                }
+            }
+            0x305 | 0x340..=0x343 => {
+                // mtvec, mscratch, mepc, mcause, mtval
+                let m = &mut self.state.machine;
+                let (reg, mask) = match id {
+                    // Only direct and vectored modes are supported.
+                    0x305 => (&mut m.mtvec, !0b10),
+                    0x340 => (&mut m.mscratch, !0),
+                    // Instructions are at least 2-byte aligned.
+                    0x341 => (&mut m.mepc, !0b1),
+                    0x342 => (&mut m.mcause, !0),
+                    _ => (&mut m.mtval, !0),
+                };
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = *reg;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        *reg = value & mask;
+                        true
+                    }
+                }
+            }
+            0x320 => {
+                // mcountinhibit
+                let m = &mut self.state.machine;
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = m.mcountinhibit;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        m.mcountinhibit = value & !0b111;
+                        true
+                    }
+                }
+            }
+            0x323..=0x33F => {
+                // mhpmevent3 - mhpmevent31
+                let reg = &mut self.state.machine.mhpmevent[(id - 0x323) as usize];
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = *reg;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        *reg = value;
+                        true
+                    }
+                }
+            }
+            0xB03..=0xB1F | 0xB83..=0xB9F | 0xC03..=0xC1F | 0xC83..=0xC9F => {
+                // mhpmcounter3 - mhpmcounter31, their upper halves, and read-only shadows
+                let reg = &mut self.state.machine.mhpmcounter[((id & 0x1f) - 3) as usize];
+                let shift = if id & 0x80 != 0 { 32 } else { 0 };
+                match access {
+                    CsrAccess::Read(dest) => {
+                        *dest = (*reg >> shift) as u32;
+                        true
+                    }
+                    CsrAccess::Write(value) => {
+                        *reg = (*reg & !(0xffff_ffff << shift)) | (value as u64) << shift;
+                        true
+                    }
+                }
+            }
             0xC00 => {
                 // cycle
                 match access {
@@ -178,7 +1419,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = self.clock.read_cycle() as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             0xC80 => {
@@ -188,7 +1429,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = (self.clock.read_cycle() >> 32) as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             0xC01 => {
@@ -198,7 +1439,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = self.clock.read_time() as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             0xC81 => {
@@ -208,7 +1449,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = (self.clock.read_time() >> 32) as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             0xC02 => {
@@ -218,7 +1459,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = self.clock.read_instret() as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             0xC82 => {
@@ -228,7 +1469,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                         *dest = (self.clock.read_instret() >> 32) as u32;
                         true
                     }
-                    CsrAccess::Write(_) => true,
+                    CsrAccess::Write(_) => false,
                 }
             }
             _ => false,
@@ -259,14 +1500,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0111 funct3=000
     fn jalr(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let dst_base = self.state.x[rs1];
+        let dst_base = self.state.x.read(rs1);
         write_rd!(self, rd, { self.state.pc.wrapping_add(self.instsz) });
         end_jump_op!(self, { dst_base.wrapping_add(i_imm as u32) })
     }
 
     //% opcode=110_0011 funct3=000
     fn beq(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if self.state.x[rs1] == self.state.x[rs2] {
+        if self.state.x.read(rs1) == self.state.x.read(rs2) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -275,7 +1516,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0011 funct3=001
     fn bne(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if self.state.x[rs1] != self.state.x[rs2] {
+        if self.state.x.read(rs1) != self.state.x.read(rs2) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -284,7 +1525,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0011 funct3=100
     fn blt(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if (self.state.x[rs1] as i32) < (self.state.x[rs2] as i32) {
+        if (self.state.x.read(rs1) as i32) < (self.state.x.read(rs2) as i32) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -293,7 +1534,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0011 funct3=101
     fn bge(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if (self.state.x[rs1] as i32) >= (self.state.x[rs2] as i32) {
+        if (self.state.x.read(rs1) as i32) >= (self.state.x.read(rs2) as i32) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -302,7 +1543,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0011 funct3=110
     fn bltu(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if self.state.x[rs1] < self.state.x[rs2] {
+        if self.state.x.read(rs1) < self.state.x.read(rs2) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -311,7 +1552,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=110_0011 funct3=111
     fn bgeu(&mut self, rs1: usize, rs2: usize, b_imm: i32) -> CpuExit {
-        if self.state.x[rs1] >= self.state.x[rs2] {
+        if self.state.x.read(rs1) >= self.state.x.read(rs2) {
             end_branch_op!(self, b_imm)
         } else {
             end_op!(self)
@@ -320,9 +1561,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0011 funct3=000
     fn lb(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: i8 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             write_rd!(self, rd, { value as u32 });
             end_op!(self)
         } else {
@@ -332,9 +1573,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0011 funct3=001
     fn lh(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: i16 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             write_rd!(self, rd, { value as u32 });
             end_op!(self)
         } else {
@@ -344,9 +1585,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0011 funct3=010
     fn lw(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: u32 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             write_rd!(self, rd, { value });
             end_op!(self)
         } else {
@@ -356,9 +1597,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0011 funct3=100
     fn lbu(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: u8 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             write_rd!(self, rd, { value as u32 });
             end_op!(self)
         } else {
@@ -368,9 +1609,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0011 funct3=101
     fn lhu(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: u16 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             write_rd!(self, rd, { value as u32 });
             end_op!(self)
         } else {
@@ -380,9 +1621,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_0011 funct3=000
     fn sb(&mut self, rs1: usize, rs2: usize, s_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(s_imm as u32);
-        let value = self.state.x[rs2] as u8;
-        if self.mem.access(addr, MemoryAccess::Store(value)) {
+        let addr = self.state.x.read(rs1).wrapping_add(s_imm as u32);
+        let value = self.state.x.read(rs2) as u8;
+        if self.store(addr, value) {
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -391,9 +1632,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_0011 funct3=001
     fn sh(&mut self, rs1: usize, rs2: usize, s_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(s_imm as u32);
-        let value = self.state.x[rs2] as u16;
-        if self.mem.access(addr, MemoryAccess::Store(value)) {
+        let addr = self.state.x.read(rs1).wrapping_add(s_imm as u32);
+        let value = self.state.x.read(rs2) as u16;
+        if self.store(addr, value) {
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -402,9 +1643,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_0011 funct3=010
     fn sw(&mut self, rs1: usize, rs2: usize, s_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(s_imm as u32);
-        let value = self.state.x[rs2];
-        if self.mem.access(addr, MemoryAccess::Store(value)) {
+        let addr = self.state.x.read(rs1).wrapping_add(s_imm as u32);
+        let value = self.state.x.read(rs2);
+        if self.store(addr, value) {
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -413,56 +1654,62 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=001_0011 funct3=000
     fn addi(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1].wrapping_add(i_imm as u32) });
+        write_rd!(self, rd, {
+            self.state.x.read(rs1).wrapping_add(i_imm as u32)
+        });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=010
     fn slti(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { u32::from((self.state.x[rs1] as i32) < i_imm) });
+        write_rd!(self, rd, {
+            u32::from((self.state.x.read(rs1) as i32) < i_imm)
+        });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=011
     fn sltiu(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { u32::from(self.state.x[rs1] < i_imm as u32) });
+        write_rd!(self, rd, {
+            u32::from(self.state.x.read(rs1) < i_imm as u32)
+        });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=100
     fn xori(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { (self.state.x[rs1] ^ i_imm as u32) });
+        write_rd!(self, rd, { (self.state.x.read(rs1) ^ i_imm as u32) });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=110
     fn ori(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { (self.state.x[rs1] | i_imm as u32) });
+        write_rd!(self, rd, { (self.state.x.read(rs1) | i_imm as u32) });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=111
     fn andi(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        write_rd!(self, rd, { (self.state.x[rs1] & i_imm as u32) });
+        write_rd!(self, rd, { (self.state.x.read(rs1) & i_imm as u32) });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=001 shtype=000_0000
     fn slli(&mut self, rd: usize, rs1: usize, shamt: u32) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1].wrapping_shl(shamt) });
+        write_rd!(self, rd, { self.state.x.read(rs1).wrapping_shl(shamt) });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=101 shtype=000_0000
     fn srli(&mut self, rd: usize, rs1: usize, shamt: u32) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1].wrapping_shr(shamt) });
+        write_rd!(self, rd, { self.state.x.read(rs1).wrapping_shr(shamt) });
         end_op!(self)
     }
 
     //% opcode=001_0011 funct3=101 shtype=010_0000
     fn srai(&mut self, rd: usize, rs1: usize, shamt: u32) -> CpuExit {
         write_rd!(self, rd, {
-            ((self.state.x[rs1] as i32).wrapping_shr(shamt) as u32)
+            ((self.state.x.read(rs1) as i32).wrapping_shr(shamt) as u32)
         });
         end_op!(self)
     }
@@ -470,7 +1717,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0000 funct3=000
     fn add(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            self.state.x[rs1].wrapping_add(self.state.x[rs2])
+            self.state.x.read(rs1).wrapping_add(self.state.x.read(rs2))
         });
         end_op!(self)
     }
@@ -478,7 +1725,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0000 funct3=001
     fn sll(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            (self.state.x[rs1]).wrapping_shl(self.state.x[rs2])
+            (self.state.x.read(rs1)).wrapping_shl(self.state.x.read(rs2))
         });
         end_op!(self)
     }
@@ -486,7 +1733,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0000 funct3=010
     fn slt(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            u32::from((self.state.x[rs1] as i32) < (self.state.x[rs2] as i32))
+            u32::from((self.state.x.read(rs1) as i32) < (self.state.x.read(rs2) as i32))
         });
         end_op!(self)
     }
@@ -494,41 +1741,47 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0000 funct3=011
     fn sltu(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            u32::from(self.state.x[rs1] < self.state.x[rs2])
+            u32::from(self.state.x.read(rs1) < self.state.x.read(rs2))
         });
         end_op!(self)
     }
 
     //% opcode=011_0011 funct7=000_0000 funct3=100
     fn xor(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1] ^ self.state.x[rs2] });
+        write_rd!(self, rd, {
+            self.state.x.read(rs1) ^ self.state.x.read(rs2)
+        });
         end_op!(self)
     }
 
     //% opcode=011_0011 funct7=000_0000 funct3=101
     fn srl(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            self.state.x[rs1].wrapping_shr(self.state.x[rs2])
+            self.state.x.read(rs1).wrapping_shr(self.state.x.read(rs2))
         });
         end_op!(self)
     }
 
     //% opcode=011_0011 funct7=000_0000 funct3=110
     fn or(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1] | self.state.x[rs2] });
+        write_rd!(self, rd, {
+            self.state.x.read(rs1) | self.state.x.read(rs2)
+        });
         end_op!(self)
     }
 
     //% opcode=011_0011 funct7=000_0000 funct3=111
     fn and(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
-        write_rd!(self, rd, { self.state.x[rs1] & self.state.x[rs2] });
+        write_rd!(self, rd, {
+            self.state.x.read(rs1) & self.state.x.read(rs2)
+        });
         end_op!(self)
     }
 
     //% opcode=011_0011 funct7=010_0000 funct3=000
     fn sub(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            self.state.x[rs1].wrapping_sub(self.state.x[rs2])
+            self.state.x.read(rs1).wrapping_sub(self.state.x.read(rs2))
         });
         end_op!(self)
     }
@@ -536,7 +1789,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=010_0000 funct3=101
     fn sra(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            ((self.state.x[rs1] as i32).wrapping_shr(self.state.x[rs2])) as u32
+            ((self.state.x.read(rs1) as i32).wrapping_shr(self.state.x.read(rs2))) as u32
         });
         end_op!(self)
     }
@@ -551,83 +1804,118 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
         end_op!(self)
     }
 
-    //% opcode=111_0011 funct3=000 funct12=0000_0000_0000 rd=0_0000 rs1=0_0000
+    // The privileged instructions without operands share `funct3` = 0, and are told apart by
+    // `funct7` and `rs2`, which together form `funct12`. Decoding `funct7` first leaves room for
+    // `sfence.vma`, which has register operands in `rs1` and `rs2`.
+
+    //% opcode=111_0011 funct3=000 funct7=000_0000 rs2=0_0000 rd=0_0000 rs1=0_0000
     fn ecall(&mut self) -> CpuExit {
+        if self.trap_ecall {
+            self.enter_trap(MachineCsrs::MCAUSE_ECALL_M, 0);
+            return Ok(());
+        }
         end_op!(self, Ecall)
     }
 
-    //% opcode=111_0011 funct3=000 funct12=0000_0000_0001 rd=0_0000 rs1=0_0000
+    //% opcode=111_0011 funct3=000 funct7=000_0000 rs2=0_0001 rd=0_0000 rs1=0_0000
     fn ebreak(&mut self) -> CpuExit {
         end_op!(self, Ebreak)
     }
 
-    //% opcode=111_0011 funct3=001
-    fn csrrw(&mut self, rd: usize, rs1: usize, csr: u32) -> CpuExit {
-        let new = self.state.x[rs1];
+    //% opcode=111_0011 funct3=000 funct7=000_1000 rs2=0_0010 rd=0_0000 rs1=0_0000
+    fn sret(&mut self) -> CpuExit {
+        // Supervisor mode is not implemented.
+        end_op!(self, IllegalInstruction)
+    }
 
-        write_rd!(self, rd, {
-            let mut old: u32 = 0;
-            if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
-                end_op!(self, IllegalInstruction);
-            }
-            old
-        });
+    //% opcode=111_0011 funct3=000 funct7=000_1000 rs2=0_0101 rd=0_0000 rs1=0_0000
+    fn wfi(&mut self) -> CpuExit {
+        // Pending interrupts are checked before every instruction, so there is nothing to wait
+        // for. The spec allows implementing this as a no-op.
+        end_op!(self)
+    }
+
+    //% opcode=111_0011 funct3=000 funct7=000_1001 rd=0_0000
+    fn sfence_vma(&mut self, rs1: usize, rs2: usize) -> CpuExit {
+        let vaddr = (rs1 != 0).then(|| self.state.x.read(rs1));
+        let asid = (rs2 != 0).then(|| self.state.x.read(rs2));
+        if let Some(ref mut callback) = self.on_sfence {
+            callback(vaddr, asid);
+        }
+        end_op!(self)
+    }
+
+    //% opcode=111_0011 funct3=000 funct7=001_1000 rs2=0_0010 rd=0_0000 rs1=0_0000
+    fn mret(&mut self) -> CpuExit {
+        let m = &mut self.state.machine;
+        let mie = if m.mstatus & MachineCsrs::MSTATUS_MPIE != 0 {
+            MachineCsrs::MSTATUS_MIE
+        } else {
+            0
+        };
+        m.mstatus = (m.mstatus & !MachineCsrs::MSTATUS_MIE) | mie | MachineCsrs::MSTATUS_MPIE;
+        let mepc = m.mepc;
+        self.invalidate_reservation();
+        end_jump_op!(self, mepc)
+    }
+
+    // CSR instructions write `rd` last, so it is unaltered if the instruction is illegal.
+    // Per spec, the CSR is not read if `rd` is `x0` for `csrrw[i]`, and not written if the source
+    // is `x0` or zero for the other instructions. This avoids side effects, such as reads of `seed`
+    // consuming entropy.
 
+    //% opcode=111_0011 funct3=001
+    fn csrrw(&mut self, rd: usize, rs1: usize, csr: u32) -> CpuExit {
+        let new = self.state.x.read(rs1);
+        let mut old: u32 = 0;
+        if rd != 0 && !self.access_csr(csr, CsrAccess::Read(&mut old)) {
+            end_op!(self, IllegalInstruction);
+        }
         if !self.access_csr(csr, CsrAccess::Write(new)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
     //% opcode=111_0011 funct3=010
     fn csrrs(&mut self, rd: usize, rs1: usize, csr: u32) -> CpuExit {
-        let mask = self.state.x[rs1];
-
+        let mask = self.state.x.read(rs1);
         let mut old: u32 = 0;
         if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
             end_op!(self, IllegalInstruction);
         }
-        write_rd!(self, rd, { old });
-
         if rs1 != 0 && !self.access_csr(csr, CsrAccess::Write(old | mask)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
     //% opcode=111_0011 funct3=011
     fn csrrc(&mut self, rd: usize, rs1: usize, csr: u32) -> CpuExit {
-        let mask = self.state.x[rs1];
-
+        let mask = self.state.x.read(rs1);
         let mut old: u32 = 0;
         if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
             end_op!(self, IllegalInstruction);
         }
-        write_rd!(self, rd, { old });
-
         if rs1 != 0 && !self.access_csr(csr, CsrAccess::Write(old & !mask)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
     //% opcode=111_0011 funct3=101
     fn csrrwi(&mut self, rd: usize, zimm: u32, csr: u32) -> CpuExit {
-        write_rd!(self, rd, {
-            let mut old: u32 = 0;
-            if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
-                end_op!(self, IllegalInstruction);
-            }
-            old
-        });
-
+        let mut old: u32 = 0;
+        if rd != 0 && !self.access_csr(csr, CsrAccess::Read(&mut old)) {
+            end_op!(self, IllegalInstruction);
+        }
         if !self.access_csr(csr, CsrAccess::Write(zimm)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
@@ -637,12 +1925,10 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
         if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
             end_op!(self, IllegalInstruction);
         }
-        write_rd!(self, rd, { old });
-
-        if !self.access_csr(csr, CsrAccess::Write(old | zimm)) {
+        if zimm != 0 && !self.access_csr(csr, CsrAccess::Write(old | zimm)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
@@ -652,23 +1938,22 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
         if !self.access_csr(csr, CsrAccess::Read(&mut old)) {
             end_op!(self, IllegalInstruction);
         }
-        write_rd!(self, rd, { old });
-
-        if !self.access_csr(csr, CsrAccess::Write(old & !zimm)) {
+        if zimm != 0 && !self.access_csr(csr, CsrAccess::Write(old & !zimm)) {
             end_op!(self, IllegalInstruction);
         }
-
+        write_rd!(self, rd, { old });
         end_op!(self)
     }
 
     //
     // "M" Standard Extension for Integer Multiplication and Division
     //
+    //[rv32m{
 
     //% opcode=011_0011 funct7=000_0001 funct3=000
     fn mul(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            self.state.x[rs1].wrapping_mul(self.state.x[rs2])
+            self.state.x.read(rs1).wrapping_mul(self.state.x.read(rs2))
         });
         end_op!(self)
     }
@@ -676,8 +1961,8 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=001
     fn mulh(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let x = (self.state.x[rs1] as i32) as i64;
-            let y = (self.state.x[rs2] as i32) as i64;
+            let x = (self.state.x.read(rs1) as i32) as i64;
+            let y = (self.state.x.read(rs2) as i32) as i64;
             (x.wrapping_mul(y) >> 32) as u32
         });
         end_op!(self)
@@ -686,8 +1971,8 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=010
     fn mulhsu(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let x = (self.state.x[rs1] as i32) as i64;
-            let y = self.state.x[rs2] as i64;
+            let x = (self.state.x.read(rs1) as i32) as i64;
+            let y = self.state.x.read(rs2) as i64;
             (x.wrapping_mul(y) >> 32) as u32
         });
         end_op!(self)
@@ -696,8 +1981,8 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=011
     fn mulhu(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let x = self.state.x[rs1] as u64;
-            let y = self.state.x[rs2] as u64;
+            let x = self.state.x.read(rs1) as u64;
+            let y = self.state.x.read(rs2) as u64;
             (x.wrapping_mul(y) >> 32) as u32
         });
         end_op!(self)
@@ -706,11 +1991,11 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=100
     fn div(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let y = self.state.x[rs2] as i32;
+            let y = self.state.x.read(rs2) as i32;
             if y == 0 {
                 0xffff_ffff
             } else {
-                let x = self.state.x[rs1] as i32;
+                let x = self.state.x.read(rs1) as i32;
                 x.wrapping_div(y) as u32
             }
         });
@@ -720,11 +2005,11 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=101
     fn divu(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let y = self.state.x[rs2];
+            let y = self.state.x.read(rs2);
             if y == 0 {
                 0xffff_ffff
             } else {
-                self.state.x[rs1].wrapping_div(y)
+                self.state.x.read(rs1).wrapping_div(y)
             }
         });
         end_op!(self)
@@ -733,11 +2018,11 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=110
     fn rem(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let y = self.state.x[rs2] as i32;
+            let y = self.state.x.read(rs2) as i32;
             if y == 0 {
-                self.state.x[rs1]
+                self.state.x.read(rs1)
             } else {
-                let x = self.state.x[rs1] as i32;
+                let x = self.state.x.read(rs1) as i32;
                 x.wrapping_rem(y) as u32
             }
         });
@@ -747,26 +2032,59 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=011_0011 funct7=000_0001 funct3=111
     fn remu(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         write_rd!(self, rd, {
-            let y = self.state.x[rs2];
+            let y = self.state.x.read(rs2);
             if y == 0 {
-                self.state.x[rs1]
+                self.state.x.read(rs1)
             } else {
-                self.state.x[rs1].wrapping_rem(y)
+                self.state.x.read(rs1).wrapping_rem(y)
             }
         });
         end_op!(self)
     }
+    //]rv32m}
+
+    //
+    // "Zicond" Extension for Integer Conditional Operations
+    //
+    //[zicond{
+
+    //% opcode=011_0011 funct7=000_0111 funct3=101
+    fn czero_eqz(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
+        write_rd!(self, rd, {
+            if self.state.x.read(rs2) == 0 {
+                0
+            } else {
+                self.state.x.read(rs1)
+            }
+        });
+        end_op!(self)
+    }
+
+    //% opcode=011_0011 funct7=000_0111 funct3=111
+    fn czero_nez(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
+        write_rd!(self, rd, {
+            if self.state.x.read(rs2) != 0 {
+                0
+            } else {
+                self.state.x.read(rs1)
+            }
+        });
+        end_op!(self)
+    }
+    //]zicond}
 
     //
     // "A" Standard Extension for Atomic Instructions
     //
+    //[rv32a{
 
     //% opcode=010_1111 funct3=010 funct5=0_0010 rs2=0_0000
     fn lr_w(&mut self, rd: usize, rs1: usize, _aq: bool, _rl: bool) -> CpuExit {
-        let addr = self.state.x[rs1];
+        let addr = self.state.x.read(rs1);
         let mut value: u32 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
             self.state.reservation = Some(addr);
+            self.invalidated = false;
             write_rd!(self, rd, { value });
             end_op!(self)
         } else {
@@ -776,84 +2094,94 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_1111 funct3=010 funct5=0_0011
     fn sc_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        let addr = self.state.x[rs1];
-        if self.state.reservation == Some(addr) {
-            let value = self.state.x[rs2];
-            if self.mem.access(addr, MemoryAccess::Store(value)) {
+        let addr = self.state.x.read(rs1);
+        let result = match self.state.reservation {
+            Some(reserved) if reserved == addr => ScResult::Success,
+            Some(reserved) => ScResult::AddressMismatch { reserved },
+            None if self.invalidated => ScResult::Invalidated,
+            None => ScResult::NoReservation,
+        };
+        if result == ScResult::Success {
+            let value = self.state.x.read(rs2);
+            if self.store(addr, value) {
                 write_rd!(self, rd, { 0 });
                 self.state.reservation = None;
+                self.invalidated = false;
+                self.last_sc = Some(result);
                 end_op!(self)
             } else {
                 end_op!(self, IllegalAccess)
             }
         } else {
             write_rd!(self, rd, { 1 });
+            self.last_sc = Some(result);
             end_op!(self)
         }
     }
 
     //% opcode=010_1111 funct3=010 funct5=0_0001
     fn amoswap_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rs2] })
+        amo!(self, rd, rs1, |_| { self.state.x.read(rs2) })
     }
 
     //% opcode=010_1111 funct3=010 funct5=0_0000
     fn amoadd_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, {
-            self.state.x[rd].wrapping_add(self.state.x[rs2])
+        amo!(self, rd, rs1, |value| {
+            value.wrapping_add(self.state.x.read(rs2))
         })
     }
 
     //% opcode=010_1111 funct3=010 funct5=0_0100
     fn amoxor_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rd] ^ self.state.x[rs2] })
+        amo!(self, rd, rs1, |value| { value ^ self.state.x.read(rs2) })
     }
 
     //% opcode=010_1111 funct3=010 funct5=0_1100
     fn amoand_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rd] & self.state.x[rs2] })
+        amo!(self, rd, rs1, |value| { value & self.state.x.read(rs2) })
     }
 
     //% opcode=010_1111 funct3=010 funct5=0_1000
     fn amoor_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rd] | self.state.x[rs2] })
+        amo!(self, rd, rs1, |value| { value | self.state.x.read(rs2) })
     }
 
     //% opcode=010_1111 funct3=010 funct5=1_0000
     fn amomin_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, {
-            (self.state.x[rd] as i32).min(self.state.x[rs2] as i32) as u32
+        amo!(self, rd, rs1, |value| {
+            (value as i32).min(self.state.x.read(rs2) as i32) as u32
         })
     }
 
     //% opcode=010_1111 funct3=010 funct5=1_0100
     fn amomax_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, {
-            (self.state.x[rd] as i32).max(self.state.x[rs2] as i32) as u32
+        amo!(self, rd, rs1, |value| {
+            (value as i32).max(self.state.x.read(rs2) as i32) as u32
         })
     }
 
     //% opcode=010_1111 funct3=010 funct5=1_1000
     fn amominu_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rd].min(self.state.x[rs2]) })
+        amo!(self, rd, rs1, |value| { value.min(self.state.x.read(rs2)) })
     }
 
     //% opcode=010_1111 funct3=010 funct5=1_1100
     fn amomaxu_w(&mut self, rd: usize, rs1: usize, rs2: usize, _aq: bool, _rl: bool) -> CpuExit {
-        amo!(self, rd, rs1, { self.state.x[rd].max(self.state.x[rs2]) })
+        amo!(self, rd, rs1, |value| { value.max(self.state.x.read(rs2)) })
     }
+    //]rv32a}
 
     //
     // "F" Standard Extension for Single-Precision Floating-Point
     //
-    //f{
+    //[rv32fd{
 
     //% opcode=000_0111 funct3=010
     fn flw(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: u32 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
-            self.state.f[rd] = Sf64::from(Sf32(value));
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
+            write_fd!(self, rd, { Sf64::from(Sf32(value)) });
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -862,9 +2190,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_0111 funct3=010
     fn fsw(&mut self, rs1: usize, rs2: usize, s_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(s_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(s_imm as u32);
         let value = Sf32::from(self.state.f[rs2]).0;
-        if self.mem.access(addr, MemoryAccess::Store(value)) {
+        if self.store(addr, value) {
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -982,7 +2310,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnj_s(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let a = Sf32::from(self.state.f[rs1]).0;
         let b = Sf32::from(self.state.f[rs2]).0;
-        self.state.f[rd] = Sf64::from(Sf32((a & 0x7fff_ffff) | (b & 0x8000_0000)));
+        write_fd!(self, rd, {
+            Sf64::from(Sf32((a & 0x7fff_ffff) | (b & 0x8000_0000)))
+        });
         end_op!(self)
     }
 
@@ -990,7 +2320,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnjn_s(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let a = Sf32::from(self.state.f[rs1]).0;
         let b = Sf32::from(self.state.f[rs2]).0;
-        self.state.f[rd] = Sf64::from(Sf32((a & 0x7fff_ffff) | (!b & 0x8000_0000)));
+        write_fd!(self, rd, {
+            Sf64::from(Sf32((a & 0x7fff_ffff) | (!b & 0x8000_0000)))
+        });
         end_op!(self)
     }
 
@@ -998,7 +2330,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnjx_s(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let a = Sf32::from(self.state.f[rs1]).0;
         let b = Sf32::from(self.state.f[rs2]).0;
-        self.state.f[rd] = Sf64::from(Sf32(a ^ (b & 0x8000_0000)));
+        write_fd!(self, rd, { Sf64::from(Sf32(a ^ (b & 0x8000_0000))) });
         end_op!(self)
     }
 
@@ -1009,13 +2341,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                 let a = f32::from(self.state.f[rs1]);
                 let b = f32::from(self.state.f[rs2]);
 
-                if sf::f32_is_signaling_nan(Sf32::from(a))
-                    || sf::f32_is_signaling_nan(Sf32::from(b))
-                {
+                let signaling = sf::f32_is_signaling_nan(Sf32::from(a))
+                    || sf::f32_is_signaling_nan(Sf32::from(b));
+                if signaling {
                     sf::raise_flags(sf::FLAG_INVALID);
                 }
 
                 Sf64::from(match (a.classify(), b.classify()) {
+                    _ if signaling && self.legacy_fmin_fmax => f32::from(Sf32::NAN),
                     (FpCategory::Nan, FpCategory::Nan) => f32::from(Sf32::NAN),
                     (FpCategory::Nan, _) => b,
                     (_, FpCategory::Nan) => a,
@@ -1039,13 +2372,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                 let a = f32::from(self.state.f[rs1]);
                 let b = f32::from(self.state.f[rs2]);
 
-                if sf::f32_is_signaling_nan(Sf32::from(a))
-                    || sf::f32_is_signaling_nan(Sf32::from(b))
-                {
+                let signaling = sf::f32_is_signaling_nan(Sf32::from(a))
+                    || sf::f32_is_signaling_nan(Sf32::from(b));
+                if signaling {
                     sf::raise_flags(sf::FLAG_INVALID);
                 }
 
                 Sf64::from(match (a.classify(), b.classify()) {
+                    _ if signaling && self.legacy_fmin_fmax => f32::from(Sf32::NAN),
                     (FpCategory::Nan, FpCategory::Nan) => f32::from(Sf32::NAN),
                     (FpCategory::Nan, _) => b,
                     (_, FpCategory::Nan) => a,
@@ -1089,7 +2423,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=101_0011 funct7=111_0000 funct3=000 rs2=0_0000
     fn fmv_x_w(&mut self, rd: usize, rs1: usize) -> CpuExit {
-        self.state.x[rd] = Sf32::from(self.state.f[rs1]).0;
+        write_rd!(self, rd, { Sf32::from(self.state.f[rs1]).0 });
         end_op!(self)
     }
 
@@ -1183,20 +2517,20 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=101_0011 funct7=110_1000 rs2=0_0000
     fn fcvt_s_w(&mut self, rd: usize, rs1: usize, rm: u32) -> CpuExit {
         sf_calc!(self, rm, rd, {
-            unsafe { Sf64::from(sf::i32_to_f32(self.state.x[rs1] as i32)) }
+            unsafe { Sf64::from(sf::i32_to_f32(self.state.x.read(rs1) as i32)) }
         });
     }
 
     //% opcode=101_0011 funct7=110_1000 rs2=0_0001
     fn fcvt_s_wu(&mut self, rd: usize, rs1: usize, rm: u32) -> CpuExit {
         sf_calc!(self, rm, rd, {
-            unsafe { Sf64::from(sf::u32_to_f32(self.state.x[rs1])) }
+            unsafe { Sf64::from(sf::u32_to_f32(self.state.x.read(rs1))) }
         });
     }
 
     //% opcode=101_0011 funct7=111_1000 funct3=000 rs2=0_0000
     fn fmv_w_x(&mut self, rd: usize, rs1: usize) -> CpuExit {
-        self.state.f[rd] = Sf64::from(Sf32(self.state.x[rs1]));
+        write_fd!(self, rd, { Sf64::from(Sf32(self.state.x.read(rs1))) });
         end_op!(self)
     }
 
@@ -1206,10 +2540,10 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=000_0111 funct3=011
     fn fld(&mut self, rd: usize, rs1: usize, i_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(i_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(i_imm as u32);
         let mut value: u64 = 0;
-        if self.mem.access(addr, MemoryAccess::Load(&mut value)) {
-            self.state.f[rd] = Sf64(value);
+        if self.mem_access(addr, MemoryAccess::Load(&mut value)) {
+            write_fd!(self, rd, { Sf64(value) });
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -1218,9 +2552,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
 
     //% opcode=010_0111 funct3=011
     fn fsd(&mut self, rs1: usize, rs2: usize, s_imm: i32) -> CpuExit {
-        let addr = self.state.x[rs1].wrapping_add(s_imm as u32);
+        let addr = self.state.x.read(rs1).wrapping_add(s_imm as u32);
         let value = self.state.f[rs2].0;
-        if self.mem.access(addr, MemoryAccess::Store(value)) {
+        if self.store(addr, value) {
             end_op!(self)
         } else {
             end_op!(self, IllegalAccess)
@@ -1310,7 +2644,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnj_d(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let Sf64(a) = self.state.f[rs1];
         let Sf64(b) = self.state.f[rs2];
-        self.state.f[rd] = Sf64((a & 0x7fff_ffff_ffff_ffff) | (b & 0x8000_0000_0000_0000));
+        write_fd!(self, rd, {
+            Sf64((a & 0x7fff_ffff_ffff_ffff) | (b & 0x8000_0000_0000_0000))
+        });
         end_op!(self)
     }
 
@@ -1318,7 +2654,9 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnjn_d(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let Sf64(a) = self.state.f[rs1];
         let Sf64(b) = self.state.f[rs2];
-        self.state.f[rd] = Sf64((a & 0x7fff_ffff_ffff_ffff) | (!b & 0x8000_0000_0000_0000));
+        write_fd!(self, rd, {
+            Sf64((a & 0x7fff_ffff_ffff_ffff) | (!b & 0x8000_0000_0000_0000))
+        });
         end_op!(self)
     }
 
@@ -1326,7 +2664,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     fn fsgnjx_d(&mut self, rd: usize, rs1: usize, rs2: usize) -> CpuExit {
         let Sf64(a) = self.state.f[rs1];
         let Sf64(b) = self.state.f[rs2];
-        self.state.f[rd] = Sf64(a ^ (b & 0x8000_0000_0000_0000));
+        write_fd!(self, rd, { Sf64(a ^ (b & 0x8000_0000_0000_0000)) });
         end_op!(self)
     }
 
@@ -1337,13 +2675,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                 let a = f64::from(self.state.f[rs1]);
                 let b = f64::from(self.state.f[rs2]);
 
-                if sf::f64_is_signaling_nan(Sf64::from(a))
-                    || sf::f64_is_signaling_nan(Sf64::from(b))
-                {
+                let signaling = sf::f64_is_signaling_nan(Sf64::from(a))
+                    || sf::f64_is_signaling_nan(Sf64::from(b));
+                if signaling {
                     sf::raise_flags(sf::FLAG_INVALID);
                 }
 
                 Sf64::from(match (a.classify(), b.classify()) {
+                    _ if signaling && self.legacy_fmin_fmax => f64::from(Sf64::NAN),
                     (FpCategory::Nan, FpCategory::Nan) => f64::from(Sf64::NAN),
                     (FpCategory::Nan, _) => b,
                     (_, FpCategory::Nan) => a,
@@ -1367,13 +2706,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
                 let a = f64::from(self.state.f[rs1]);
                 let b = f64::from(self.state.f[rs2]);
 
-                if sf::f64_is_signaling_nan(Sf64::from(a))
-                    || sf::f64_is_signaling_nan(Sf64::from(b))
-                {
+                let signaling = sf::f64_is_signaling_nan(Sf64::from(a))
+                    || sf::f64_is_signaling_nan(Sf64::from(b));
+                if signaling {
                     sf::raise_flags(sf::FLAG_INVALID);
                 }
 
                 Sf64::from(match (a.classify(), b.classify()) {
+                    _ if signaling && self.legacy_fmin_fmax => f64::from(Sf64::NAN),
                     (FpCategory::Nan, FpCategory::Nan) => f64::from(Sf64::NAN),
                     (FpCategory::Nan, _) => b,
                     (_, FpCategory::Nan) => a,
@@ -1497,14 +2837,14 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% opcode=101_0011 funct7=110_1001 rs2=0_0000
     fn fcvt_d_w(&mut self, rd: usize, rs1: usize, rm: u32) -> CpuExit {
         sf_calc!(self, rm, rd, {
-            unsafe { sf::i32_to_f64(self.state.x[rs1] as i32) }
+            unsafe { sf::i32_to_f64(self.state.x.read(rs1) as i32) }
         });
     }
 
     //% opcode=101_0011 funct7=110_1001 rs2=0_0001
     fn fcvt_d_wu(&mut self, rd: usize, rs1: usize, rm: u32) -> CpuExit {
         sf_calc!(self, rm, rd, {
-            unsafe { sf::u32_to_f64(self.state.x[rs1]) }
+            unsafe { sf::u32_to_f64(self.state.x.read(rs1)) }
         });
     }
 
@@ -1535,11 +2875,16 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
             }
         });
     }
-    //f}
+    //]rv32fd}
 
     //
     // "C" Standard Extension for Compressed Instructions, Version 2.0
     //
+    // Reserved code points decode to `illegal`. This includes shifts with `shamt[5]` set, which
+    // are reserved for custom extensions on RV32C. HINT code points, such as most instructions
+    // with `rd = x0` or a zero immediate, decode to their base instruction, which then has no
+    // effect.
+    //
 
     //% cquad=00 cfunct3=000 cimm4spn=0000_0000
     //    name=c_illegal decomp=illegal
@@ -1562,16 +2907,28 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% cquad=01 cfunct3=010
     //    name=c_li decomp=addi rd=crs1rd rs1=crx0 i_imm=cimmi
     //
-    //% cquad=01 cfunct3=011 crs1rd=0_0010
+    //% cquad=01 cfunct3=011 crs1rd=0_0010 cimm6=00_0000
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=01 cfunct3=011 crs1rd=0_0010 cimm6=_
     //    name=c_addi16sp decomp=addi rd=crsp rs1=crsp i_imm=cimm16sp
     //
-    //% cquad=01 cfunct3=011 crs1rd=_
+    //% cquad=01 cfunct3=011 crs1rd=_ cimm6=00_0000
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=01 cfunct3=011 crs1rd=_ cimm6=_
     //    name=c_lui decomp=lui rd=crs1rd u_imm=cimmui
     //
-    //% cquad=01 cfunct3=100 crs1rd_h2=00
+    //% cquad=01 cfunct3=100 crs1rd_h2=00 cfunct4_l0=1
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=01 cfunct3=100 crs1rd_h2=00 cfunct4_l0=0
     //    name=c_srli decomp=srli rd=crs1rdq rs1=crs1rdq shamt=cimmsh6
     //
-    //% cquad=01 cfunct3=100 crs1rd_h2=01
+    //% cquad=01 cfunct3=100 crs1rd_h2=01 cfunct4_l0=1
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=01 cfunct3=100 crs1rd_h2=01 cfunct4_l0=0
     //    name=c_srai decomp=srai rd=crs1rdq rs1=crs1rdq shamt=cimmsh6
     //
     //% cquad=01 cfunct3=100 crs1rd_h2=10
@@ -1598,17 +2955,26 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% cquad=01 cfunct3=111
     //    name=c_bnez decomp=bne rs1=crs1rdq rs2=crx0 b_imm=cimmb
     //
-    //% cquad=10 cfunct3=000
+    //% cquad=10 cfunct3=000 cfunct4_l0=1
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=10 cfunct3=000 cfunct4_l0=0
     //    name=c_slli decomp=slli rd=crs1rd rs1=crs1rd shamt=cimmsh6
     //
-    //% cquad=10 cfunct3=010
+    //% cquad=10 cfunct3=010 crs1rd=0_0000
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=10 cfunct3=010 crs1rd=_
     //    name=c_lwsp decomp=lw rd=crs1rd rs1=crsp i_imm=cimmlwsp
     //
-    //% cquad=10 cfunct3=100 cfunct4_l0=0 crs2=0_0000
+    //% cquad=10 cfunct3=100 cfunct4_l0=0 crs2=0_0000 crs1rd=0_0000
+    //    name=c_illegal decomp=illegal
+    //
+    //% cquad=10 cfunct3=100 cfunct4_l0=0 crs2=0_0000 crs1rd=_
     //    name=c_jr decomp=jalr rd=crx0 rs1=crs1rd i_imm=czero
     //
     //% cquad=10 cfunct3=100 cfunct4_l0=0 crs2=_
-    //    name=c_mv decomp=addi rd=crs1rd rs1=crs2 i_imm=czero
+    //    name=c_mv decomp=add rd=crs1rd rs1=crx0 rs2=crs2
     //
     //% cquad=10 cfunct3=100 cfunct4_l0=1 crs2=0_0000 crs1rd=0_0000
     //    name=c_ebreak decomp=ebreak
@@ -1622,7 +2988,7 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //% cquad=10 cfunct3=110
     //    name=c_swsp decomp=sw rs1=crsp rs2=crs2 s_imm=cimmswsp
     //
-    //f{
+    //[rv32fd{
     //% cquad=00 cfunct3=001
     //    name=c_fld decomp=fld rd=crs2q rs1=crs1rdq i_imm=cimmd
     //
@@ -1646,5 +3012,5 @@ impl<'s, 'm, 'c, M: 'm + Memory, C: 'c + Clock> Interp<'s, 'm, 'c, M, C> {
     //
     //% cquad=10 cfunct3=111
     //    name=c_fswsp decomp=fsw rs1=crsp rs2=crs2 s_imm=cimmswsp
-    //f}
+    //]rv32fd}
 }
@@ -27,6 +27,11 @@ macro_rules! end_jump_op {
             }
         }
 
+        $interp.clock.on_branch_taken();
+        $interp
+            .state
+            .machine
+            .count_event(MachineCsrs::HPM_EVENT_BRANCH_TAKEN);
         $interp.state.pc = pc;
         return Ok(());
     }};
@@ -49,6 +54,11 @@ macro_rules! end_branch_op {
             }
         }
 
+        $interp.clock.on_branch_taken();
+        $interp
+            .state
+            .machine
+            .count_event(MachineCsrs::HPM_EVENT_BRANCH_TAKEN);
         $interp.state.pc = pc;
         return Ok(());
     }};
@@ -59,28 +69,53 @@ macro_rules! end_branch_op {
 macro_rules! write_rd {
     ( $interp:expr , $rd:expr , $code:block ) => {{
         if $rd != 0 {
-            $interp.state.x[$rd] = $code;
+            let value: u32 = $code;
+            let value = $interp.inject_fault($rd, value);
+            if $interp.logging_writes() {
+                let old = $interp.state.x.read($rd);
+                $interp.log_write(WriteLocation::X($rd), old as u64, value as u64);
+            }
+            $interp.state.x.write($rd, value);
+        }
+    }};
+}
+
+/// Wrap a block, writing the result to floating-point register `$rd`.
+#[cfg(feature = "rv32fd")]
+macro_rules! write_fd {
+    ( $interp:expr , $rd:expr , $code:block ) => {{
+        let value: Sf64 = $code;
+        if $interp.logging_writes() {
+            let old = $interp.state.f[$rd];
+            $interp.log_write(WriteLocation::F($rd), old.0, value.0);
         }
+        $interp.state.f[$rd] = value;
     }};
 }
 
 /// Macro used to implement AMO instructions.
+/// The block is given the loaded value, and calculates the value to store.
+#[cfg(feature = "rv32a")]
 macro_rules! amo {
-    ( $interp:expr , $rd:expr , $rs1:expr , $code:block ) => {{
-        let addr = $interp.state.x[$rs1];
+    ( $interp:expr , $rd:expr , $rs1:expr , |$value:pat_param| $code:block ) => {{
+        let addr = $interp.state.x.read($rs1);
         if addr % 4 != 0 {
             end_op!($interp, MisalignedAccess);
         }
 
         let mut value: u32 = 0;
-        if !$interp.mem.access(addr, MemoryAccess::Load(&mut value)) {
+        if !$interp.mem_access(addr, MemoryAccess::Load(&mut value)) {
             end_op!($interp, IllegalAccess);
         }
 
+        // Calculate before writing `rd`, which may be the same register as `rs2`.
+        let result: u32 = {
+            let $value = value;
+            $code
+        };
         write_rd!($interp, $rd, { value });
 
-        let value: u32 = $code;
-        if !$interp.mem.access(addr, MemoryAccess::Store(value)) {
+        if !$interp.store(addr, result) {
             end_op!($interp, IllegalAccess);
         }
 
@@ -99,7 +134,7 @@ macro_rules! sf_wrap {
                 // Reserved values.
                 5 | 6 => end_op!($interp, IllegalInstruction),
                 // Dynamic rounding mode.
-                7 => ($interp.state.fcsr & 0b1110_0000) >> 5,
+                7 => $interp.state.frm(),
                 // Inline rounding mode. Values match with SoftFloat.
                 _ => $rm,
             } as u8);
@@ -122,11 +157,11 @@ macro_rules! sf_wrap {
 #[cfg(feature = "rv32fd")]
 macro_rules! sf_calc {
     ( $interp:expr , $rd:expr , $code:block ) => {{
-        $interp.state.f[$rd] = sf_wrap!($interp, $code);
+        write_fd!($interp, $rd, { sf_wrap!($interp, $code) });
         end_op!($interp)
     }};
     ( $interp:expr , $rm:expr , $rd:expr , $code:block ) => {{
-        $interp.state.f[$rd] = sf_wrap!($interp, $rm, $code);
+        write_fd!($interp, $rd, { sf_wrap!($interp, $rm, $code) });
         end_op!($interp)
     }};
 }
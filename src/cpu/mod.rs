@@ -7,10 +7,16 @@
 #[macro_use]
 mod macros;
 
+mod compare;
 mod interp;
 mod op;
+mod trace;
 mod types;
+mod write_log;
 
+pub use self::compare::*;
 pub use self::interp::*;
 pub use self::op::*;
+pub use self::trace::*;
 pub use self::types::*;
+pub use self::write_log::*;
@@ -1,10 +1,30 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
 /// A large enum holding a parsed instruction and its arguments.
+///
+/// With the `serde` feature, this enum is serializable using Serde.
 #[allow(missing_docs)]
 #[derive(Copy,Clone,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Op {
     //% variants
 }
 
+/// The kind of an `Op`, identifying the instruction without its operands.
+///
+/// There is one variant for every variant of `Op`, with the same name, so patterns can be matched
+/// with `op.kind() == OpKind::Add` instead of destructuring the fields.
+///
+/// With the `serde` feature, this enum is serializable using Serde.
+#[allow(missing_docs)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OpKind {
+    //% kinds
+}
+
 impl Op {
     /// Parse an instruction. Returns `None` on failure.
     pub fn parse(instr: u32) -> Option<Op> {
@@ -16,6 +36,163 @@ impl Op {
     pub fn parse_c(instr: u16) -> Option<Op> {
         //% parse_c
     }
+
+    /// Parse an instruction as fetched, returning it with its encoded length in bytes. Returns
+    /// `None` on failure.
+    ///
+    /// Because compressed instructions parse to their expanded equivalent, the `Op` alone does not
+    /// tell how many bytes it occupied. Compressed instructions occupy only the lower 16 bits of
+    /// `instr`, as in `Interp::last_fetch`, and are only parsed with the `rv32c` feature.
+    pub fn decode(instr: u32) -> Option<(Op, u32)> {
+        match instr_len(instr) {
+            #[cfg(feature = "rv32c")]
+            2 => Op::parse_c(instr as u16).map(|op| (op, 2)),
+            4 => Op::parse(instr).map(|op| (op, 4)),
+            _ => None,
+        }
+    }
+
+    /// The kind of the instruction, without its operands.
+    pub fn kind(&self) -> OpKind {
+        match *self {
+            //% kind
+        }
+    }
+
+    /// The assembler mnemonic of the instruction, e.g. `"fadd.s"`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            //% names
+        }
+    }
+
+    /// The registers read by the instruction, in operand order.
+    ///
+    /// Registers are reported as encoded, so this may include `x0`.
+    pub fn reads(&self) -> impl Iterator<Item = Reg> {
+        let regs: [Option<Reg>; 3] = match *self {
+            //% reads
+        };
+        regs.into_iter().flatten()
+    }
+
+    /// The register written by the instruction, if any.
+    ///
+    /// The register is reported as encoded, so this may be `x0`, which is never actually written.
+    pub fn writes(&self) -> Option<Reg> {
+        match *self {
+            //% writes
+        }
+    }
+}
+
+/// The length in bytes of an instruction, determined from its lowest bits: 4 if they are `11`, and
+/// 2 for a compressed instruction otherwise.
+pub fn instr_len(instr: u32) -> u32 {
+    if instr & 3 == 3 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Disassemble an instruction, e.g. `0x00a5_0533` to `"add a0, a0, a0"`. Returns `None` if the
+/// instruction cannot be parsed.
+///
+/// See the `Display` implementation of `Op` for the format.
+pub fn disasm(instr: u32) -> Option<String> {
+    Op::parse(instr).map(|op| op.to_string())
+}
+
+/// Disassemble a rv32c instruction. Returns `None` if the instruction cannot be parsed.
+///
+/// Compressed instructions are parsed to their expanded equivalent, so this returns the expanded
+/// form, e.g. `0x0505` (`c.addi a0, 1`) to `"addi a0, a0, 1"`.
+#[cfg(feature = "rv32c")]
+pub fn disasm_c(instr: u16) -> Option<String> {
+    Op::parse_c(instr).map(|op| op.to_string())
+}
+
+/// Formats the instruction in assembler syntax, e.g. `"lw a0, 8(sp)"`.
+///
+/// Registers use their ABI names. Immediates are decimal, except for the upper immediate of `lui`
+/// and `auipc`, and CSR numbers, which are hexadecimal. Branch and jump targets are offsets
+/// relative to the instruction. The rounding mode is only shown if it is not dynamic.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            //% display
+        }
+    }
+}
+
+/// The `.aq` and `.rl` suffix of an atomic instruction mnemonic.
+#[cfg(feature = "rv32a")]
+fn aqrl(aq: bool, rl: bool) -> &'static str {
+    match (aq, rl) {
+        (false, false) => "",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (true, true) => ".aqrl",
+    }
+}
+
+/// The rounding mode operand of a floating-point instruction, empty if dynamic.
+#[cfg(feature = "rv32fd")]
+fn rm_operand(rm: u32) -> &'static str {
+    match rm {
+        0 => ", rne",
+        1 => ", rtz",
+        2 => ", rdn",
+        3 => ", rup",
+        4 => ", rmm",
+        7 => "",
+        _ => ", invalid",
+    }
+}
+
+/// The predecessor or successor set of a `fence`, e.g. `"rw"`.
+fn fence_set(bits: u32) -> String {
+    "iorw"
+        .chars()
+        .enumerate()
+        .filter(|&(i, _)| bits & (8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// A register operand of an `Op`, tagged with its register file.
+///
+/// With the `serde` feature, this enum is serializable using Serde.
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Reg {
+    /// An integer register, by index.
+    X(usize),
+    /// A floating-point register, by index.
+    F(usize),
+}
+
+/// Formats the register using its ABI name, e.g. `"a0"` or `"fa0"`.
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const X_NAMES: [&str; 32] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+            "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+            "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+            "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+        ];
+        const F_NAMES: [&str; 32] = [
+            "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+            "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+            "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+            "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+        ];
+        match *self {
+            Reg::X(reg) => f.write_str(X_NAMES[reg]),
+            Reg::F(reg) => f.write_str(F_NAMES[reg]),
+        }
+    }
 }
 
 //
@@ -35,6 +212,7 @@ fn funct3(instr: u32) -> u32 {
     (instr & 0b0000_0000_0000_0000_0111_0000_0000_0000) >> 12
 }
 
+#[cfg(feature = "rv32a")]
 fn funct5(instr: u32) -> u32 {
     (instr & 0b1111_1000_0000_0000_0000_0000_0000_0000) >> 27
 }
@@ -43,10 +221,6 @@ fn funct7(instr: u32) -> u32 {
     (instr & 0b1111_1110_0000_0000_0000_0000_0000_0000) >> 25
 }
 
-fn funct12(instr: u32) -> u32 { 
-    (instr & 0b1111_1111_1111_0000_0000_0000_0000_0000) >> 20
-}
-
 fn shtype(instr: u32) -> u32 {
     (instr & 0b1111_1110_0000_0000_0000_0000_0000_0000) >> 25
 }
@@ -111,10 +285,12 @@ fn shamt(instr: u32) -> u32 {
     (instr & 0b0000_0001_1111_0000_0000_0000_0000_0000) >> 20
 }
 
+#[cfg(feature = "rv32a")]
 fn aq(instr: u32) -> bool {
     (instr & 0b0000_0100_0000_0000_0000_0000_0000_0000) != 0
 }
 
+#[cfg(feature = "rv32a")]
 fn rl(instr: u32) -> bool {
     (instr & 0b0000_0010_0000_0000_0000_0000_0000_0000) != 0
 }
@@ -125,11 +301,11 @@ fn rm(instr: u32) -> u32 {
 }
 
 fn pred(instr: u32) -> u32 {
-    (instr & 0b0000_1111_0000_0000_0000_0000_0000_0000) >> 19
+    (instr & 0b0000_1111_0000_0000_0000_0000_0000_0000) >> 24
 }
 
 fn succ(instr: u32) -> u32 {
-    (instr & 0b0000_0000_1111_0000_0000_0000_0000_0000) >> 15
+    (instr & 0b0000_0000_1111_0000_0000_0000_0000_0000) >> 20
 }
 
 fn csr(instr: u32) -> u32 {
@@ -165,6 +341,10 @@ mod rv32c {
     pub fn crs2_h2(instr: u16) -> u16 {
         (instr & 0b0000_0000_0110_0000) >> 5
     }
+    pub fn cimm6(instr: u16) -> u16 {
+        (instr & 0b0001_0000_0000_0000) >> 7 |
+        (instr & 0b0000_0000_0111_1100) >> 2
+    }
 
     // Hardwired register fields.
     pub fn crx0(_instr: u16) -> usize {
@@ -0,0 +1,268 @@
+use std::io::{self, Read, Write};
+
+use crate::cpu::types::{CpuState, Memory, MemoryAccess, RegFile};
+use crate::cpu::write_log::WriteLocation;
+#[cfg(feature = "rv32fd")]
+use crate::softfloat::Sf64;
+
+/// Magic bytes at the start of a binary trace.
+pub const TRACE_MAGIC: [u8; 4] = *b"RVTR";
+/// Version of the binary trace format written by `TraceWriter`.
+pub const TRACE_VERSION: u8 = 1;
+
+const TAG_X: u8 = 0;
+const TAG_F: u8 = 1;
+const TAG_MEM: u8 = 2;
+
+/// A record of a single executed instruction in a binary trace.
+///
+/// Only the register files, memory and the PC are recorded, so changes to CSRs such as `fcsr` and
+/// the reservation slot are not.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// Address of the instruction.
+    pub pc: u32,
+    /// Raw bits of the instruction, as returned by `Interp::last_fetch`.
+    pub instr: u32,
+    /// The PC after the instruction.
+    pub next_pc: u32,
+    /// The values written by the instruction, zero-extended to 64 bits, in order.
+    pub writes: Vec<(WriteLocation, u64)>,
+}
+
+impl TraceRecord {
+    /// Apply the writes of the instruction and the new PC to state and memory.
+    ///
+    /// Replaying all records of a trace in order reconstructs the state evolution without
+    /// executing instructions. Returns `false` if a memory store failed.
+    pub fn apply<R: RegFile, M: Memory>(&self, state: &mut CpuState<R>, mem: &mut M) -> bool {
+        let mut ok = true;
+        for &(location, value) in &self.writes {
+            match location {
+                WriteLocation::X(i) => state.x.write(i, value as u32),
+                #[cfg(feature = "rv32fd")]
+                WriteLocation::F(i) => state.f[i] = Sf64(value),
+                #[cfg(not(feature = "rv32fd"))]
+                WriteLocation::F(_) => {}
+                WriteLocation::Mem { addr, size } => {
+                    let bytes = value.to_le_bytes();
+                    for i in 0..size {
+                        let access = MemoryAccess::Store(bytes[i as usize]);
+                        ok &= mem.access(addr.wrapping_add(i), access);
+                    }
+                }
+            }
+        }
+        state.pc = self.next_pc;
+        ok
+    }
+
+    /// Encode the record, in the format described at `TraceWriter`.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.pc.to_le_bytes());
+        buf.extend_from_slice(&self.instr.to_le_bytes());
+        buf.extend_from_slice(&self.next_pc.to_le_bytes());
+        buf.push(self.writes.len() as u8);
+        for &(location, value) in &self.writes {
+            match location {
+                WriteLocation::X(i) => {
+                    buf.extend_from_slice(&[TAG_X, i as u8]);
+                    buf.extend_from_slice(&(value as u32).to_le_bytes());
+                }
+                WriteLocation::F(i) => {
+                    buf.extend_from_slice(&[TAG_F, i as u8]);
+                    buf.extend_from_slice(&value.to_le_bytes());
+                }
+                WriteLocation::Mem { addr, size } => {
+                    buf.push(TAG_MEM);
+                    buf.extend_from_slice(&addr.to_le_bytes());
+                    buf.push(size as u8);
+                    buf.extend_from_slice(&value.to_le_bytes()[..size as usize]);
+                }
+            }
+        }
+    }
+}
+
+/// Writes a compact binary trace of executed instructions, used with `Interp::trace`.
+///
+/// The trace starts with `TRACE_MAGIC` and a `TRACE_VERSION` byte. Every instruction executed,
+/// including one that stops the virtual CPU, is followed by a record. All integers are
+/// little-endian:
+///
+/// - `u32` PC, `u32` raw instruction bits, `u32` PC after the instruction,
+/// - `u8` number of writes, followed by each write as a `u8` tag and:
+///   - `0`: `u8` integer register index, `u32` value,
+///   - `1`: `u8` floating-point register index, `u64` value,
+///   - `2`: `u32` store address, `u8` size, and `size` bytes of data.
+///
+/// Only writes are recorded, so unchanged registers take no space. The first I/O error stops
+/// tracing, and is kept in `error`.
+pub struct TraceWriter {
+    out: Box<dyn Write>,
+    buf: Vec<u8>,
+    writes: Vec<(WriteLocation, u64)>,
+    held: Option<Vec<u8>>,
+    error: Option<io::Error>,
+}
+
+impl TraceWriter {
+    /// Create a writer, writing the header to `out` immediately.
+    pub fn new(out: Box<dyn Write>) -> io::Result<Self> {
+        let mut out = out;
+        out.write_all(&TRACE_MAGIC)?;
+        out.write_all(&[TRACE_VERSION])?;
+        Ok(TraceWriter {
+            out,
+            buf: Vec::new(),
+            writes: Vec::new(),
+            held: None,
+            error: None,
+        })
+    }
+
+    /// The I/O error that stopped tracing, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Collect a write for the current instruction.
+    pub(crate) fn push_write(&mut self, location: WriteLocation, value: u64) {
+        self.writes.push((location, value));
+    }
+
+    /// Write the record of the current instruction, with the writes collected so far.
+    pub(crate) fn finish(&mut self, pc: u32, instr: u32, next_pc: u32) {
+        let record = TraceRecord {
+            pc,
+            instr,
+            next_pc,
+            writes: std::mem::take(&mut self.writes),
+        };
+        if self.error.is_some() {
+            return;
+        }
+        self.buf.clear();
+        record.encode(&mut self.buf);
+        match self.held {
+            Some(ref mut held) => held.extend_from_slice(&self.buf),
+            None => {
+                if let Err(err) = self.out.write_all(&self.buf) {
+                    self.error = Some(err);
+                }
+            }
+        }
+    }
+
+    /// Hold back records finished from now on, until `release` is called.
+    pub(crate) fn hold(&mut self) {
+        self.held = Some(Vec::new());
+    }
+
+    /// Stop holding back records, writing the held records if `keep` is set, or discarding them.
+    pub(crate) fn release(&mut self, keep: bool) {
+        let held = self.held.take().unwrap_or_default();
+        if keep && self.error.is_none() {
+            if let Err(err) = self.out.write_all(&held) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Reads the records of a binary trace written by `TraceWriter`.
+pub struct TraceReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Create a reader, reading and checking the header.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        inner.read_exact(&mut header)?;
+        if header[..4] != TRACE_MAGIC {
+            return Err(invalid_data("magic mismatch, likely not a trace"));
+        }
+        if header[4] != TRACE_VERSION {
+            return Err(invalid_data("unsupported trace version"));
+        }
+        Ok(TraceReader { inner })
+    }
+
+    /// Read the next record, or `None` at the end of the trace.
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut first = [0u8; 1];
+        if self.inner.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let mut rest = [0u8; 12];
+        self.inner.read_exact(&mut rest)?;
+        let word = |i: usize| u32::from_le_bytes([rest[i], rest[i + 1], rest[i + 2], rest[i + 3]]);
+        let pc = u32::from_le_bytes([first[0], rest[0], rest[1], rest[2]]);
+        let instr = word(3);
+        let next_pc = word(7);
+        let count = rest[11];
+
+        let mut writes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let tag = self.read_u8()?;
+            let write = match tag {
+                TAG_X => (WriteLocation::X(self.read_index()?), self.read_le(4)?),
+                TAG_F => (WriteLocation::F(self.read_index()?), self.read_le(8)?),
+                TAG_MEM => {
+                    let addr = self.read_le(4)? as u32;
+                    let size = self.read_u8()? as u32;
+                    if size > 8 {
+                        return Err(invalid_data("store size out of range"));
+                    }
+                    (WriteLocation::Mem { addr, size }, self.read_le(size)?)
+                }
+                _ => return Err(invalid_data("unknown write tag")),
+            };
+            writes.push(write);
+        }
+        Ok(Some(TraceRecord {
+            pc,
+            instr,
+            next_pc,
+            writes,
+        }))
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.inner.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_index(&mut self) -> io::Result<usize> {
+        match self.read_u8()? {
+            i @ 0..=31 => Ok(i as usize),
+            _ => Err(invalid_data("register index out of range")),
+        }
+    }
+
+    /// Read a little-endian value of `size` bytes, zero-extended.
+    fn read_le(&mut self, size: u32) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.inner.read_exact(&mut bytes[..size as usize])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
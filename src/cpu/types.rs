@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 use std::mem::size_of;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::cpu::op::Op;
+use crate::cpu::op::{Op, OpKind, Reg};
 #[cfg(feature = "rv32fd")]
 use crate::softfloat::Sf64;
 
@@ -20,8 +23,28 @@ pub enum CpuError {
 
     /// Tried to fetch the next instruction from a bad address.
     ///
-    /// This error is typically fatal. State is unaltered.
-    IllegalFetch,
+    /// `fell_through` is set if the previous instruction continued sequentially to this address,
+    /// rather than jumping or taking a branch. This usually means execution ran past the end of
+    /// code, for example a function missing its `ret`, or a loaded image that is too short. It is
+    /// not set for the first instruction executed by an `Interp`, or if the PC was changed from
+    /// outside. This error is typically fatal. State is unaltered.
+    IllegalFetch {
+        /// Whether the PC was reached sequentially from the previous instruction.
+        fell_through: bool,
+    },
+
+    /// Fetched the first half of a 32-bit instruction, but not the second half.
+    ///
+    /// This is only raised with the `rv32c` feature, where instructions are fetched in halves, and
+    /// usually means the instruction straddles the end of a memory region. `pc` is the address of
+    /// the instruction, and `addr` is the address of the half that failed. This error is typically
+    /// fatal. State is unaltered.
+    TruncatedFetch {
+        /// Address of the instruction.
+        pc: u32,
+        /// Address of the second half of the instruction.
+        addr: u32,
+    },
 
     /// Tried to execute an invalid instruction.
     ///
@@ -55,18 +78,266 @@ pub enum CpuError {
 
     /// The `Clock` indicated the execution quota was exceeded.
     ///
-    /// This is typically handled by the caller and resumed from. State is unaltered.
+    /// This is typically handled by the caller and resumed from. State is unaltered, and the
+    /// instruction at `pc` was not yet fetched, so resuming after adjusting the quota continues
+    /// exactly where execution left off.
     QuotaExceeded,
+
+    /// The wall-clock timeout of `Interp::run_with_timeout` expired.
+    ///
+    /// This is typically handled by the caller. State is unaltered, and the instruction at `pc`
+    /// was not yet fetched, so execution can be resumed.
+    Timeout,
+
+    /// The stop flag of `Interp::run_cancellable` was set.
+    ///
+    /// This is typically handled by the caller. State is unaltered, and the instruction at `pc`
+    /// was not yet fetched, so execution can be resumed.
+    Cancelled,
+
+    /// Tried to fetch an instruction from an address written by the guest.
+    ///
+    /// This is only raised when `Interp::written` is enabled, and may indicate self-modifying code
+    /// or injected code. `addr` is the first written byte of the instruction. State is unaltered,
+    /// and the instruction is not executed.
+    ExecuteAfterWrite {
+        /// The written address.
+        addr: u32,
+    },
+
+    /// The stack pointer is not 16-byte aligned after an instruction.
+    ///
+    /// This is only raised when `Interp::check_stack_alignment` is enabled, and indicates a
+    /// violation of the calling convention. `pc` is advanced to the next instruction, and the
+    /// instruction that misaligned the stack pointer has completed.
+    StackMisaligned,
+
+    /// An instruction was executed again, without any register or memory state changing since it
+    /// was last executed.
+    ///
+    /// This is only raised when `Interp::loop_detection` is enabled, and is a heuristic for a
+    /// program that hangs. `pc` is the address of the repeated instruction, which has completed.
+    SuspectedInfiniteLoop {
+        /// Address of the repeated instruction.
+        pc: u32,
+    },
+}
+
+impl CpuError {
+    /// Whether the error is typically fatal, as documented for each variant.
+    ///
+    /// These are the errors raised when an instruction cannot be fetched or completed, which
+    /// `Interp::step_atomic` rolls back.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            CpuError::MisalignedFetch
+                | CpuError::IllegalFetch { .. }
+                | CpuError::TruncatedFetch { .. }
+                | CpuError::IllegalInstruction
+                | CpuError::IllegalAccess
+                | CpuError::MisalignedAccess
+        )
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::MisalignedFetch => f.write_str("misaligned fetch"),
+            CpuError::IllegalFetch { fell_through } => {
+                if fell_through {
+                    f.write_str("illegal fetch after falling through")
+                } else {
+                    f.write_str("illegal fetch")
+                }
+            }
+            CpuError::TruncatedFetch { pc, addr } => {
+                write!(f, "truncated fetch at {:#x}, failed at {:#x}", pc, addr)
+            }
+            CpuError::IllegalInstruction => f.write_str("illegal instruction"),
+            CpuError::IllegalAccess => f.write_str("illegal access"),
+            CpuError::MisalignedAccess => f.write_str("misaligned access"),
+            CpuError::Ecall => f.write_str("ecall"),
+            CpuError::Ebreak => f.write_str("ebreak"),
+            CpuError::QuotaExceeded => f.write_str("quota exceeded"),
+            CpuError::Timeout => f.write_str("timeout"),
+            CpuError::Cancelled => f.write_str("cancelled"),
+            CpuError::ExecuteAfterWrite { addr } => {
+                write!(f, "execute after write to {:#x}", addr)
+            }
+            CpuError::StackMisaligned => f.write_str("stack misaligned"),
+            CpuError::SuspectedInfiniteLoop { pc } => {
+                write!(f, "suspected infinite loop at {:#x}", pc)
+            }
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+/// A stop reason of the virtual CPU, with context, returned by `Interp::try_run`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RunError {
+    /// The reason the virtual CPU stopped.
+    pub cause: CpuError,
+    /// The instruction that caused the stop, if it could be loaded and parsed.
+    pub op: Option<Op>,
+    /// Address of the instruction that caused the stop.
+    pub pc: u32,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}", self.cause, self.pc)
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// A system call made using `ecall`, returned by `Interp::run_to_syscall`.
+///
+/// Decoded following the Linux calling convention, which is also used by the RISC-V proxy kernel:
+/// the number is in `a7`, and arguments are in `a0` through `a5`. The result is returned in `a0`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Syscall {
+    /// The system call number, from `a7`.
+    pub number: u32,
+    /// The arguments, from `a0` through `a5`.
+    pub args: [u32; 6],
+}
+
+impl Syscall {
+    /// Decode the system call from the registers of the virtual CPU.
+    pub fn from_state<R: RegFile>(state: &CpuState<R>) -> Self {
+        let mut args = [0; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = state.x.read(10 + i);
+        }
+        Syscall {
+            number: state.x.read(17),
+            args,
+        }
+    }
+}
+
+/// Number of memory accesses performed by an `Interp`, returned by `Interp::access_counts`.
+///
+/// Every call into `Memory` is counted, including those that fail. With the `rv32c` feature, a
+/// 32-bit instruction is fetched in two 16-bit halves, and counts as two fetches. An atomic memory
+/// operation counts as both a load and a store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AccessCounts {
+    /// Number of data loads.
+    pub loads: u64,
+    /// Number of data stores.
+    pub stores: u64,
+    /// Number of instruction fetches.
+    pub fetches: u64,
+}
+
+/// A CSR implemented by the interpreter, returned by `Interp::supported_csrs`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CsrInfo {
+    /// Address of the CSR.
+    pub id: u32,
+    /// Assembler name of the CSR, e.g. `"mstatus"`.
+    pub name: String,
+    /// Whether the CSR can be written. Writes may still ignore some or all bits.
+    pub writable: bool,
+}
+
+/// A histogram of data access alignments, used with `Interp::alignment_stats`.
+///
+/// Every data load and store is recorded with its width and its offset within a cache line,
+/// including those that fail. An atomic memory operation is recorded as both a load and a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AccessAlignmentStats {
+    /// Size of a cache line in bytes, a power of two.
+    pub line_size: u32,
+    /// Number of accesses, keyed by width in bytes and offset within a cache line.
+    pub histogram: BTreeMap<(u32, u32), u64>,
+    /// Number of accesses that straddle two cache lines.
+    pub straddling: u64,
+    /// Number of accesses narrower than a 32-bit word.
+    pub sub_word: u64,
+}
+
+impl AccessAlignmentStats {
+    /// Create empty statistics for the given cache line size, which must be a power of two.
+    pub fn new(line_size: u32) -> Self {
+        assert!(
+            line_size.is_power_of_two(),
+            "line size must be a power of two"
+        );
+        AccessAlignmentStats {
+            line_size,
+            ..Default::default()
+        }
+    }
+
+    /// Record an access of `size` bytes at `addr`.
+    pub fn record(&mut self, addr: u32, size: u32) {
+        let offset = addr & (self.line_size - 1);
+        *self.histogram.entry((size, offset)).or_default() += 1;
+        if offset + size > self.line_size {
+            self.straddling += 1;
+        }
+        if size < 4 {
+            self.sub_word += 1;
+        }
+    }
+
+    /// Total number of accesses recorded.
+    pub fn total(&self) -> u64 {
+        self.histogram.values().sum()
+    }
+}
+
+/// A trait used by the interpreter to access the integer registers.
+///
+/// The default implementation is a plain array, but custom implementations can be used to log
+/// register writes, or to model shadow or banked registers.
+///
+/// Register `x0` is hardwired to zero by the interpreter: `write` is never called for register 0.
+/// Implementations must return 0 when reading register 0.
+pub trait RegFile {
+    /// Read integer register `reg`, which is in the range 0 to 31.
+    fn read(&self, reg: usize) -> u32;
+
+    /// Write integer register `reg`, which is in the range 1 to 31.
+    fn write(&mut self, reg: usize, value: u32);
+}
+
+impl RegFile for [u32; 32] {
+    #[inline]
+    fn read(&self, reg: usize) -> u32 {
+        self[reg]
+    }
+
+    #[inline]
+    fn write(&mut self, reg: usize, value: u32) {
+        self[reg] = value;
+    }
 }
 
 /// Struct containing all virtual CPU state.
 ///
+/// The integer registers are a plain array by default, but may be any `RegFile` implementation.
+///
+/// Equality and hashing compare the complete architectural state, including floating-point
+/// registers by bit pattern and the reservation slot.
+///
 /// With the `serde` feature, this structure is serializable using Serde.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-pub struct CpuState {
+pub struct CpuState<R: RegFile = [u32; 32]> {
     /// Integer registers.
-    pub x: [u32; 32],
+    pub x: R,
 
     /// Floating-point registers.
     #[cfg(feature = "rv32fd")]
@@ -80,8 +351,149 @@ pub struct CpuState {
 
     /// Reservation slot for the atomic extension.
     ///
-    /// When modifying memory outside the interpreter, this should usually be cleared.
+    /// This holds the address of the last `lr.w`, and `sc.w` succeeds only if it matches. The
+    /// interpreter does not track which memory is modified outside of it, so the reservation
+    /// must be cleared with `clear_reservation` when:
+    ///
+    /// - the host writes to guest memory directly, e.g. to emulate DMA,
+    /// - another hart sharing the same memory stores to it,
+    /// - the state is switched to a different context, e.g. a guest thread or trap handler.
+    ///
+    /// Failing to do so may cause an `sc.w` to succeed even though the reserved word changed.
     pub reservation: Option<u32>,
+
+    /// Machine-mode CSRs used for trap delivery.
+    pub machine: MachineCsrs,
+}
+
+/// Machine-mode CSRs used for trap delivery, part of `CpuState`.
+///
+/// Only machine mode is implemented, so `mstatus` always reports the previous privilege mode
+/// (`MPP`) as machine mode. Only the `MIE` and `MPIE` fields of `mstatus` are writable.
+///
+/// Interrupts are raised by the host setting bits in `mip`, for example from `Clint::pending`. An
+/// interrupt is taken before the next instruction when it is pending in `mip`, enabled in `mie`,
+/// and interrupts are globally enabled by `MSTATUS_MIE`. The supervisor interrupt bits may also
+/// be raised, but are taken in machine mode.
+///
+/// With the `serde` feature, this structure is serializable using Serde.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MachineCsrs {
+    /// Machine status, a combination of `MSTATUS_*` bits.
+    pub mstatus: u32,
+    /// Enabled interrupts, a combination of `MIP_*` bits.
+    pub mie: u32,
+    /// Pending interrupts, a combination of `MIP_*` bits.
+    pub mip: u32,
+    /// Trap handler base address in the upper 30 bits, and mode in the lower 2 bits.
+    ///
+    /// In direct mode (0), all traps jump to the base address. In vectored mode (1), interrupts
+    /// jump to the base address plus 4 times the interrupt number.
+    pub mtvec: u32,
+    /// Scratch register for the trap handler.
+    pub mscratch: u32,
+    /// Address of the instruction that was interrupted or trapped.
+    pub mepc: u32,
+    /// Cause of the last trap. The top bit is set for interrupts.
+    pub mcause: u32,
+    /// Additional information about the last trap, such as a faulting address.
+    pub mtval: u32,
+    /// Upper half of machine status, a combination of `MSTATUSH_*` bits.
+    ///
+    /// Only the `MBE` field is implemented, selecting big-endian data accesses. Only available with
+    /// the `big-endian` feature.
+    #[cfg(feature = "big-endian")]
+    pub mstatush: u32,
+    /// Inhibit bits of the performance-monitor counters, bit `n` for `mhpmcounter<n>`.
+    ///
+    /// Only bits 3 through 31 are implemented. The `cycle` and `instret` counters are provided by
+    /// the `Clock`, and cannot be inhibited.
+    pub mcountinhibit: u32,
+    /// Event selectors `mhpmevent3` through `mhpmevent31`, one of the `HPM_EVENT_*` values.
+    pub mhpmevent: [u32; 29],
+    /// Performance-monitor counters `mhpmcounter3` through `mhpmcounter31`.
+    ///
+    /// A counter is incremented for every event matching its selector. Counters with an unknown
+    /// event selector never change.
+    pub mhpmcounter: [u64; 29],
+}
+
+impl MachineCsrs {
+    /// Bit in `mstatus` that globally enables interrupts.
+    pub const MSTATUS_MIE: u32 = 1 << 3;
+    /// Bit in `mstatus` holding the value of `MSTATUS_MIE` before the last trap.
+    pub const MSTATUS_MPIE: u32 = 1 << 7;
+    /// Field in `mstatus` holding the privilege mode before the last trap.
+    pub const MSTATUS_MPP: u32 = 3 << 11;
+
+    /// Bit in `mstatush` selecting big-endian data accesses in machine mode.
+    #[cfg(feature = "big-endian")]
+    pub const MSTATUSH_MBE: u32 = 1 << 5;
+
+    /// Bit in `mip` and `mie` for a supervisor software interrupt.
+    pub const MIP_SSIP: u32 = 1 << 1;
+    /// Bit in `mip` and `mie` for a machine software interrupt.
+    pub const MIP_MSIP: u32 = 1 << 3;
+    /// Bit in `mip` and `mie` for a supervisor timer interrupt.
+    pub const MIP_STIP: u32 = 1 << 5;
+    /// Bit in `mip` and `mie` for a machine timer interrupt.
+    pub const MIP_MTIP: u32 = 1 << 7;
+    /// Bit in `mip` and `mie` for a supervisor external interrupt.
+    pub const MIP_SEIP: u32 = 1 << 9;
+    /// Bit in `mip` and `mie` for a machine external interrupt.
+    pub const MIP_MEIP: u32 = 1 << 11;
+
+    /// Bit set in `mcause` for interrupts.
+    pub const MCAUSE_INTERRUPT: u32 = 1 << 31;
+    /// Exception code in `mcause` for an illegal instruction, used by `Interp::trap_illegal`.
+    pub const MCAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+    /// Exception code in `mcause` for an environment call from user mode.
+    pub const MCAUSE_ECALL_U: u32 = 8;
+    /// Exception code in `mcause` for an environment call from supervisor mode.
+    pub const MCAUSE_ECALL_S: u32 = 9;
+    /// Exception code in `mcause` for an environment call from machine mode.
+    ///
+    /// Only machine mode is implemented, so this is the code used by `Interp::trap_ecall`.
+    pub const MCAUSE_ECALL_M: u32 = 11;
+
+    /// Interrupt numbers, in order of decreasing priority.
+    pub const INTERRUPT_PRIORITY: [u32; 6] = [11, 3, 7, 9, 1, 5];
+
+    /// Event selector that counts nothing.
+    pub const HPM_EVENT_NONE: u32 = 0;
+    /// Event selector that counts executed instructions, like `Clock::progress`.
+    pub const HPM_EVENT_INSTRET: u32 = 1;
+    /// Event selector that counts taken branches and jumps, like `Clock::on_branch_taken`.
+    pub const HPM_EVENT_BRANCH_TAKEN: u32 = 2;
+    /// Event selector that counts data loads, including those of atomic instructions.
+    pub const HPM_EVENT_LOAD: u32 = 3;
+    /// Event selector that counts stores, including those of atomic instructions.
+    pub const HPM_EVENT_STORE: u32 = 4;
+
+    /// The highest priority interrupt that is pending and enabled, if interrupts are enabled.
+    ///
+    /// Returns the interrupt number, which is also the bit in `mip` and the `mcause` code.
+    pub fn pending_interrupt(&self) -> Option<u32> {
+        if self.mstatus & Self::MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.mip & self.mie;
+        Self::INTERRUPT_PRIORITY
+            .iter()
+            .copied()
+            .find(|&code| pending & (1 << code) != 0)
+    }
+
+    /// Increment the performance-monitor counters selecting the given event, unless inhibited.
+    pub fn count_event(&mut self, event: u32) {
+        let counters = self.mhpmevent.iter().zip(self.mhpmcounter.iter_mut());
+        for (i, (&selector, counter)) in counters.enumerate() {
+            if selector == event && self.mcountinhibit & (1 << (i + 3)) == 0 {
+                *counter = counter.wrapping_add(1);
+            }
+        }
+    }
 }
 
 impl CpuState {
@@ -89,17 +501,145 @@ impl CpuState {
     ///
     /// All registers are initialized to zero.
     pub fn new(pc: u32) -> Self {
+        CpuState::with_regs(pc, [0; 32])
+    }
+
+    /// Create a new state instance, with the given `pc` starting value and stack pointer.
+    ///
+    /// The stack pointer `x2` is set to `sp`, which is usually the top of the stack, and should be
+    /// 16-byte aligned per the calling convention. Programs that read `argc` and `argv` expect
+    /// them in `a0` and `a1`, which can be set afterwards. All other registers are initialized
+    /// to zero.
+    pub fn new_with_sp(pc: u32, sp: u32) -> Self {
+        let mut state = CpuState::new(pc);
+        state.x[2] = sp;
+        state
+    }
+}
+
+impl<R: RegFile> CpuState<R> {
+    /// Create a new state instance, with the given `pc` starting value and integer registers.
+    ///
+    /// All other registers are initialized to zero.
+    pub fn with_regs(pc: u32, x: R) -> Self {
         CpuState {
-            x: [0; 32],
+            x,
             #[cfg(feature = "rv32fd")]
             f: [Sf64(0); 32],
             pc,
             fcsr: 0,
             reservation: None,
+            machine: MachineCsrs::default(),
+        }
+    }
+
+    /// Get the accumulated floating-point exception flags, the `fflags` field of `fcsr`.
+    pub fn fflags(&self) -> u32 {
+        self.fcsr & 0x1f
+    }
+
+    /// Set the accumulated floating-point exception flags, the `fflags` field of `fcsr`.
+    ///
+    /// Only the lower 5 bits of `value` are used.
+    pub fn set_fflags(&mut self, value: u32) {
+        self.fcsr = (self.fcsr & !0x1f) | (value & 0x1f);
+    }
+
+    /// Clear the accumulated floating-point exception flags.
+    pub fn clear_fflags(&mut self) {
+        self.set_fflags(0);
+    }
+
+    /// Get the dynamic rounding mode, the `frm` field of `fcsr`.
+    pub fn frm(&self) -> u32 {
+        (self.fcsr >> 5) & 0x7
+    }
+
+    /// Set the dynamic rounding mode, the `frm` field of `fcsr`.
+    ///
+    /// Only the lower 3 bits of `value` are used.
+    pub fn set_frm(&mut self, value: u32) {
+        self.fcsr = (self.fcsr & !0xe0) | ((value & 0x7) << 5);
+    }
+
+    /// Get the raw bits of all floating-point registers.
+    ///
+    /// Only available with the `rv32fd` feature.
+    #[cfg(feature = "rv32fd")]
+    pub fn f_bits(&self) -> [u64; 32] {
+        let mut bits = [0; 32];
+        for (dst, src) in bits.iter_mut().zip(&self.f) {
+            *dst = src.0;
+        }
+        bits
+    }
+
+    /// Set all floating-point registers from raw bits.
+    ///
+    /// Single-precision values must be NaN-boxed, with the upper 32 bits set. Only available with
+    /// the `rv32fd` feature.
+    #[cfg(feature = "rv32fd")]
+    pub fn set_f_bits(&mut self, bits: [u64; 32]) {
+        for (dst, &src) in self.f.iter_mut().zip(&bits) {
+            *dst = Sf64(src);
         }
     }
+
+    /// Clear the reservation slot, causing the next `sc.w` to fail.
+    ///
+    /// See `reservation` for when this is necessary.
+    pub fn clear_reservation(&mut self) {
+        self.reservation = None;
+    }
 }
 
+/// Values that can be loaded from or stored to memory: the integer types of 1, 2, 4 or 8 bytes.
+///
+/// This converts between values and their little-endian byte representation, which allows
+/// `Memory` implementations to handle any access width without unsafe code. The trait is sealed,
+/// so implementations can rely on `SIZE` being at most 8.
+pub trait MemoryValue: Copy + sealed::Sealed {
+    /// Size of the value in bytes.
+    const SIZE: usize;
+
+    /// Decode a value from exactly `SIZE` little-endian bytes.
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    /// Encode the value into exactly `SIZE` little-endian bytes.
+    fn to_le_slice(self, bytes: &mut [u8]);
+}
+
+mod sealed {
+    /// Prevents implementing `MemoryValue` outside this crate.
+    pub trait Sealed {}
+}
+
+macro_rules! impl_memory_value {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl sealed::Sealed for $t {}
+
+            impl MemoryValue for $t {
+                const SIZE: usize = size_of::<$t>();
+
+                #[inline]
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    let mut buf = [0u8; size_of::<$t>()];
+                    buf.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(buf)
+                }
+
+                #[inline]
+                fn to_le_slice(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_memory_value!(u8, i8, u16, i16, u32, i32, u64, i64);
+
 /// Types of memory access used with the `Memory` trait.
 pub enum MemoryAccess<'a, T: Copy + 'a> {
     /// Load a value from memory, placing the result in the contained reference.
@@ -110,37 +650,170 @@ pub enum MemoryAccess<'a, T: Copy + 'a> {
     Exec(&'a mut T),
 }
 
+/// Permissions of an address in memory, returned by `Memory::permissions`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Perms {
+    /// Whether loads are allowed.
+    pub read: bool,
+    /// Whether stores are allowed.
+    pub write: bool,
+    /// Whether instruction fetches are allowed.
+    pub exec: bool,
+}
+
+impl Perms {
+    /// No access allowed.
+    pub const NONE: Perms = Perms {
+        read: false,
+        write: false,
+        exec: false,
+    };
+    /// All types of access allowed.
+    pub const ALL: Perms = Perms {
+        read: true,
+        write: true,
+        exec: true,
+    };
+}
+
 /// A trait used by the interpreter to implement loads and stores.
 pub trait Memory {
     /// Access the given address in memory.
-    fn access<T: Copy>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool;
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool;
+
+    /// The permissions of the given address, for introspection by tools such as debuggers.
+    ///
+    /// The interpreter does not use this, and instead relies on the result of `access`. The
+    /// default implementation returns `None`, meaning the permissions are unknown.
+    fn permissions(&self, _addr: u32) -> Option<Perms> {
+        None
+    }
+
+    /// Called by the interpreter when a load or store of the given address failed, before raising
+    /// `CpuError::IllegalAccess`.
+    ///
+    /// If this returns `true`, the address is assumed to be accessible now, for example because a
+    /// page was mapped, and just the failed access is retried once. The instruction is not
+    /// restarted, so earlier accesses of the same instruction are not repeated. This allows the
+    /// host to implement demand paging or lazy allocation. Instruction fetches are not retried.
+    ///
+    /// The default implementation returns `false`, so the access fails.
+    fn handle_fault(&mut self, _addr: u32, _is_write: bool) -> bool {
+        false
+    }
+
+    /// Transfer a block of bytes starting at the given address, for bulk transfers by the host,
+    /// such as DMA devices and loaders.
+    ///
+    /// When `write` is `true`, `buf` is stored to memory. Otherwise, `buf` is filled with the
+    /// contents of memory. Addresses wrap around at the end of the address space. Returns `false`
+    /// if any byte could not be accessed, in which case the transfer may be partially done.
+    ///
+    /// The default implementation performs a byte access for every byte. Memory backed by a
+    /// contiguous region can override this to copy the block at once.
+    fn access_block(&mut self, addr: u32, buf: &mut [u8], write: bool) -> bool {
+        buf.iter_mut().enumerate().all(|(i, byte)| {
+            let addr = addr.wrapping_add(i as u32);
+            if write {
+                self.access(addr, MemoryAccess::Store(*byte))
+            } else {
+                self.access(addr, MemoryAccess::Load(byte))
+            }
+        })
+    }
+}
+
+/// A mutable reference to a `Memory` can be used in place of the memory itself, which is useful
+/// with wrappers such as those in the `mem` module.
+impl<M: Memory + ?Sized> Memory for &mut M {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        (**self).access(addr, access)
+    }
+
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        (**self).permissions(addr)
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        (**self).handle_fault(addr, is_write)
+    }
+
+    fn access_block(&mut self, addr: u32, buf: &mut [u8], write: bool) -> bool {
+        (**self).access_block(addr, buf, write)
+    }
 }
 
 /// A simple byte array can be used to implement a block of DRAM.
 ///
 /// This is typically wrapped by a `Memory` implementation that does access control and translates
 /// addresses, because by default all types of access are allowed, and the base address is 0.
+///
+/// Values are stored in little-endian byte order, regardless of the host.
 impl Memory for [u8] {
-    fn access<T: Copy>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
         let addr = addr as usize;
-        let end = addr + size_of::<T>();
-        if let Some(slice) = self.get_mut(addr..end) {
-            let ptr = slice.as_mut_ptr() as *mut T;
-            match access {
-                MemoryAccess::Load(dest) | MemoryAccess::Exec(dest) => {
-                    unsafe { *dest = *ptr };
-                }
-                MemoryAccess::Store(value) => {
-                    unsafe { *ptr = value };
+        let end = match addr.checked_add(T::SIZE) {
+            Some(end) => end,
+            None => return false,
+        };
+        match self.get_mut(addr..end) {
+            Some(bytes) => {
+                match access {
+                    MemoryAccess::Load(dest) | MemoryAccess::Exec(dest) => {
+                        *dest = T::from_le_slice(bytes);
+                    }
+                    MemoryAccess::Store(value) => value.to_le_slice(bytes),
                 }
+                true
             }
-            true
+            None => false,
+        }
+    }
+
+    /// All types of access are allowed within the array, and none outside of it.
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        if (addr as usize) < self.len() {
+            Some(Perms::ALL)
         } else {
-            false
+            Some(Perms::NONE)
+        }
+    }
+
+    fn access_block(&mut self, addr: u32, buf: &mut [u8], write: bool) -> bool {
+        let addr = addr as usize;
+        let end = match addr.checked_add(buf.len()) {
+            Some(end) => end,
+            None => return false,
+        };
+        match self.get_mut(addr..end) {
+            Some(bytes) if write => bytes.copy_from_slice(buf),
+            Some(bytes) => buf.copy_from_slice(bytes),
+            None => return false,
         }
+        true
     }
 }
 
+/// The outcome of the last `sc.w`, returned by `Interp::last_sc_result`.
+///
+/// Only available with the `rv32a` feature.
+#[cfg(feature = "rv32a")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScResult {
+    /// The reservation matched, and the store was performed.
+    Success,
+    /// There was no reservation, because no `lr.w` preceded it, or a previous `sc.w` consumed it.
+    NoReservation,
+    /// The reservation is for a different address.
+    AddressMismatch {
+        /// The reserved address.
+        reserved: u32,
+    },
+    /// The reservation was cleared by `Interp::invalidate_reservation`, or by entering or
+    /// returning from a trap handler.
+    Invalidated,
+}
+
 /// A trait used by the interpreter to implement the clock CSRs.
 pub trait Clock {
     /// Read the `cycle` CSR, which counts the number of CPU cycles executed.
@@ -166,12 +839,37 @@ pub trait Clock {
     /// Check execution quotas. Called at the very start of `Interp::step`.
     ///
     /// When this return `false`, the virtual CPU is stopped with `CpuError::QuotaExceeded`. This
-    /// allows the simulator to implement time slicing.
+    /// allows the simulator to implement time slicing. No instruction is skipped or executed
+    /// twice when execution is resumed after raising the quota.
     ///
     /// This method is optional, and always returns `true` if not implemented.
     fn check_quota(&self) -> bool {
         true
     }
+
+    /// Called when a branch is taken or a jump is performed, before `progress` is called for the
+    /// instruction.
+    ///
+    /// A pipeline model can use this to charge a penalty for the flush. This method is optional,
+    /// and does nothing if not implemented.
+    fn on_branch_taken(&mut self) {}
+
+    /// Called when an instruction traps, stopping the virtual CPU with the given cause.
+    ///
+    /// If the trap was raised by a decoded instruction, this is called after `progress` for that
+    /// instruction. It is not called for `CpuError::QuotaExceeded`, nor for traps delivered to the
+    /// guest, which call `on_trap_taken` instead. This method is optional, and does nothing if not
+    /// implemented.
+    fn on_trap(&mut self, _cause: CpuError) {}
+
+    /// Called when a trap is delivered to the guest trap handler at `mtvec`.
+    ///
+    /// `mcause` is the value written to the `mcause` CSR, which has `MachineCsrs::MCAUSE_INTERRUPT`
+    /// set for interrupts. Besides interrupts, this is called for environment calls with
+    /// `Interp::trap_ecall`, and illegal instructions with `Interp::trap_illegal`. Execution
+    /// continues at the handler, so `on_trap` is not called for these. This method is optional,
+    /// and does nothing if not implemented.
+    fn on_trap_taken(&mut self, _mcause: u32) {}
 }
 
 /// A simple implementation of the `Clock` trait.
@@ -211,3 +909,150 @@ impl Clock for SimpleClock {
         self.instret = self.instret.wrapping_add(1);
     }
 }
+
+/// A `Clock` implementation with a timer running at a fixed fraction of the CPU frequency.
+///
+/// Every instruction takes one cycle, and `time` advances once every `cycles_per_tick` cycles.
+/// This gives guest delay loops a plausible relationship between `time` and `cycle`, while staying
+/// deterministic.
+///
+/// With the `serde` feature, this structure is serializable using Serde.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FixedFreqClock {
+    /// Cycle counter CSR.
+    pub cycle: u64,
+    /// Instruction counter CSR.
+    pub instret: u64,
+    /// Number of cycles per tick of the `time` CSR.
+    pub cycles_per_tick: u64,
+}
+
+impl FixedFreqClock {
+    /// Cycles per tick for a 10 MHz timer with a 1 GHz CPU clock.
+    pub const STANDARD_CYCLES_PER_TICK: u64 = 100;
+
+    /// Create an instance with counters starting at 0.
+    ///
+    /// Panics if `cycles_per_tick` is 0.
+    pub fn new(cycles_per_tick: u64) -> Self {
+        assert!(cycles_per_tick > 0, "cycles_per_tick must be nonzero");
+        FixedFreqClock {
+            cycle: 0,
+            instret: 0,
+            cycles_per_tick,
+        }
+    }
+
+    /// Create an instance with a 10 MHz timer and a 1 GHz CPU clock.
+    pub fn standard() -> Self {
+        FixedFreqClock::new(Self::STANDARD_CYCLES_PER_TICK)
+    }
+}
+
+impl Clock for FixedFreqClock {
+    fn read_cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    fn read_time(&self) -> u64 {
+        self.cycle / self.cycles_per_tick
+    }
+
+    fn read_instret(&self) -> u64 {
+        self.instret
+    }
+
+    fn progress(&mut self, _op: &Op) {
+        self.cycle = self.cycle.wrapping_add(1);
+        self.instret = self.instret.wrapping_add(1);
+    }
+}
+
+/// A `Clock` implementation modeling an in-order pipeline with a register scoreboard.
+///
+/// Every instruction issues in one cycle, but its result only becomes available after the extra
+/// latency given by the `latency` function. An instruction that reads a register whose result is
+/// not yet available stalls until it is. Dependencies are found using `Op::reads` and
+/// `Op::writes`. The `time` CSR returns the cycle counter.
+///
+/// With `ScoreboardClock::load_use`, this models the classic load-use hazard of a 5-stage
+/// pipeline: an instruction that uses the result of the immediately preceding load stalls for one
+/// cycle.
+#[derive(Clone, Debug)]
+pub struct ScoreboardClock {
+    /// Cycle counter CSR, including stalls.
+    pub cycle: u64,
+    /// Instruction counter CSR.
+    pub instret: u64,
+    /// Number of cycles spent stalled on dependencies.
+    pub stalls: u64,
+    /// Extra cycles before the result of an instruction is available to following instructions.
+    pub latency: fn(&Op) -> u64,
+    /// Cycle at which each register becomes available, integer registers followed by
+    /// floating-point registers.
+    ready: [u64; 64],
+}
+
+impl ScoreboardClock {
+    /// Create an instance with the given latency function, and counters starting at 0.
+    pub fn new(latency: fn(&Op) -> u64) -> Self {
+        ScoreboardClock {
+            cycle: 0,
+            instret: 0,
+            stalls: 0,
+            latency,
+            ready: [0; 64],
+        }
+    }
+
+    /// Create an instance where loads have one cycle of extra latency, and all other
+    /// instructions have none.
+    pub fn load_use() -> Self {
+        ScoreboardClock::new(|op| match op.kind() {
+            OpKind::Lb | OpKind::Lh | OpKind::Lw | OpKind::Lbu | OpKind::Lhu => 1,
+            #[cfg(feature = "rv32a")]
+            OpKind::LrW => 1,
+            #[cfg(feature = "rv32fd")]
+            OpKind::Flw | OpKind::Fld => 1,
+            _ => 0,
+        })
+    }
+
+    /// Index of a register in the scoreboard. Returns `None` for `x0`, which is never written.
+    fn slot(reg: Reg) -> Option<usize> {
+        match reg {
+            Reg::X(0) => None,
+            Reg::X(i) => Some(i),
+            Reg::F(i) => Some(32 + i),
+        }
+    }
+}
+
+impl Clock for ScoreboardClock {
+    fn read_cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    fn read_time(&self) -> u64 {
+        self.cycle
+    }
+
+    fn read_instret(&self) -> u64 {
+        self.instret
+    }
+
+    fn progress(&mut self, op: &Op) {
+        let issue = op
+            .reads()
+            .filter_map(Self::slot)
+            .map(|i| self.ready[i])
+            .fold(self.cycle, u64::max);
+        self.stalls += issue - self.cycle;
+        self.cycle = issue + 1;
+        if let Some(i) = op.writes().and_then(Self::slot) {
+            self.ready[i] = self.cycle + (self.latency)(op);
+        }
+        self.instret = self.instret.wrapping_add(1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+/// A location written by an instruction, part of a `WriteRecord`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WriteLocation {
+    /// An integer register, by index.
+    X(usize),
+    /// A floating-point register, by index.
+    F(usize),
+    /// Memory written by a store.
+    Mem {
+        /// Address of the store.
+        addr: u32,
+        /// Size of the store in bytes.
+        size: u32,
+    },
+}
+
+/// A single write performed by an instruction, recorded in a `WriteLog`.
+///
+/// Values are zero-extended to 64 bits. Writing `old` back to `location` and setting the PC to
+/// `pc` undoes the write.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WriteRecord {
+    /// Address of the instruction that performed the write.
+    pub pc: u32,
+    /// The location written.
+    pub location: WriteLocation,
+    /// The value before the write.
+    pub old: u64,
+    /// The value after the write.
+    pub new: u64,
+}
+
+/// A ring buffer of register and memory writes, used with `Interp::write_log`.
+///
+/// When full, the oldest records are discarded. Only the register files and memory are recorded,
+/// so changes to the PC, CSRs such as `fcsr` and the reservation slot are not.
+#[derive(Clone, Debug)]
+pub struct WriteLog {
+    records: VecDeque<WriteRecord>,
+    capacity: usize,
+}
+
+impl WriteLog {
+    /// Create an empty log, holding at most `capacity` records.
+    pub fn new(capacity: usize) -> Self {
+        WriteLog {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add a record, discarding the oldest record if the log is full.
+    pub fn push(&mut self, record: WriteRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Remove and return the most recent record.
+    pub fn pop(&mut self) -> Option<WriteRecord> {
+        self.records.pop_back()
+    }
+
+    /// The records in the log, oldest first.
+    pub fn records(&self) -> &VecDeque<WriteRecord> {
+        &self.records
+    }
+
+    /// Number of records in the log.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether the log holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Discard all records.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
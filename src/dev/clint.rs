@@ -0,0 +1,109 @@
+use crate::cpu::{Clock, MachineCsrs, Memory, MemoryAccess, MemoryValue};
+
+/// A core-local interruptor (CLINT) for a single hart, using the common SiFive layout.
+///
+/// This exposes the memory-mapped `msip`, `mtimecmp` and `mtime` registers. The `mtime` register
+/// follows the `time` value of a `Clock`, which is sampled by calling `sync`. The interpreter has
+/// no access to devices, so this is typically done by the host between steps.
+///
+/// Guest writes to `mtime` do not affect the `Clock`. Instead, the CLINT keeps an offset to the
+/// clock, so the `time` CSR and `mtime` register may diverge after such a write.
+///
+/// The CLINT has no connection to the interpreter, so the host should copy `pending` into the
+/// `mip` field of `CpuState::machine` between steps, to deliver timer and software interrupts.
+pub struct Clint {
+    /// Machine software interrupt pending.
+    pub msip: bool,
+    /// Machine timer compare value.
+    pub mtimecmp: u64,
+    /// Last `time` value sampled from the clock.
+    time: u64,
+    /// Offset added to the clock to produce `mtime`.
+    offset: u64,
+}
+
+impl Clint {
+    /// Offset of the `msip` register.
+    pub const MSIP: u32 = 0x0000;
+    /// Offset of the `mtimecmp` register.
+    pub const MTIMECMP: u32 = 0x4000;
+    /// Offset of the `mtime` register.
+    pub const MTIME: u32 = 0xbff8;
+    /// Size of the device in the address space.
+    pub const SIZE: u32 = 0x1_0000;
+
+    /// Create an instance with no pending interrupts.
+    ///
+    /// `mtimecmp` starts at its maximum value, so the timer interrupt is not raised until the
+    /// guest programs it.
+    pub fn new() -> Self {
+        Clint {
+            msip: false,
+            mtimecmp: u64::MAX,
+            time: 0,
+            offset: 0,
+        }
+    }
+
+    /// Sample the current time from the clock.
+    pub fn sync<C: Clock>(&mut self, clock: &C) {
+        self.time = clock.read_time();
+    }
+
+    /// Read the `mtime` register.
+    pub fn mtime(&self) -> u64 {
+        self.time.wrapping_add(self.offset)
+    }
+
+    /// Write the `mtime` register.
+    pub fn set_mtime(&mut self, value: u64) {
+        self.offset = value.wrapping_sub(self.time);
+    }
+
+    /// Whether the timer interrupt is raised, because `mtime >= mtimecmp`.
+    pub fn timer_pending(&self) -> bool {
+        self.mtime() >= self.mtimecmp
+    }
+
+    /// Pending interrupts, as a combination of `MachineCsrs::MIP_*` bits.
+    pub fn pending(&self) -> u32 {
+        let mut mip = 0;
+        if self.msip {
+            mip |= MachineCsrs::MIP_MSIP;
+        }
+        if self.timer_pending() {
+            mip |= MachineCsrs::MIP_MTIP;
+        }
+        mip
+    }
+}
+
+impl Memory for Clint {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        let (base, value, size) = match addr {
+            Self::MSIP..=0x0003 => (Self::MSIP, u64::from(self.msip), 4),
+            Self::MTIMECMP..=0x4007 => (Self::MTIMECMP, self.mtimecmp, 8),
+            Self::MTIME..=0xbfff => (Self::MTIME, self.mtime(), 8),
+            _ => return false,
+        };
+
+        // Use a byte buffer for the register, so 32-bit halves can be accessed.
+        let mut buf = value.to_le_bytes();
+        match access {
+            MemoryAccess::Load(_) => Memory::access(&mut buf[..size], addr - base, access),
+            MemoryAccess::Store(_) => {
+                if !Memory::access(&mut buf[..size], addr - base, access) {
+                    return false;
+                }
+                let value = u64::from_le_bytes(buf);
+                match base {
+                    Self::MSIP => self.msip = value & 1 != 0,
+                    Self::MTIMECMP => self.mtimecmp = value,
+                    _ => self.set_mtime(value),
+                }
+                true
+            }
+            MemoryAccess::Exec(_) => false,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::mem::size_of;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue};
+
+/// A source of random bits, used by `EntropyDevice` and the `seed` CSR.
+pub trait EntropySource {
+    /// Produce the next 32 random bits.
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Any closure returning a `u32` can be used as an entropy source, e.g. to wrap a host RNG.
+impl<F: FnMut() -> u32> EntropySource for F {
+    fn next_u32(&mut self) -> u32 {
+        self()
+    }
+}
+
+/// A deterministic entropy source, based on the SplitMix64 generator.
+///
+/// This is not suitable for real cryptographic use, but two instances created with the same seed
+/// produce the exact same sequence, so a recorded run replays identically.
+///
+/// With the `serde` feature, this structure is serializable using Serde.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SeededEntropy {
+    /// Generator state.
+    pub state: u64,
+}
+
+impl SeededEntropy {
+    /// Create an instance with the given seed.
+    pub fn new(seed: u64) -> Self {
+        SeededEntropy { state: seed }
+    }
+}
+
+impl EntropySource for SeededEntropy {
+    fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        ((z ^ (z >> 31)) >> 32) as u32
+    }
+}
+
+/// A memory-mapped entropy register.
+///
+/// The device consists of a single 32-bit register. Every load returns fresh bits from the
+/// `EntropySource`, consuming one 32-bit value per load regardless of width. Stores are accepted
+/// and ignored, and instruction fetches fail.
+pub struct EntropyDevice<R: EntropySource> {
+    /// The source of random bits.
+    pub source: R,
+}
+
+impl<R: EntropySource> EntropyDevice<R> {
+    /// Size of the device in the address space.
+    pub const SIZE: u32 = 4;
+
+    /// Create a device reading from the given source.
+    pub fn new(source: R) -> Self {
+        EntropyDevice { source }
+    }
+}
+
+impl<R: EntropySource> Memory for EntropyDevice<R> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        if addr as usize + size_of::<T>() > Self::SIZE as usize {
+            return false;
+        }
+        match access {
+            MemoryAccess::Load(dest) => {
+                let mut buf = self.source.next_u32().to_le_bytes();
+                Memory::access(&mut buf[..], addr, MemoryAccess::Load(dest))
+            }
+            MemoryAccess::Store(_) => true,
+            MemoryAccess::Exec(_) => false,
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! Simple memory-mapped devices.
+//!
+//! Each device implements `Memory`, using addresses relative to the start of the device. Like a
+//! `[u8]` block of DRAM, they are meant to be placed in a memory map by a wrapping `Memory`
+//! implementation that translates addresses.
+
+#![allow(clippy::new_without_default)]
+
+mod clint;
+mod entropy;
+mod uart;
+
+pub use self::clint::*;
+pub use self::entropy::*;
+pub use self::uart::*;
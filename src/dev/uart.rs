@@ -0,0 +1,141 @@
+use std::io::{ErrorKind, Read, Write};
+use std::mem::size_of;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue};
+
+/// A minimal 16550-compatible UART.
+///
+/// Bytes written to the transmit holding register are written to `tx`, and bytes read from the
+/// receive buffer register are read from `rx`. The line status register reports data ready when
+/// a byte is available from `rx`, and the transmitter is always reported ready.
+///
+/// The remaining registers are plain storage without any effect, except for the interrupt
+/// identification register, which always reports no pending interrupts. The divisor latch is not
+/// modeled, so baud rate configuration written by the guest is simply accepted.
+///
+/// Registers are `stride` bytes apart. Classic 16550 layouts use a stride of 1, but many SoCs
+/// place registers on 4-byte boundaries. An access must start at a register boundary, and may
+/// not be wider than the stride.
+///
+/// Reading the line status register may read from `rx` to check if data is available, and will
+/// block if `rx` blocks. Readers that return `ErrorKind::WouldBlock` are supported, and are
+/// treated as having no data available.
+pub struct Uart<R: Read, W: Write> {
+    /// Source of received bytes.
+    pub rx: R,
+    /// Sink for transmitted bytes.
+    pub tx: W,
+    /// Distance between registers in bytes.
+    pub stride: u32,
+    /// Byte read from `rx`, but not yet read by the guest.
+    rx_pending: Option<u8>,
+    /// Storage for registers without special behavior.
+    regs: [u8; 8],
+}
+
+impl<R: Read, W: Write> Uart<R, W> {
+    /// Receive buffer register (read) and transmit holding register (write).
+    pub const REG_DATA: u32 = 0;
+    /// Interrupt identification register (read) and FIFO control register (write).
+    pub const REG_IIR: u32 = 2;
+    /// Line status register.
+    pub const REG_LSR: u32 = 5;
+
+    /// Line status bit indicating a received byte is available.
+    pub const LSR_DATA_READY: u8 = 0x01;
+    /// Line status bit indicating the transmit holding register is empty.
+    pub const LSR_THR_EMPTY: u8 = 0x20;
+    /// Line status bit indicating the transmitter is idle.
+    pub const LSR_TX_IDLE: u8 = 0x40;
+
+    /// Create a UART with the given register stride.
+    pub fn new(rx: R, tx: W, stride: u32) -> Self {
+        assert!(stride.is_power_of_two(), "stride must be a power of two");
+        Uart {
+            rx,
+            tx,
+            stride,
+            rx_pending: None,
+            regs: [0; 8],
+        }
+    }
+
+    /// Size of the device in the address space.
+    pub fn size(&self) -> u32 {
+        8 * self.stride
+    }
+
+    /// Try to make a received byte available, returning whether one is.
+    fn poll_rx(&mut self) -> bool {
+        if self.rx_pending.is_none() {
+            let mut buf = [0u8; 1];
+            self.rx_pending = loop {
+                match self.rx.read(&mut buf) {
+                    Ok(1) => break Some(buf[0]),
+                    Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+                    _ => break None,
+                }
+            };
+        }
+        self.rx_pending.is_some()
+    }
+
+    /// Read a register.
+    fn read_reg(&mut self, reg: u32) -> u8 {
+        match reg {
+            Self::REG_DATA => {
+                self.poll_rx();
+                self.rx_pending.take().unwrap_or(0)
+            }
+            Self::REG_IIR => 0x01,
+            Self::REG_LSR => {
+                let ready = if self.poll_rx() {
+                    Self::LSR_DATA_READY
+                } else {
+                    0
+                };
+                ready | Self::LSR_THR_EMPTY | Self::LSR_TX_IDLE
+            }
+            _ => self.regs[reg as usize],
+        }
+    }
+
+    /// Write a register. Returns `false` if transmission failed.
+    fn write_reg(&mut self, reg: u32, value: u8) -> bool {
+        match reg {
+            Self::REG_DATA => self.tx.write_all(&[value]).is_ok(),
+            Self::REG_IIR | Self::REG_LSR => true,
+            _ => {
+                self.regs[reg as usize] = value;
+                true
+            }
+        }
+    }
+}
+
+impl<R: Read, W: Write> Memory for Uart<R, W> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        let width = size_of::<T>() as u32;
+        if !addr.is_multiple_of(self.stride)
+            || addr >= self.size()
+            || width > self.stride.clamp(1, 4)
+        {
+            return false;
+        }
+        let reg = addr / self.stride;
+
+        // Registers are a single byte, and wider accesses use the low byte.
+        let mut buf = [0u8; 4];
+        match access {
+            MemoryAccess::Load(dest) => {
+                buf[0] = self.read_reg(reg);
+                Memory::access(&mut buf[..], 0, MemoryAccess::Load(dest))
+            }
+            MemoryAccess::Store(value) => {
+                Memory::access(&mut buf[..], 0, MemoryAccess::Store(value));
+                self.write_reg(reg, buf[0])
+            }
+            MemoryAccess::Exec(_) => false,
+        }
+    }
+}
@@ -1,14 +1,24 @@
-#![allow(clippy::cast_lossless, clippy::transmute_ptr_to_ref)]
+#![allow(clippy::cast_lossless)]
 
-//! A simple copy-free ELF parser.
+//! A simple ELF parser.
 //!
 //! This parser is limited, and parses only the specific kind of ELF files we expect to run.
 //!
-//! `Elf32::parse` can be used to parse a byte array into structs that reference the original data.
-//! Note that these structs also hold values in the original endianness.
+//! `Elf32::parse` can be used to parse a byte array into header structs, and slices that reference
+//! the original data. Note that the header structs hold values in the original endianness.
+//!
+//! The header structs are packed to match the file layout. Headers are always copied out of the
+//! data using unaligned reads, and fields must be read by value, because references to fields of
+//! a packed struct may be unaligned.
+//!
+//! When the file only needs to be loaded, `load_streaming` can instead copy segments from a reader
+//! directly into memory, without reading the whole file.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+use std::ptr;
 
-use std::mem::{size_of, transmute};
-use std::slice;
+use crate::cpu::{Memory, Perms};
 
 /// Expected ELF magic value.
 pub const ELF_IDENT_MAGIC: u32 = 0x7f45_4c46;
@@ -22,14 +32,36 @@ pub const ELF_IDENT_DATA_2LSB: u8 = 1;
 pub const ELF_IDENT_ABI_SYSV: u8 = 0;
 /// Executable type value.
 pub const ELF_TYPE_EXECUTABLE: u16 = 2;
+/// Shared object type value, also used for position-independent executables.
+pub const ELF_TYPE_DYNAMIC: u16 = 3;
 /// RISC-V machine type value.
 pub const ELF_MACHINE_RISCV: u16 = 243;
 /// Expected ELF version.
 pub const ELF_VERSION_CURRENT: u32 = 1;
 /// Program header bit indicating a loadable entry.
 pub const ELF_PROGRAM_TYPE_LOADABLE: u32 = 1;
+/// Program header flag indicating an executable segment.
+pub const ELF_PROGRAM_FLAG_EXEC: u32 = 1;
+/// Program header flag indicating a writable segment.
+pub const ELF_PROGRAM_FLAG_WRITE: u32 = 2;
+/// Program header flag indicating a readable segment.
+pub const ELF_PROGRAM_FLAG_READ: u32 = 4;
+/// Section header type indicating a symbol table.
+pub const ELF_SECTION_TYPE_SYMTAB: u32 = 2;
+/// Section header type indicating relocation entries with addends.
+pub const ELF_SECTION_TYPE_RELA: u32 = 4;
 /// Section header type indicating space with no data (bss).
 pub const ELF_SECTION_TYPE_NOBITS: u32 = 8;
+/// Section header type indicating a symbol table for dynamic linking.
+pub const ELF_SECTION_TYPE_DYNSYM: u32 = 11;
+/// Section header flag indicating the section occupies memory during execution.
+pub const ELF_SECTION_FLAG_ALLOC: u32 = 2;
+/// Symbol section index indicating an undefined symbol.
+pub const ELF_SYMBOL_INDEX_UNDEFINED: u16 = 0;
+/// Relocation type for a 32-bit absolute address of a symbol.
+pub const R_RISCV_32: u8 = 1;
+/// Relocation type for an address relative to the load bias.
+pub const R_RISCV_RELATIVE: u8 = 3;
 
 trait ElfFileAddressable {
     fn get_range(&self) -> (u32, u32);
@@ -37,7 +69,7 @@ trait ElfFileAddressable {
 
 /// ELF identity header.
 #[derive(Clone, Copy, Debug)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct ElfIdent {
     /// ELF magic value, matches `ELF_IDENT_MAGIC`.
     pub magic: u32,
@@ -57,7 +89,7 @@ pub struct ElfIdent {
 
 /// ELF 32-bit header.
 #[derive(Clone, Copy, Debug)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct ElfHeader32 {
     /// File type, one of `ELF_TYPE_*`.
     pub typ: u16,
@@ -89,7 +121,7 @@ pub struct ElfHeader32 {
 
 /// ELF 32-bit program header.
 #[derive(Clone, Copy, Debug)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct ElfProgramHeader32 {
     /// Type, a combination of `ELF_PROGRAM_TYPE_*`
     pub typ: u32,
@@ -103,11 +135,22 @@ pub struct ElfProgramHeader32 {
     pub filesz: u32,
     /// Size of the image in memory.
     pub memsz: u32,
-    /// Type-specific flags.
+    /// Flags, a combination of `ELF_PROGRAM_FLAG_*`.
     pub flags: u32,
     /// Memory alignment in bytes.
     pub align: u32,
 }
+impl ElfProgramHeader32 {
+    /// Memory permissions of the segment, from the `ELF_PROGRAM_FLAG_*` flags.
+    pub fn perms(&self) -> Perms {
+        Perms {
+            read: self.flags & ELF_PROGRAM_FLAG_READ != 0,
+            write: self.flags & ELF_PROGRAM_FLAG_WRITE != 0,
+            exec: self.flags & ELF_PROGRAM_FLAG_EXEC != 0,
+        }
+    }
+}
+
 impl ElfFileAddressable for ElfProgramHeader32 {
     fn get_range(&self) -> (u32, u32) {
         (self.offset, self.filesz)
@@ -116,7 +159,7 @@ impl ElfFileAddressable for ElfProgramHeader32 {
 
 /// ELF 32-bit section header.
 #[derive(Clone, Copy, Debug)]
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct ElfSectionHeader32 {
     /// Index in the string section containing the section name.
     pub name: u32,
@@ -152,17 +195,59 @@ impl ElfFileAddressable for ElfSectionHeader32 {
     }
 }
 
+/// ELF 32-bit relocation entry with addend.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct ElfRela32 {
+    /// Virtual address of the location to relocate.
+    pub offset: u32,
+    /// Symbol index in the upper 24 bits, and relocation type (`R_RISCV_*`) in the lower 8 bits.
+    pub info: u32,
+    /// Constant addend used to compute the value.
+    pub addend: i32,
+}
+
+impl ElfRela32 {
+    /// Symbol table index of the relocation.
+    pub fn sym(&self) -> u32 {
+        self.info >> 8
+    }
+
+    /// Relocation type, one of `R_RISCV_*`.
+    pub fn typ(&self) -> u8 {
+        self.info as u8
+    }
+}
+
+/// ELF 32-bit symbol table entry.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct ElfSym32 {
+    /// Index in the string section containing the symbol name.
+    pub name: u32,
+    /// Value of the symbol, typically an address.
+    pub value: u32,
+    /// Size of the object referenced by the symbol.
+    pub size: u32,
+    /// Symbol type and binding.
+    pub info: u8,
+    /// Symbol visibility.
+    pub other: u8,
+    /// Index of the section the symbol is defined in, or `ELF_SYMBOL_INDEX_UNDEFINED`.
+    pub shndx: u16,
+}
+
 /// ELF 32-bit file structure.
 #[derive(Debug)]
 pub struct Elf32<'a> {
     /// The identity header.
-    pub ident: &'a ElfIdent,
+    pub ident: ElfIdent,
     /// The main header.
-    pub header: &'a ElfHeader32,
+    pub header: ElfHeader32,
     /// Program headers.
-    pub ph: Vec<&'a ElfProgramHeader32>,
+    pub ph: Vec<ElfProgramHeader32>,
     /// Section headers.
-    pub sh: Vec<&'a ElfSectionHeader32>,
+    pub sh: Vec<ElfSectionHeader32>,
     /// Program data.
     pub p: Vec<&'a [u8]>,
     /// Section data.
@@ -170,34 +255,18 @@ pub struct Elf32<'a> {
 }
 
 impl<'a> Elf32<'a> {
-    /// Parse an ELF file, and return structs referencing the data.
+    /// Parse an ELF file, copying the headers, and return slices referencing the data.
     pub fn parse(data: &'a [u8]) -> Result<Elf32<'a>, String> {
         if data.len() < size_of::<ElfIdent>() + size_of::<ElfHeader32>() {
             return Err("file too short to contain headers".to_owned());
         }
 
-        let ident: &'a ElfIdent = unsafe { transmute(data.as_ptr()) };
-        if u32::from_be(ident.magic) != ELF_IDENT_MAGIC {
-            return Err("magic mismatch, likely not an ELF".to_owned());
-        }
-        if ident.version != ELF_IDENT_VERSION_CURRENT {
-            let ident_version = ident.version;
-            return Err(format!("unsupported version {}", ident_version));
-        }
-        if ident.class != ELF_IDENT_CLASS_32 {
-            return Err("only 32-bit class supported".to_owned());
-        }
+        let (ident_data, header_data) = data.split_at(size_of::<ElfIdent>());
+        let ident = read_entries::<ElfIdent>(ident_data).next().unwrap();
+        check_ident(&ident)?;
 
-        let header: &'a ElfHeader32 =
-            unsafe { transmute(data.as_ptr().add(size_of::<ElfIdent>())) };
-        if header.version != ELF_VERSION_CURRENT {
-            let header_version = header.version;
-            return Err(format!("unsupported version {}", header_version));
-        }
-        if header.typ != ELF_TYPE_EXECUTABLE {
-            let header_typ = header.typ;
-            return Err(format!("unsupported type {}", header_typ));
-        }
+        let header = read_entries::<ElfHeader32>(header_data).next().unwrap();
+        check_header(&header)?;
 
         let (ph, p) = resolve_parts::<ElfProgramHeader32>(
             data,
@@ -221,6 +290,316 @@ impl<'a> Elf32<'a> {
             s,
         })
     }
+
+    /// Find the value of a symbol by name, typically its address.
+    ///
+    /// Searches both the regular and dynamic symbol tables. Undefined symbols are ignored.
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.sh
+            .iter()
+            .zip(&self.s)
+            .filter(|(sh, _)| {
+                sh.typ == ELF_SECTION_TYPE_SYMTAB || sh.typ == ELF_SECTION_TYPE_DYNSYM
+            })
+            .find_map(|(sh, data)| {
+                let strtab = self.s.get(sh.link as usize).copied().unwrap_or(&[]);
+                read_entries::<ElfSym32>(data)
+                    .find(|sym| {
+                        sym.shndx != ELF_SYMBOL_INDEX_UNDEFINED
+                            && strtab
+                                .get(sym.name as usize..)
+                                .and_then(|s| s.split(|&c| c == 0).next())
+                                == Some(name.as_bytes())
+                    })
+                    .map(|sym| sym.value)
+            })
+    }
+
+    /// Find the symbol at or nearest before an address, returning its name and the offset of the
+    /// address from it.
+    ///
+    /// This is useful to describe code addresses as `symbol+offset`. Section and file symbols,
+    /// undefined symbols, and symbols without a name are ignored. Returns `None` if there is no
+    /// symbol at or before the address.
+    pub fn symbolize(&self, addr: u32) -> Option<(&'a str, u32)> {
+        const SYMBOL_TYPE_SECTION: u8 = 3;
+        const SYMBOL_TYPE_FILE: u8 = 4;
+        self.sh
+            .iter()
+            .zip(&self.s)
+            .filter(|(sh, _)| {
+                sh.typ == ELF_SECTION_TYPE_SYMTAB || sh.typ == ELF_SECTION_TYPE_DYNSYM
+            })
+            .flat_map(|(sh, &data)| {
+                let strtab = self.s.get(sh.link as usize).copied().unwrap_or(&[]);
+                read_entries::<ElfSym32>(data).filter_map(move |sym| {
+                    let typ = sym.info & 0xf;
+                    if sym.shndx == ELF_SYMBOL_INDEX_UNDEFINED
+                        || typ == SYMBOL_TYPE_SECTION
+                        || typ == SYMBOL_TYPE_FILE
+                        || sym.value > addr
+                    {
+                        return None;
+                    }
+                    let name = strtab.get(sym.name as usize..)?.split(|&c| c == 0).next()?;
+                    let name = std::str::from_utf8(name)
+                        .ok()
+                        .filter(|name| !name.is_empty())?;
+                    Some((name, addr - sym.value))
+                })
+            })
+            .min_by_key(|&(_, offset)| offset)
+    }
+
+    /// Copy loadable segments into memory, and return the entry point.
+    ///
+    /// Segments are placed at their virtual address plus `load_bias`, and the returned entry
+    /// point is adjusted likewise. A non-zero bias is only allowed for position-independent
+    /// executables (`ELF_TYPE_DYNAMIC`).
+    ///
+    /// When `zero_bss` is set, the part of each segment beyond its file data (typically bss) is
+    /// filled with zeroes. This can be skipped if memory is known to be zeroed already.
+    pub fn load_into<M: Memory>(
+        &self,
+        mem: &mut M,
+        load_bias: u32,
+        zero_bss: bool,
+    ) -> Result<u32, String> {
+        if load_bias != 0 && self.header.typ != ELF_TYPE_DYNAMIC {
+            return Err("load bias requires a position-independent executable".to_owned());
+        }
+
+        for (ph, data) in self.ph.iter().zip(&self.p) {
+            if ph.typ != ELF_PROGRAM_TYPE_LOADABLE {
+                continue;
+            }
+            let vaddr = ph.vaddr.wrapping_add(load_bias);
+            if !write_bytes(mem, vaddr, data)
+                || (zero_bss && !zero_bytes(mem, vaddr, data.len() as u32, ph.memsz))
+            {
+                return Err(format!("failed to load segment at {:#x}", vaddr));
+            }
+        }
+
+        Ok(self.header.entry.wrapping_add(load_bias))
+    }
+}
+
+/// Check the identity header for a supported file.
+fn check_ident(ident: &ElfIdent) -> Result<(), String> {
+    if u32::from_be(ident.magic) != ELF_IDENT_MAGIC {
+        return Err("magic mismatch, likely not an ELF".to_owned());
+    }
+    if ident.version != ELF_IDENT_VERSION_CURRENT {
+        let ident_version = ident.version;
+        return Err(format!("unsupported version {}", ident_version));
+    }
+    if ident.class != ELF_IDENT_CLASS_32 {
+        return Err("only 32-bit class supported".to_owned());
+    }
+    Ok(())
+}
+
+/// Check the main header for a supported file.
+fn check_header(header: &ElfHeader32) -> Result<(), String> {
+    if header.version != ELF_VERSION_CURRENT {
+        let header_version = header.version;
+        return Err(format!("unsupported version {}", header_version));
+    }
+    if header.typ != ELF_TYPE_EXECUTABLE && header.typ != ELF_TYPE_DYNAMIC {
+        let header_typ = header.typ;
+        return Err(format!("unsupported type {}", header_typ));
+    }
+    Ok(())
+}
+
+/// Load an ELF file from a reader, copying loadable segments into memory.
+///
+/// Unlike `Elf32::parse`, this never reads the entire file. Only the headers are read, after which
+/// each loadable segment is copied directly into memory. Returns the entry point.
+///
+/// The part of each segment beyond its file data (typically bss) is filled with zeroes.
+pub fn load_streaming<R: Read + Seek, M: Memory>(
+    mut reader: R,
+    mem: &mut M,
+) -> Result<u32, String> {
+    let mut buf = [0u8; size_of::<ElfIdent>() + size_of::<ElfHeader32>()];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|_| "file too short to contain headers".to_owned())?;
+    let (ident_buf, header_buf) = buf.split_at(size_of::<ElfIdent>());
+    let ident = read_entries::<ElfIdent>(ident_buf).next().unwrap();
+    check_ident(&ident)?;
+    let header = read_entries::<ElfHeader32>(header_buf).next().unwrap();
+    check_header(&header)?;
+
+    // Read all program headers before seeking to segment data.
+    let phentsize = header.phentsize as usize;
+    if header.phoff != 0 && phentsize < size_of::<ElfProgramHeader32>() {
+        return Err("headers smaller than defined in specification".to_owned());
+    }
+    let mut phs = Vec::new();
+    if header.phoff != 0 {
+        // Read entries one by one, so a bogus count cannot cause a huge allocation.
+        let mut buf = vec![0u8; phentsize];
+        reader
+            .seek(SeekFrom::Start(header.phoff as u64))
+            .map_err(|_| "reference to data beyond end of file".to_owned())?;
+        for _ in 0..header.phnum {
+            reader
+                .read_exact(&mut buf)
+                .map_err(|_| "reference to data beyond end of file".to_owned())?;
+            phs.extend(read_entries::<ElfProgramHeader32>(&buf).next());
+        }
+    }
+
+    let mut buf = [0u8; 4096];
+    for ph in phs {
+        if ph.typ != ELF_PROGRAM_TYPE_LOADABLE {
+            continue;
+        }
+        reader
+            .seek(SeekFrom::Start(ph.offset as u64))
+            .map_err(|e| format!("failed to read segment: {}", e))?;
+        let mut addr = ph.vaddr;
+        let mut remaining = ph.filesz as usize;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(4096)];
+            reader
+                .read_exact(chunk)
+                .map_err(|_| "reference to data beyond end of file".to_owned())?;
+            if !write_bytes(mem, addr, chunk) {
+                let vaddr = ph.vaddr;
+                return Err(format!("failed to load segment at {:#x}", vaddr));
+            }
+            addr = addr.wrapping_add(chunk.len() as u32);
+            remaining -= chunk.len();
+        }
+        if !zero_bytes(mem, ph.vaddr, ph.filesz, ph.memsz) {
+            let vaddr = ph.vaddr;
+            return Err(format!("failed to load segment at {:#x}", vaddr));
+        }
+    }
+
+    Ok(header.entry)
+}
+
+/// Apply dynamic relocations to loaded segments.
+///
+/// This processes all `ELF_SECTION_TYPE_RELA` sections that are allocated in memory, such as
+/// `.rela.dyn` and `.rela.plt`. Only `R_RISCV_RELATIVE` and `R_RISCV_32` are supported, which is
+/// sufficient for static position-independent executables. The `load_bias` must match the one
+/// used with `Elf32::load_into`.
+pub fn apply_relocations<M: Memory>(
+    elf: &Elf32,
+    mem: &mut M,
+    load_bias: u32,
+) -> Result<(), String> {
+    for (sh, data) in elf.sh.iter().zip(&elf.s) {
+        if sh.typ != ELF_SECTION_TYPE_RELA || sh.flags & ELF_SECTION_FLAG_ALLOC == 0 {
+            continue;
+        }
+        let symtab = elf.s.get(sh.link as usize).copied().unwrap_or(&[]);
+        for rela in read_entries::<ElfRela32>(data) {
+            let value = match rela.typ() {
+                R_RISCV_RELATIVE => load_bias.wrapping_add(rela.addend as u32),
+                R_RISCV_32 => {
+                    let sym = read_entries::<ElfSym32>(symtab)
+                        .nth(rela.sym() as usize)
+                        .ok_or_else(|| "relocation references invalid symbol".to_owned())?;
+                    if sym.shndx == ELF_SYMBOL_INDEX_UNDEFINED {
+                        return Err("relocation references undefined symbol".to_owned());
+                    }
+                    sym.value
+                        .wrapping_add(load_bias)
+                        .wrapping_add(rela.addend as u32)
+                }
+                typ => return Err(format!("unsupported relocation type {}", typ)),
+            };
+            let addr = rela.offset.wrapping_add(load_bias);
+            if !write_bytes(mem, addr, &value.to_le_bytes()) {
+                return Err(format!("failed to apply relocation at {:#x}", addr));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set up the initial stack of a program, with arguments and environment.
+///
+/// This follows the layout the Linux kernel uses at process entry, which C runtimes expect to find
+/// at `sp`: `argc`, then the `argv` pointers, then the `envp` pointers, each array terminated by a
+/// null pointer, followed by an empty auxiliary vector. The strings themselves are placed at the
+/// top of the stack, below `sp_top`.
+///
+/// Returns the new stack pointer, which is 16-byte aligned and should be placed in `x2`.
+pub fn setup_stack<M: Memory>(
+    mem: &mut M,
+    sp_top: u32,
+    args: &[&str],
+    env: &[&str],
+) -> Result<u32, String> {
+    // Copy strings to the top of the stack, collecting pointers.
+    let mut addr = sp_top;
+    let mut ptrs = Vec::with_capacity(args.len() + env.len());
+    for s in args.iter().chain(env) {
+        addr = addr.wrapping_sub(s.len() as u32 + 1);
+        let terminated = [s.as_bytes(), &[0]].concat();
+        if !write_bytes(mem, addr, &terminated) {
+            return Err(format!("failed to write string to stack at {:#x}", addr));
+        }
+        ptrs.push(addr);
+    }
+    let (argv, envp) = ptrs.split_at(args.len());
+
+    // Build the table: argc, argv, null, envp, null, and an `AT_NULL` auxiliary entry.
+    let mut table = Vec::with_capacity(ptrs.len() + 5);
+    table.push(args.len() as u32);
+    table.extend_from_slice(argv);
+    table.push(0);
+    table.extend_from_slice(envp);
+    table.push(0);
+    table.extend_from_slice(&[0, 0]);
+
+    let sp = addr.wrapping_sub(4 * table.len() as u32) & !15;
+    for (i, word) in table.iter().enumerate() {
+        let waddr = sp.wrapping_add(4 * i as u32);
+        if !write_bytes(mem, waddr, &word.to_le_bytes()) {
+            return Err(format!("failed to write to stack at {:#x}", waddr));
+        }
+    }
+    Ok(sp)
+}
+
+/// Read fixed-size entries from section data.
+fn read_entries<T: Copy>(data: &[u8]) -> impl Iterator<Item = T> + '_ {
+    data.chunks_exact(size_of::<T>())
+        .map(|chunk| unsafe { ptr::read_unaligned(chunk.as_ptr() as *const T) })
+}
+
+/// Fill memory in the range `[addr + start, addr + end)` with zeroes. Returns `false` if any store
+/// failed.
+fn zero_bytes<M: Memory>(mem: &mut M, addr: u32, start: u32, end: u32) -> bool {
+    let mut zeroes = [0u8; 4096];
+    let mut offset = start;
+    while offset < end {
+        let chunk = &mut zeroes[..(end - offset).min(4096) as usize];
+        if !mem.access_block(addr.wrapping_add(offset), chunk, true) {
+            return false;
+        }
+        offset += chunk.len() as u32;
+    }
+    true
+}
+
+/// Write bytes to memory at the given address. Returns `false` if any store failed.
+fn write_bytes<M: Memory>(mem: &mut M, addr: u32, data: &[u8]) -> bool {
+    let mut buf = [0u8; 4096];
+    data.chunks(4096).enumerate().all(|(i, data)| {
+        let chunk = &mut buf[..data.len()];
+        chunk.copy_from_slice(data);
+        mem.access_block(addr.wrapping_add(4096 * i as u32), chunk, true)
+    })
 }
 
 fn resolve_parts<'a, T>(
@@ -228,9 +607,9 @@ fn resolve_parts<'a, T>(
     offset: u32,
     entsize16: u16,
     num16: u16,
-) -> Result<(Vec<&'a T>, Vec<&'a [u8]>), String>
+) -> Result<(Vec<T>, Vec<&'a [u8]>), String>
 where
-    T: ElfFileAddressable,
+    T: ElfFileAddressable + Copy,
 {
     let entsize = entsize16 as u32;
     let num = num16 as u32;
@@ -241,12 +620,16 @@ where
         if (entsize as usize) < size_of::<T>() {
             return Err("headers smaller than defined in specification".to_owned());
         }
-        if data.len() < (offset + entsize * num) as usize {
-            return Err("reference to data beyond end of file".to_owned());
+        match entsize
+            .checked_mul(num)
+            .and_then(|size| size.checked_add(offset))
+        {
+            Some(end) if end as usize <= data.len() => {}
+            _ => return Err("reference to data beyond end of file".to_owned()),
         }
         (0..num)
-            .map(|i| unsafe { transmute(data.as_ptr().offset((offset + i * entsize) as isize)) })
-            .collect::<Vec<&'a T>>()
+            .flat_map(|i| read_entries::<T>(&data[(offset + i * entsize) as usize..]).next())
+            .collect::<Vec<T>>()
     };
 
     let blocks = headers
@@ -254,13 +637,11 @@ where
         .map(|h| -> Result<&'a [u8], String> {
             let (offset, size) = h.get_range();
             if size == 0 {
-                Ok(&[])
-            } else if data.len() < (offset + size) as usize {
-                Err("reference to data beyond end of file".to_owned())
-            } else {
-                Ok(unsafe {
-                    slice::from_raw_parts(data.as_ptr().offset(offset as isize), size as usize)
-                })
+                return Ok(&[]);
+            }
+            match offset.checked_add(size) {
+                Some(end) if end as usize <= data.len() => Ok(&data[offset as usize..end as usize]),
+                _ => Err("reference to data beyond end of file".to_owned()),
             }
         })
         .collect::<Result<Vec<_>, _>>()?;
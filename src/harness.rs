@@ -0,0 +1,71 @@
+//! Helpers for running the official RISC-V ISA tests (riscv-tests).
+//!
+//! Only available with the `test-helpers` feature.
+
+#![allow(clippy::new_without_default)]
+
+use crate::cpu::{Clock, CpuError, Interp, Memory, Op, RegFile};
+
+/// The outcome of a riscv-tests program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TestResult {
+    /// All tests passed.
+    Pass,
+    /// A test failed.
+    Fail {
+        /// Number of the failing test case, as assigned by the `TEST_CASE` macros.
+        test_num: u32,
+    },
+}
+
+impl TestResult {
+    /// Decode a result value, as placed in `gp` or stored to `tohost` by riscv-tests.
+    ///
+    /// Bit 0 is the done flag, and the remaining bits are the number of the failing test, or zero
+    /// if all tests passed. Returns `None` if the done flag is not set.
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            _ if value & 1 == 0 => None,
+            1 => Some(TestResult::Pass),
+            _ => Some(TestResult::Fail {
+                test_num: value >> 1,
+            }),
+        }
+    }
+}
+
+/// Runs riscv-tests programs, checking the pass/fail convention.
+///
+/// The test environment ends a program with `ecall`, with the result in a register, usually `gp`
+/// (`x3`), encoded as described at `TestResult::from_value`. Environments that instead store the
+/// result to `tohost` can be handled with `ToHost` from the `mem` module and
+/// `TestResult::from_value`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RiscvTestHarness {
+    /// The integer register holding the result when the program makes an environment call.
+    pub reg: usize,
+}
+
+impl RiscvTestHarness {
+    /// Create a harness reading the result from `gp`, as in the standard test environment.
+    pub fn new() -> Self {
+        RiscvTestHarness { reg: 3 }
+    }
+
+    /// Run the test program until it makes an environment call, and decode its result.
+    ///
+    /// Any stop reason other than `Ecall` is returned as the error. An `Ecall` without the done
+    /// flag set in the result register is returned as an error too, and execution can be resumed
+    /// by calling this method again.
+    pub fn run<M: Memory, C: Clock, R: RegFile>(
+        &self,
+        interp: &mut Interp<'_, '_, '_, M, C, R>,
+    ) -> Result<TestResult, (CpuError, Option<Op>)> {
+        match interp.run() {
+            (CpuError::Ecall, op) => {
+                TestResult::from_value(interp.state.x.read(self.reg)).ok_or((CpuError::Ecall, op))
+            }
+            err => Err(err),
+        }
+    }
+}
@@ -11,7 +11,12 @@
 //! the virtual machine.
 //!
 //! When using the feature `serde`, a `CpuState` can be serialized (and deserialized) in order to
-//! suspend a virtual machine to persistent storage.
+//! suspend a virtual machine to persistent storage. Decoded `Op`s can likewise be serialized, for
+//! example to record an instruction trace.
+//!
+//! A few simple memory-mapped devices are provided in the `dev` module, which can be placed in a
+//! memory map alongside DRAM. The `mem` module contains helpers for building `Memory`
+//! implementations.
 //!
 //! A very basic ELF parser is also provided in the `elf` module. Rvsim itself uses this parser to
 //! run the official RISC-V test suite.
@@ -47,7 +52,11 @@
 //! /// extended by adding more `else if` clauses, working through blocks of memory from highest
 //! /// base address to lowest.
 //! impl rvsim::Memory for SimpleMemory {
-//!     fn access<T: Copy>(&mut self, addr: u32, access: rvsim::MemoryAccess<T>) -> bool {
+//!     fn access<T: rvsim::MemoryValue>(
+//!         &mut self,
+//!         addr: u32,
+//!         access: rvsim::MemoryAccess<T>,
+//!     ) -> bool {
 //!         if addr >= Self::DRAM_BASE {
 //!             rvsim::Memory::access(&mut self.dram[..], addr - Self::DRAM_BASE, access)
 //!         } else {
@@ -95,7 +104,13 @@
 #[allow(unused_parens)]
 mod cpu;
 
+pub mod asm;
+pub mod dev;
 pub mod elf;
+#[cfg(feature = "test-helpers")]
+pub mod harness;
+pub mod mem;
+pub mod proxy;
 #[cfg(feature = "rv32fd")]
 pub mod softfloat;
 
@@ -0,0 +1,47 @@
+use std::mem::size_of;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue};
+
+/// A `Memory` implementation backed by a closure, for quick experiments.
+///
+/// The closure is called as `f(addr, bytes, write)` for every access, where `bytes` has the size
+/// of the access, or of the whole block for `access_block`. For a read, the closure should fill `bytes` with the contents of memory at
+/// `addr`. For a write, `bytes` holds the data to be written. Instruction fetches are reads.
+///
+/// The closure returns `false` to indicate the access failed.
+///
+/// Bytes are in little-endian order, matching a `[u8]` block of DRAM.
+pub struct ClosureMemory<F: FnMut(u32, &mut [u8], bool) -> bool> {
+    /// The closure handling accesses.
+    pub f: F,
+}
+
+impl<F: FnMut(u32, &mut [u8], bool) -> bool> ClosureMemory<F> {
+    /// Create an instance calling the given closure.
+    pub fn new(f: F) -> Self {
+        ClosureMemory { f }
+    }
+}
+
+impl<F: FnMut(u32, &mut [u8], bool) -> bool> Memory for ClosureMemory<F> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        // The interpreter accesses at most 8 bytes at a time.
+        let mut buf = [0u8; 8];
+        let bytes = match buf.get_mut(..size_of::<T>()) {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+        match access {
+            MemoryAccess::Load(_) | MemoryAccess::Exec(_) => {
+                (self.f)(addr, bytes, false) && Memory::access(bytes, 0, access)
+            }
+            MemoryAccess::Store(_) => {
+                Memory::access(&mut *bytes, 0, access) && (self.f)(addr, bytes, true)
+            }
+        }
+    }
+
+    fn access_block(&mut self, addr: u32, buf: &mut [u8], write: bool) -> bool {
+        (self.f)(addr, buf, write)
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue, Perms};
+
+/// A copy-on-write `Memory` wrapper, for quickly rolling back memory.
+///
+/// Stores are never passed to the inner memory. Instead, the page containing the store is first
+/// copied from the inner memory into an overlay, and the store modifies the copy. Loads and fetches
+/// are served from the overlay if the page was copied, and from the inner memory otherwise.
+///
+/// `rollback` discards the overlay, returning memory to its state at the last `checkpoint`, in
+/// time proportional to the number of modified pages. This pairs well with `Interp::snapshot`,
+/// for example to rerun a program with many different inputs while fuzzing.
+///
+/// The page size is configurable. Small pages copy less memory on the first store to a page,
+/// while large pages reduce overhead for programs that write large areas of memory. A page size
+/// of 1 tracks individual bytes.
+///
+/// Pages are copied using byte loads from the inner memory, so a store fails if any byte in its
+/// page cannot be loaded. This makes the wrapper suitable for blocks of DRAM, but not for devices.
+pub struct CowMemory<M: Memory> {
+    /// The wrapped memory, which holds the contents at the last checkpoint.
+    pub inner: M,
+    /// Log2 of the page size.
+    page_shift: u32,
+    /// Modified pages, by page number.
+    overlay: HashMap<u32, Box<[u8]>>,
+}
+
+impl<M: Memory> CowMemory<M> {
+    /// The default page size, in bytes.
+    pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+
+    /// Wrap memory, using the default page size.
+    pub fn new(inner: M) -> Self {
+        Self::with_page_size(inner, Self::DEFAULT_PAGE_SIZE)
+    }
+
+    /// Wrap memory, using the given page size in bytes.
+    ///
+    /// Panics if the page size is not a power of two.
+    pub fn with_page_size(inner: M, page_size: u32) -> Self {
+        assert!(
+            page_size.is_power_of_two(),
+            "page size must be a power of two"
+        );
+        CowMemory {
+            inner,
+            page_shift: page_size.trailing_zeros(),
+            overlay: HashMap::new(),
+        }
+    }
+
+    /// The page size, in bytes.
+    pub fn page_size(&self) -> u32 {
+        1 << self.page_shift
+    }
+
+    /// Number of pages modified since the last checkpoint or rollback.
+    pub fn dirty_pages(&self) -> usize {
+        self.overlay.len()
+    }
+
+    /// The contents of pages modified since the last checkpoint or rollback, as pairs of base
+    /// address and data, ordered by address.
+    ///
+    /// Together with the inner memory at the last checkpoint, this is the complete memory
+    /// contents, so persisting just these pages is a cheap snapshot. Restore it with `rollback`
+    /// followed by `load_pages`. CPU state, including the reservation slot and CSRs, is captured
+    /// separately with `Interp::snapshot`, and must be restored together with the pages.
+    pub fn dirty_page_data(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        let mut pages = self.overlay.keys().copied().collect::<Vec<_>>();
+        pages.sort_unstable();
+        pages
+            .into_iter()
+            .map(move |page| (page << self.page_shift, &self.overlay[&page][..]))
+    }
+
+    /// Load pages as returned by `dirty_page_data`, replacing the contents of those pages.
+    ///
+    /// The pages become modified pages, as if written by stores. Panics if a base address is not
+    /// aligned to the page size, or the data is not exactly one page.
+    pub fn load_pages<'p>(&mut self, pages: impl IntoIterator<Item = (u32, &'p [u8])>) {
+        let size = self.page_size();
+        for (base, data) in pages {
+            assert!(base % size == 0, "page base address must be aligned");
+            assert!(data.len() == size as usize, "page data must be one page");
+            self.overlay.insert(base >> self.page_shift, data.into());
+        }
+    }
+
+    /// Discard all stores since the last checkpoint or rollback.
+    pub fn rollback(&mut self) {
+        self.overlay.clear();
+    }
+
+    /// Write all stores since the last checkpoint or rollback to the inner memory, making the
+    /// current contents the state that `rollback` returns to.
+    ///
+    /// Returns `false` if the inner memory refused any of the stores. The overlay is cleared
+    /// regardless.
+    pub fn checkpoint(&mut self) -> bool {
+        let mut ok = true;
+        for (page, data) in self.overlay.drain() {
+            let base = page << self.page_shift;
+            for (i, &byte) in data.iter().enumerate() {
+                ok &= self
+                    .inner
+                    .access(base.wrapping_add(i as u32), MemoryAccess::Store(byte));
+            }
+        }
+        ok
+    }
+
+    /// Copy a page from the inner memory into the overlay, if not already present.
+    fn copy_page(&mut self, page: u32) -> bool {
+        if self.overlay.contains_key(&page) {
+            return true;
+        }
+        let base = page << self.page_shift;
+        let mut data = vec![0u8; 1 << self.page_shift].into_boxed_slice();
+        for (i, byte) in data.iter_mut().enumerate() {
+            if !self
+                .inner
+                .access(base.wrapping_add(i as u32), MemoryAccess::Load(byte))
+            {
+                return false;
+            }
+        }
+        self.overlay.insert(page, data);
+        true
+    }
+}
+
+impl<M: Memory> Memory for CowMemory<M> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        let shift = self.page_shift;
+        let mask = (1u32 << shift) - 1;
+        let addrs = (0..T::SIZE as u32).map(|i| addr.wrapping_add(i));
+
+        // Loads from unmodified pages go straight to the inner memory.
+        let exec = matches!(access, MemoryAccess::Exec(_));
+        if !matches!(access, MemoryAccess::Store(_))
+            && addrs
+                .clone()
+                .all(|a| !self.overlay.contains_key(&(a >> shift)))
+        {
+            return self.inner.access(addr, access);
+        }
+
+        // The interpreter accesses at most 8 bytes at a time.
+        let mut buf = [0u8; 8];
+        let bytes = &mut buf[..T::SIZE];
+        match access {
+            MemoryAccess::Load(dest) | MemoryAccess::Exec(dest) => {
+                for (byte, a) in bytes.iter_mut().zip(addrs) {
+                    let ok = match self.overlay.get(&(a >> shift)) {
+                        Some(data) => {
+                            *byte = data[(a & mask) as usize];
+                            true
+                        }
+                        None if exec => self.inner.access(a, MemoryAccess::Exec(byte)),
+                        None => self.inner.access(a, MemoryAccess::Load(byte)),
+                    };
+                    if !ok {
+                        return false;
+                    }
+                }
+                *dest = T::from_le_slice(bytes);
+                true
+            }
+            MemoryAccess::Store(value) => {
+                if !addrs.clone().all(|a| self.copy_page(a >> shift)) {
+                    return false;
+                }
+                value.to_le_slice(bytes);
+                for (&byte, a) in bytes.iter().zip(addrs) {
+                    let data = self.overlay.get_mut(&(a >> shift)).unwrap();
+                    data[(a & mask) as usize] = byte;
+                }
+                true
+            }
+        }
+    }
+
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        self.inner.permissions(addr)
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        self.inner.handle_fault(addr, is_write)
+    }
+}
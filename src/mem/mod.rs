@@ -0,0 +1,16 @@
+//! Helpers for building `Memory` implementations.
+//!
+//! These either adapt other types to the `Memory` trait, or wrap another `Memory` to add
+//! behavior, so they can be used with the `Interp`.
+
+mod closure;
+mod cow;
+mod no_execute;
+mod poison;
+mod signature;
+
+pub use self::closure::*;
+pub use self::cow::*;
+pub use self::no_execute::*;
+pub use self::poison::*;
+pub use self::signature::*;
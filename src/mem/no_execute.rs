@@ -0,0 +1,65 @@
+use std::ops::Range;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue, Perms};
+
+/// A `Memory` wrapper that refuses instruction fetches from a range of addresses.
+///
+/// This implements no-execute protection for data regions, such as the stack or heap. Loads and
+/// stores are passed through to the inner memory, but fetching an instruction from the range
+/// fails, which stops the interpreter with `CpuError::IllegalFetch`.
+///
+/// ```
+/// use rvsim::mem::NoExecute;
+/// use rvsim::{CpuError, CpuState, Interp, SimpleClock};
+///
+/// // Code in the first half of memory, data in the second half.
+/// let mut dram = vec![0u8; 0x2000];
+/// dram[..4].copy_from_slice(&0x0000_106fu32.to_le_bytes()); // j 0x1000
+/// let mut mem = NoExecute::new(&mut dram[..], 0x1000..0x2000);
+///
+/// let mut state = CpuState::new(0);
+/// let mut clock = SimpleClock::new();
+/// let (err, _) = Interp::new(&mut state, &mut mem, &mut clock).run();
+/// assert_eq!(err, CpuError::IllegalFetch { fell_through: false });
+/// assert_eq!(state.pc, 0x1000);
+/// ```
+pub struct NoExecute<M: Memory> {
+    /// The wrapped memory.
+    pub inner: M,
+    /// Addresses from which instructions may not be fetched.
+    pub range: Range<u32>,
+}
+
+impl<M: Memory> NoExecute<M> {
+    /// Wrap memory, refusing instruction fetches from the given range.
+    pub fn new(inner: M, range: Range<u32>) -> Self {
+        NoExecute { inner, range }
+    }
+}
+
+impl<M: Memory> Memory for NoExecute<M> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        if let MemoryAccess::Exec(_) = access {
+            if self.range.contains(&addr) {
+                return false;
+            }
+        }
+        self.inner.access(addr, access)
+    }
+
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        let perms = self.inner.permissions(addr)?;
+        Some(Perms {
+            exec: perms.exec && !self.range.contains(&addr),
+            ..perms
+        })
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        self.inner.handle_fault(addr, is_write)
+    }
+
+    fn access_block(&mut self, addr: u32, buf: &mut [u8], write: bool) -> bool {
+        self.inner.access_block(addr, buf, write)
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue, Perms};
+
+/// A load that read memory never written since reset, recorded by `PoisonMemory`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PoisonedLoad {
+    /// Address of the load.
+    pub addr: u32,
+    /// Size of the load, in bytes.
+    pub size: u32,
+    /// Bit `i` is set if byte `addr + i` was never written.
+    pub uninit: u32,
+}
+
+/// A `Memory` wrapper that flags loads from uninitialized memory.
+///
+/// This is a debugging aid similar to MemorySanitizer. Every byte successfully stored through the
+/// wrapper is marked as initialized, and a load that reads any byte not marked is recorded in
+/// `poisoned`. A load that spans both initialized and uninitialized bytes is recorded once, with a
+/// mask of the uninitialized bytes.
+///
+/// By default, poisoned loads are only recorded, and the load proceeds. When `trap` is set, the
+/// load also fails, which stops the interpreter with `CpuError::IllegalAccess`.
+///
+/// Instruction fetches are not checked. Memory initialized outside the wrapper, such as a program
+/// loaded directly into the inner memory, must be marked with `mark_initialized`.
+pub struct PoisonMemory<M: Memory> {
+    /// The wrapped memory.
+    pub inner: M,
+    /// Whether a poisoned load fails, instead of only being recorded.
+    pub trap: bool,
+    /// Poisoned loads, in the order they happened.
+    pub poisoned: Vec<PoisonedLoad>,
+    /// Addresses of initialized bytes.
+    initialized: HashSet<u32>,
+}
+
+impl<M: Memory> PoisonMemory<M> {
+    /// Wrap memory, with all bytes uninitialized, recording poisoned loads without trapping.
+    pub fn new(inner: M) -> Self {
+        PoisonMemory {
+            inner,
+            trap: false,
+            poisoned: Vec::new(),
+            initialized: HashSet::new(),
+        }
+    }
+
+    /// Mark a range of addresses as initialized.
+    pub fn mark_initialized(&mut self, range: Range<u32>) {
+        self.initialized.extend(range);
+    }
+
+    /// Whether the byte at the given address was initialized.
+    pub fn is_initialized(&self, addr: u32) -> bool {
+        self.initialized.contains(&addr)
+    }
+
+    /// Mark all memory as uninitialized again, and clear recorded loads.
+    pub fn reset(&mut self) {
+        self.initialized.clear();
+        self.poisoned.clear();
+    }
+}
+
+impl<M: Memory> Memory for PoisonMemory<M> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        match access {
+            MemoryAccess::Load(dest) => {
+                let uninit = (0..T::SIZE as u32)
+                    .filter(|&i| !self.is_initialized(addr.wrapping_add(i)))
+                    .fold(0, |mask, i| mask | 1 << i);
+                if uninit != 0 {
+                    self.poisoned.push(PoisonedLoad {
+                        addr,
+                        size: T::SIZE as u32,
+                        uninit,
+                    });
+                    if self.trap {
+                        return false;
+                    }
+                }
+                self.inner.access(addr, MemoryAccess::Load(dest))
+            }
+            MemoryAccess::Store(value) => {
+                let ok = self.inner.access(addr, MemoryAccess::Store(value));
+                if ok {
+                    self.initialized
+                        .extend((0..T::SIZE as u32).map(|i| addr.wrapping_add(i)));
+                }
+                ok
+            }
+            MemoryAccess::Exec(dest) => self.inner.access(addr, MemoryAccess::Exec(dest)),
+        }
+    }
+
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        self.inner.permissions(addr)
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        self.inner.handle_fault(addr, is_write)
+    }
+}
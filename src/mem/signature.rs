@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::cpu::{Memory, MemoryAccess, MemoryValue, Perms};
+
+/// A `Memory` wrapper that watches for the guest writing to a `tohost` location.
+///
+/// Test suites such as riscv-arch-test signal completion by storing a nonzero value to the
+/// `tohost` symbol, after which they typically loop forever. The store is passed through to the
+/// inner memory, and the value is recorded, so the host can check `value` between steps.
+pub struct ToHost<M: Memory> {
+    /// The wrapped memory.
+    pub inner: M,
+    /// Address of the `tohost` location.
+    pub addr: u32,
+    /// The last nonzero value written to `tohost`, if any.
+    pub value: Option<u32>,
+}
+
+impl<M: Memory> ToHost<M> {
+    /// Wrap memory, watching for stores to the given address.
+    pub fn new(inner: M, addr: u32) -> Self {
+        ToHost {
+            inner,
+            addr,
+            value: None,
+        }
+    }
+}
+
+impl<M: Memory> Memory for ToHost<M> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        if let MemoryAccess::Store(value) = access {
+            if addr == self.addr {
+                // Decode the value by storing it to a temporary buffer.
+                let mut buf = [0u8; 8];
+                if Memory::access(&mut buf[..], 0, MemoryAccess::Store(value)) {
+                    let value = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                    if value != 0 {
+                        self.value = Some(value);
+                    }
+                }
+            }
+        }
+        self.inner.access(addr, access)
+    }
+
+    fn permissions(&self, addr: u32) -> Option<Perms> {
+        self.inner.permissions(addr)
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        self.inner.handle_fault(addr, is_write)
+    }
+}
+
+/// Write a memory range in the signature format used by riscv-arch-test (RISCOF).
+///
+/// The signature consists of one 32-bit word per line, in lowercase hexadecimal with 8 digits.
+/// The range is typically given by the `begin_signature` and `end_signature` symbols of the test.
+pub fn dump_signature<M: Memory, W: Write>(
+    mem: &mut M,
+    range: Range<u32>,
+    mut out: W,
+) -> io::Result<()> {
+    for addr in range.step_by(4) {
+        let mut word: u32 = 0;
+        if !mem.access(addr, MemoryAccess::Load(&mut word)) {
+            return Err(io::Error::other(format!(
+                "failed to read signature at {:#x}",
+                addr
+            )));
+        }
+        writeln!(out, "{:08x}", word)?;
+    }
+    Ok(())
+}
@@ -6,6 +6,7 @@
 // This file is modified from bindings generated by `bindgen` based on
 // `softfloat.h`. It contains only the parts we use.
 
+use std::cmp::Ordering;
 use std::mem::transmute;
 
 #[cfg(feature = "serde")]
@@ -35,9 +36,12 @@ pub const FLAG_INVALID: u8 = 16;
 
 /// A single-precision soft-float. Internally represented as `u32`.
 ///
+/// Equality and hashing compare the bit pattern, not the floating-point value. Thus, a NaN is
+/// equal to itself, but `0.0` and `-0.0` are different.
+///
 /// With the `serde` feature, this structure is serializable using Serde.
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Sf32(pub u32);
 
@@ -49,6 +53,15 @@ impl Sf32 {
     pub fn negate(self) -> Sf32 {
         Sf32(self.0 ^ 0x8000_0000)
     }
+
+    /// Compare using the IEEE 754 `totalOrder` predicate, like `f32::total_cmp`.
+    ///
+    /// Orders negative NaNs, -∞, negative numbers, -0, +0, positive numbers, +∞ and positive NaNs,
+    /// in that order, so this is useful for deterministic sorting.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        let key = |x: u32| (x ^ ((((x as i32) >> 31) as u32) >> 1)) as i32;
+        key(self.0).cmp(&key(other.0))
+    }
 }
 
 impl From<f32> for Sf32 {
@@ -67,9 +80,12 @@ impl From<Sf32> for f32 {
 
 /// A double-precision soft-float. Internally represented as `u64`.
 ///
+/// Equality and hashing compare the bit pattern, not the floating-point value. Thus, a NaN is
+/// equal to itself, but `0.0` and `-0.0` are different.
+///
 /// With the `serde` feature, this structure is serializable using Serde.
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Sf64(pub u64);
 
@@ -81,6 +97,14 @@ impl Sf64 {
     pub fn negate(self) -> Sf64 {
         Sf64(self.0 ^ 0x8000_0000_0000_0000)
     }
+
+    /// Compare using the IEEE 754 `totalOrder` predicate, like `f64::total_cmp`.
+    ///
+    /// See `Sf32::total_cmp`.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        let key = |x: u64| (x ^ ((((x as i64) >> 63) as u64) >> 1)) as i64;
+        key(self.0).cmp(&key(other.0))
+    }
 }
 
 impl From<f64> for Sf64 {
@@ -0,0 +1,165 @@
+use rvsim::asm::{AsmBuilder, AsmError};
+use rvsim::*;
+
+fn words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn encodings() {
+    // Expected values assembled with llvm-mc.
+    let mut asm = AsmBuilder::new();
+    asm.label("start")
+        .lui(10, 0x12345)
+        .auipc(1, 0xfffff)
+        .jal(1, "end")
+        .jalr(5, 11, -4)
+        .beq(10, 11, "start")
+        .bne(8, 9, "end")
+        .blt(5, 6, "start")
+        .bge(5, 6, "end")
+        .bltu(12, 13, "start")
+        .bgeu(12, 13, "end")
+        .lb(10, 2, -1)
+        .lh(10, 2, 2)
+        .lw(10, 2, 2047)
+        .lbu(10, 2, -2048)
+        .lhu(10, 2, 6)
+        .sb(10, 2, -1)
+        .sh(11, 2, 2)
+        .sw(31, 27, -2048)
+        .addi(10, 11, -7)
+        .slti(10, 11, 3)
+        .sltiu(10, 11, 3)
+        .xori(10, 11, -1)
+        .ori(10, 11, 0x7f)
+        .andi(10, 11, 0xff)
+        .slli(10, 11, 31)
+        .srli(10, 11, 1)
+        .srai(10, 11, 7)
+        .add(10, 11, 12)
+        .sub(10, 11, 12)
+        .sll(10, 11, 12)
+        .slt(10, 11, 12)
+        .sltu(10, 11, 12)
+        .xor(10, 11, 12)
+        .srl(10, 11, 12)
+        .sra(10, 11, 12)
+        .or(10, 11, 12)
+        .and(10, 11, 12)
+        .ecall()
+        .ebreak()
+        .label("end");
+    assert_eq!(
+        words(&asm.build().unwrap()),
+        [
+            0x1234_5537, // lui a0, 0x12345
+            0xffff_f097, // auipc ra, 0xfffff
+            0x0940_00ef, // jal ra, end
+            0xffc5_82e7, // jalr t0, -4(a1)
+            0xfeb5_08e3, // beq a0, a1, start
+            0x0894_1463, // bne s0, s1, end
+            0xfe62_c4e3, // blt t0, t1, start
+            0x0862_d063, // bge t0, t1, end
+            0xfed6_60e3, // bltu a2, a3, start
+            0x06d6_7c63, // bgeu a2, a3, end
+            0xfff1_0503, // lb a0, -1(sp)
+            0x0021_1503, // lh a0, 2(sp)
+            0x7ff1_2503, // lw a0, 2047(sp)
+            0x8001_4503, // lbu a0, -2048(sp)
+            0x0061_5503, // lhu a0, 6(sp)
+            0xfea1_0fa3, // sb a0, -1(sp)
+            0x00b1_1123, // sh a1, 2(sp)
+            0x81fd_a023, // sw t6, -2048(s11)
+            0xff95_8513, // addi a0, a1, -7
+            0x0035_a513, // slti a0, a1, 3
+            0x0035_b513, // sltiu a0, a1, 3
+            0xfff5_c513, // xori a0, a1, -1
+            0x07f5_e513, // ori a0, a1, 127
+            0x0ff5_f513, // andi a0, a1, 255
+            0x01f5_9513, // slli a0, a1, 31
+            0x0015_d513, // srli a0, a1, 1
+            0x4075_d513, // srai a0, a1, 7
+            0x00c5_8533, // add a0, a1, a2
+            0x40c5_8533, // sub a0, a1, a2
+            0x00c5_9533, // sll a0, a1, a2
+            0x00c5_a533, // slt a0, a1, a2
+            0x00c5_b533, // sltu a0, a1, a2
+            0x00c5_c533, // xor a0, a1, a2
+            0x00c5_d533, // srl a0, a1, a2
+            0x40c5_d533, // sra a0, a1, a2
+            0x00c5_e533, // or a0, a1, a2
+            0x00c5_f533, // and a0, a1, a2
+            0x0000_0073, // ecall
+            0x0010_0073, // ebreak
+        ]
+    );
+}
+
+#[test]
+fn label_errors() {
+    let mut asm = AsmBuilder::new();
+    asm.j("missing");
+    assert_eq!(
+        asm.build(),
+        Err(AsmError::UndefinedLabel("missing".to_owned()))
+    );
+
+    let mut asm = AsmBuilder::new();
+    asm.label("twice").nop().label("twice");
+    assert_eq!(
+        asm.build(),
+        Err(AsmError::DuplicateLabel("twice".to_owned()))
+    );
+
+    // Branches reach 4 KiB in either direction.
+    for (nops, result) in [
+        (1022, Ok(())),
+        (
+            1023,
+            Err(AsmError::OutOfRange {
+                label: "far".to_owned(),
+                offset: 4096,
+            }),
+        ),
+    ] {
+        let mut asm = AsmBuilder::new();
+        asm.beq(0, 0, "far");
+        for _ in 0..nops {
+            asm.nop();
+        }
+        asm.label("far");
+        assert_eq!(asm.build().map(|_| ()), result);
+    }
+}
+
+#[test]
+fn run_program() {
+    let mut asm = AsmBuilder::new();
+    asm.li(10, 0x1234_5fff) // a0 = 0x12345fff
+        .li(11, -3) // a1 = -3
+        .li(12, 0) // a2 = 0
+        .jal(1, "sum")
+        .ebreak()
+        // Adds a1 to a2 until a1 reaches zero, then returns.
+        .label("sum")
+        .beq(11, 0, "done")
+        .add(12, 12, 11)
+        .addi(11, 11, 1)
+        .j("sum")
+        .label("done")
+        .jalr(0, 1, 0);
+    let mut dram = asm.build().unwrap();
+    let mut mem = &mut dram[..];
+
+    let mut state = CpuState::new(0);
+    let mut clock = SimpleClock::new();
+    let (err, _) = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(err, CpuError::Ebreak);
+    assert_eq!(state.x[10], 0x1234_5fff);
+    assert_eq!(state.x[11], 0);
+    assert_eq!(state.x[12], -6i32 as u32);
+}
@@ -0,0 +1,170 @@
+// Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use rvsim::*;
+
+pub const DRAM_BASE: u32 = 0x1000_0000;
+pub const DRAM_SIZE: usize = 0x10_0000;
+pub const DEVICE_BASE: u32 = 0x0200_0000;
+
+/// A memory map with DRAM at `DRAM_BASE`, and a single device at `DEVICE_BASE`.
+pub struct TestMemory<D = NoDevice> {
+    pub dram: Vec<u8>,
+    pub dev: D,
+}
+
+/// Placeholder for a memory map without a device.
+pub struct NoDevice;
+
+impl Memory for NoDevice {
+    fn access<T: MemoryValue>(&mut self, _addr: u32, _access: MemoryAccess<T>) -> bool {
+        false
+    }
+}
+
+impl TestMemory {
+    /// Create a memory map with the given code at the start of DRAM.
+    pub fn new(code: &[u32]) -> Self {
+        Self::with_device(code, NoDevice)
+    }
+}
+
+impl<D: Memory> TestMemory<D> {
+    /// Create a memory map with the given code at the start of DRAM, and a device.
+    pub fn with_device(code: &[u32], dev: D) -> Self {
+        let mut mem = Self {
+            dram: vec![0; DRAM_SIZE],
+            dev,
+        };
+        for (i, word) in code.iter().enumerate() {
+            mem.write(DRAM_BASE + 4 * i as u32, &word.to_le_bytes());
+        }
+        mem
+    }
+
+    /// Write raw bytes to DRAM at the given address.
+    pub fn write(&mut self, addr: u32, data: &[u8]) {
+        let offset = (addr - DRAM_BASE) as usize;
+        self.dram[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+impl<D: Memory> Memory for TestMemory<D> {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        if addr >= DRAM_BASE {
+            Memory::access(&mut self.dram[..], addr - DRAM_BASE, access)
+        } else if addr >= DEVICE_BASE {
+            self.dev.access(addr - DEVICE_BASE, access)
+        } else {
+            false
+        }
+    }
+}
+
+/// Run until the virtual CPU stops, using a `SimpleClock`.
+pub fn run<D: Memory>(state: &mut CpuState, mem: &mut TestMemory<D>) -> (CpuError, Option<Op>) {
+    let mut clock = SimpleClock::new();
+    Interp::new(state, mem, &mut clock).run()
+}
+
+/// Section to add to an ELF file built with `build_elf_with_sections`.
+pub struct TestSection<'a> {
+    pub typ: u32,
+    pub flags: u32,
+    pub link: u32,
+    pub data: &'a [u8],
+}
+
+/// Build a minimal little-endian RISC-V ELF file, with a single loadable segment.
+///
+/// The segment holds `data` at `vaddr`, and is `memsz` bytes in memory.
+pub fn build_elf(typ: u16, entry: u32, vaddr: u32, data: &[u8], memsz: u32) -> Vec<u8> {
+    build_elf_with_sections(typ, entry, vaddr, data, memsz, &[])
+}
+
+/// Like `build_elf`, but also adds sections after the null section at index 0.
+pub fn build_elf_with_sections(
+    typ: u16,
+    entry: u32,
+    vaddr: u32,
+    data: &[u8],
+    memsz: u32,
+    sections: &[TestSection],
+) -> Vec<u8> {
+    const EHSIZE: u32 = 52;
+    const PHENTSIZE: u32 = 32;
+    const SHENTSIZE: u32 = 40;
+
+    fn push_words(out: &mut Vec<u8>, words: &[u32]) {
+        for word in words {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    // Lay out segment data, then section data, then the section header table.
+    let segment_offset = EHSIZE + PHENTSIZE;
+    let mut section_offsets = Vec::new();
+    let mut offset = segment_offset + data.len() as u32;
+    for section in sections {
+        section_offsets.push(offset);
+        offset += section.data.len() as u32;
+    }
+    let (shoff, shnum) = if sections.is_empty() {
+        (0, 0)
+    } else {
+        (offset, sections.len() as u16 + 1)
+    };
+
+    let mut out = Vec::new();
+    // Identity header.
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    // Main header.
+    out.extend_from_slice(&typ.to_le_bytes());
+    out.extend_from_slice(&elf::ELF_MACHINE_RISCV.to_le_bytes());
+    push_words(&mut out, &[1, entry, EHSIZE, shoff, 0]);
+    for half in &[
+        EHSIZE as u16,
+        PHENTSIZE as u16,
+        1,
+        SHENTSIZE as u16,
+        shnum,
+        0,
+    ] {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+    // Program header.
+    let filesz = data.len() as u32;
+    push_words(
+        &mut out,
+        &[1, segment_offset, vaddr, vaddr, filesz, memsz, 0b101, 4],
+    );
+    // Segment and section data.
+    out.extend_from_slice(data);
+    for section in sections {
+        out.extend_from_slice(section.data);
+    }
+    // Section headers.
+    if !sections.is_empty() {
+        push_words(&mut out, &[0; 10]);
+    }
+    for (section, offset) in sections.iter().zip(section_offsets) {
+        let size = section.data.len() as u32;
+        push_words(
+            &mut out,
+            &[
+                0,
+                section.typ,
+                section.flags,
+                0,
+                offset,
+                size,
+                section.link,
+                0,
+                4,
+                0,
+            ],
+        );
+    }
+    out
+}
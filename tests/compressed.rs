@@ -0,0 +1,451 @@
+#![cfg(feature = "rv32c")]
+
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::*;
+
+const C_EBREAK: u16 = 0x9002;
+
+/// Create memory holding compressed code at the start of DRAM.
+fn compressed_memory(code: &[u16]) -> TestMemory {
+    let mut mem = TestMemory::new(&[]);
+    for (i, half) in code.iter().enumerate() {
+        mem.write(DRAM_BASE + 2 * i as u32, &half.to_le_bytes());
+    }
+    mem
+}
+
+#[test]
+fn reserved_rd_x0() {
+    let cases = [
+        0x4002, // c.lwsp x0, 0(sp)
+        0x8002, // c.jr x0
+    ];
+    for &instr in &cases {
+        assert_eq!(Op::parse_c(instr), None, "{:#06x}", instr);
+
+        let mut mem = compressed_memory(&[instr, C_EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        assert_eq!(
+            run(&mut state, &mut mem),
+            (CpuError::IllegalInstruction, None)
+        );
+        assert_eq!(state.pc, DRAM_BASE);
+    }
+
+    // The same encodings with other registers are valid.
+    assert_eq!(
+        Op::parse_c(0x4512), // c.lwsp a0, 4(sp)
+        Some(Op::Lw {
+            rd: 10,
+            rs1: 2,
+            i_imm: 4
+        })
+    );
+    assert_eq!(
+        Op::parse_c(0x8082), // c.jr ra
+        Some(Op::Jalr {
+            rd: 0,
+            rs1: 1,
+            i_imm: 0
+        })
+    );
+}
+
+#[test]
+fn hints_rd_x0() {
+    let code = [
+        0x4005, // c.li x0, 1
+        0x6005, // c.lui x0, 1
+        0x802a, // c.mv x0, a0
+        0x902a, // c.add x0, a0
+        0x0005, // c.addi x0, 1
+        0x0006, // c.slli x0, 1
+        C_EBREAK,
+    ];
+    let mut mem = compressed_memory(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = 5;
+    let expect = state.x;
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+    assert_eq!(state.x, expect);
+    assert_eq!(state.pc, DRAM_BASE + 14);
+}
+
+#[test]
+fn addi16sp_requires_sp() {
+    // With `rd = x2`, this encoding is `c.addi16sp`.
+    assert_eq!(
+        Op::parse_c(0x6141), // c.addi16sp sp, 16
+        Some(Op::Addi {
+            rd: 2,
+            rs1: 2,
+            i_imm: 16
+        })
+    );
+    // With any other `rd`, it is `c.lui`, and does not touch `sp`.
+    assert_eq!(
+        Op::parse_c(0x6185), // c.lui gp, 1
+        Some(Op::Lui {
+            rd: 3,
+            u_imm: 0x1000
+        })
+    );
+}
+
+#[test]
+fn reserved_zero_immediate() {
+    let cases = [
+        0x6501, // c.lui a0, 0
+        0x6001, // c.lui x0, 0
+        0x6101, // c.addi16sp sp, 0
+        0x1502, // c.slli a0, 32
+        0x9001, // c.srli s0, 32
+        0x9401, // c.srai s0, 32
+    ];
+    for &instr in &cases {
+        assert_eq!(Op::parse_c(instr), None, "{:#06x}", instr);
+
+        let mut mem = compressed_memory(&[instr, C_EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        assert_eq!(
+            run(&mut state, &mut mem),
+            (CpuError::IllegalInstruction, None)
+        );
+    }
+}
+
+#[test]
+fn hints_zero_immediate() {
+    let code = [
+        0x0001, // c.nop
+        0x0501, // c.addi a0, 0
+        0x0502, // c.slli a0, 0
+        0x8001, // c.srli s0, 0
+        0x8401, // c.srai s0, 0
+        C_EBREAK,
+    ];
+    let mut mem = compressed_memory(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[8] = 0x8000_0001;
+    state.x[10] = 0x8000_0001;
+    let expect = state.x;
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+    assert_eq!(state.x, expect);
+    assert_eq!(state.pc, DRAM_BASE + 12);
+
+    // Nonzero immediates are still valid.
+    assert_eq!(
+        Op::parse_c(0x6505), // c.lui a0, 1
+        Some(Op::Lui {
+            rd: 10,
+            u_imm: 0x1000
+        })
+    );
+    assert_eq!(
+        Op::parse_c(0x057e), // c.slli a0, 31
+        Some(Op::Slli {
+            rd: 10,
+            rs1: 10,
+            shamt: 31
+        })
+    );
+}
+
+#[test]
+fn jump_link_register() {
+    const TARGET: u32 = DRAM_BASE + 0x100;
+
+    // Columns: instruction, expected pc, expected register written.
+    let cases = [
+        (0xa801, DRAM_BASE + 16, None),                 // c.j 16
+        (0x8282, TARGET, None),                         // c.jr t0
+        (0x9282, TARGET, Some((1, DRAM_BASE + 2))),     // c.jalr t0
+        (0x9082, TARGET + 4, Some((1, DRAM_BASE + 2))), // c.jalr ra
+    ];
+    for &(instr, pc, link) in &cases {
+        let mut mem = compressed_memory(&[instr]);
+        let mut state = CpuState::new(DRAM_BASE);
+        for (i, x) in state.x.iter_mut().enumerate().skip(1) {
+            *x = 0x1111 * i as u32;
+        }
+        state.x[1] = TARGET + 4;
+        state.x[5] = TARGET;
+        let mut expect = state.clone();
+        expect.pc = pc;
+        if let Some((reg, value)) = link {
+            expect.x[reg] = value;
+        }
+
+        let mut clock = SimpleClock::new();
+        Interp::new(&mut state, &mut mem, &mut clock)
+            .step()
+            .unwrap();
+        assert_eq!(state, expect, "instr {:#06x}", instr);
+    }
+}
+
+#[test]
+fn compressed_ebreak() {
+    assert_eq!(Op::parse_c(C_EBREAK), Some(Op::Ebreak));
+
+    let mut mem = compressed_memory(&[
+        C_EBREAK, // c.ebreak
+        0x4515,   // c.li a0, 5
+        C_EBREAK, // c.ebreak
+    ]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // The PC advances past the 2-byte breakpoint, so resuming continues after it.
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE, C_EBREAK as u32)));
+    assert_eq!(interp.state.pc, DRAM_BASE + 2);
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.state.x[10], 5);
+    assert_eq!(interp.state.pc, DRAM_BASE + 6);
+}
+
+#[test]
+fn truncated_fetch() {
+    // A 32-bit instruction in the last two bytes of DRAM.
+    let pc = DRAM_BASE + DRAM_SIZE as u32 - 2;
+    let mut mem = TestMemory::new(&[]);
+    mem.write(pc, &0x0513u16.to_le_bytes()); // Low half of `addi a0, x0, 0`
+    let mut state = CpuState::new(pc);
+    let expect = state.clone();
+
+    let err = run(&mut state, &mut mem).0;
+    assert_eq!(err, CpuError::TruncatedFetch { pc, addr: pc + 2 });
+    assert_eq!(
+        err.to_string(),
+        "truncated fetch at 0x100ffffe, failed at 0x10100000"
+    );
+    assert_eq!(state, expect);
+
+    // A compressed instruction in the same place fetches fine.
+    mem.write(pc, &C_EBREAK.to_le_bytes());
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+}
+
+#[test]
+fn decode_length() {
+    assert_eq!(instr_len(0x0505), 2);
+    assert_eq!(instr_len(0x0015_0513), 4);
+
+    // Both forms of `addi a0, a0, 1` parse to the same `Op`, but differ in length.
+    let op = Op::Addi {
+        rd: 10,
+        rs1: 10,
+        i_imm: 1,
+    };
+    assert_eq!(Op::decode(0x0505), Some((op, 2))); // c.addi a0, 1
+    assert_eq!(Op::decode(0x0015_0513), Some((op, 4))); // addi a0, a0, 1
+    assert_eq!(Op::decode(0x0000), None);
+}
+
+#[test]
+fn halfword_aligned_fetch() {
+    // 32-bit instructions at addresses that are only 2-byte aligned, split into halves.
+    let mut mem = compressed_memory(&[
+        0x0001, // c.nop
+        0x5537, 0x1234, // lui a0, 0x12345
+        0x0513, 0x6785, // addi a0, a0, 0x678
+        C_EBREAK,
+    ]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    interp.step().unwrap();
+    assert_eq!(interp.state.pc, DRAM_BASE + 2);
+    assert_eq!(
+        interp.step(),
+        Ok(Op::Lui {
+            rd: 10,
+            u_imm: 0x1234_5000
+        })
+    );
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE + 2, 0x1234_5537)));
+    assert_eq!(interp.state.pc, DRAM_BASE + 6);
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.state.x[10], 0x1234_5678);
+    assert_eq!(interp.state.pc, DRAM_BASE + 12);
+}
+
+#[test]
+fn trap_illegal_compressed() {
+    const HANDLER: u32 = DRAM_BASE + 0x100;
+    let mut mem = compressed_memory(&[
+        0x0000, // illegal compressed instruction
+        0x2573, 0x7c00, // csrr a0, 0x7c0
+        0x4515, // c.li a0, 5
+        C_EBREAK,
+    ]);
+    // Skips the instruction by advancing `mepc` by its size, and counts traps in `a1`.
+    let handler: &[u32] = &[
+        0x3410_2373, // csrr t1, mepc
+        0x3430_22f3, // csrr t0, mtval
+        0x0032_f293, // andi t0, t0, 3
+        0x0030_0393, // li t2, 3
+        0x0023_0313, // addi t1, t1, 2
+        0x0072_9463, // bne t0, t2, 8
+        0x0023_0313, // addi t1, t1, 2
+        0x3413_1073, // csrw mepc, t1
+        0x0015_8593, // addi a1, a1, 1
+        0x3020_0073, // mret
+    ];
+    for (i, word) in handler.iter().enumerate() {
+        mem.write(HANDLER + 4 * i as u32, &word.to_le_bytes());
+    }
+    let mut state = CpuState::new(DRAM_BASE);
+    state.machine.mtvec = HANDLER;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.trap_illegal = true;
+
+    // The first step traps, with `mepc` at the instruction, and continues in the handler.
+    interp.step().unwrap();
+    let m = &interp.state.machine;
+    assert_eq!(m.mcause, MachineCsrs::MCAUSE_ILLEGAL_INSTRUCTION);
+    assert_eq!(m.mepc, DRAM_BASE);
+    assert_eq!(m.mtval, 0);
+    assert_eq!(interp.state.pc, HANDLER + 4);
+
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    let m = &interp.state.machine;
+    // The handler advanced `mepc` past the 4-byte instruction at `DRAM_BASE + 2`.
+    assert_eq!(m.mepc, DRAM_BASE + 6);
+    assert_eq!(m.mtval, 0x7c00_2573);
+    assert_eq!(interp.state.x[11], 2);
+    assert_eq!(interp.state.x[10], 5);
+    assert_eq!(interp.state.pc, DRAM_BASE + 10);
+}
+
+/// Check that compressed instructions parse to the same `Op` as their base expansion.
+fn check_expansions(cases: &[(u16, u32)]) {
+    for &(compressed, base) in cases {
+        let op = Op::parse(base);
+        assert!(op.is_some(), "base {:#010x}", base);
+        assert_eq!(
+            Op::parse_c(compressed),
+            op,
+            "compressed {:#06x}",
+            compressed
+        );
+    }
+}
+
+#[test]
+fn base_expansion() {
+    // Columns: compressed instruction, base expansion. Immediates cover the ends of their range.
+    check_expansions(&[
+        (0x0040, 0x0041_0413), // c.addi4spn s0, sp, 4
+        (0x1ffc, 0x3fc1_0793), // c.addi4spn a5, sp, 1020
+        (0x4188, 0x0005_a503), // c.lw a0, 0(a1)
+        (0x5fe4, 0x07c7_a483), // c.lw s1, 124(a5)
+        (0xc048, 0x00a4_2223), // c.sw a0, 4(s0)
+        (0xdf7c, 0x06f7_2e23), // c.sw a5, 124(a4)
+        (0x0001, 0x0000_0013), // c.nop
+        (0x0505, 0x0015_0513), // c.addi a0, 1
+        (0x1f81, 0xfe0f_8f93), // c.addi t6, -32
+        (0x017d, 0x01f1_0113), // c.addi sp, 31
+        (0x2ffd, 0x7fe0_00ef), // c.jal 2046
+        (0x3001, 0x801f_f0ef), // c.jal -2048
+        (0x4501, 0x0000_0513), // c.li a0, 0
+        (0x5d81, 0xfe00_0d93), // c.li s11, -32
+        (0x40fd, 0x01f0_0093), // c.li ra, 31
+        (0x617d, 0x1f01_0113), // c.addi16sp sp, 496
+        (0x7101, 0xe001_0113), // c.addi16sp sp, -512
+        (0x6505, 0x0000_1537), // c.lui a0, 1
+        (0x6ffd, 0x0001_ffb7), // c.lui t6, 31
+        (0x7781, 0xfffe_07b7), // c.lui a5, 1048544
+        (0x71fd, 0xffff_f1b7), // c.lui gp, 1048575
+        (0x8005, 0x0014_5413), // c.srli s0, 1
+        (0x83fd, 0x01f7_d793), // c.srli a5, 31
+        (0x8505, 0x4015_5513), // c.srai a0, 1
+        (0x84fd, 0x41f4_d493), // c.srai s1, 31
+        (0x8901, 0x0005_7513), // c.andi a0, 0
+        (0x9b81, 0xfe07_f793), // c.andi a5, -32
+        (0x887d, 0x01f4_7413), // c.andi s0, 31
+        (0x8c1d, 0x40f4_0433), // c.sub s0, a5
+        (0x8d2d, 0x00b5_4533), // c.xor a0, a1
+        (0x8fc5, 0x0097_e7b3), // c.or a5, s1
+        (0x8ce9, 0x00a4_f4b3), // c.and s1, a0
+        (0xaffd, 0x7fe0_006f), // c.j 2046
+        (0xb001, 0x801f_f06f), // c.j -2048
+        (0xcd7d, 0x0e05_0f63), // c.beqz a0, 254
+        (0xd001, 0xf004_00e3), // c.beqz s0, -256
+        (0xe389, 0x0007_9163), // c.bnez a5, 2
+        (0xfcfd, 0xfe04_9fe3), // c.bnez s1, -2
+        (0x0506, 0x0015_1513), // c.slli a0, 1
+        (0x0ffe, 0x01ff_9f93), // c.slli t6, 31
+        (0x4082, 0x0001_2083), // c.lwsp ra, 0(sp)
+        (0x5ffe, 0x0fc1_2f83), // c.lwsp t6, 252(sp)
+        (0x8082, 0x0000_8067), // c.jr ra
+        (0x8f82, 0x000f_8067), // c.jr t6
+        (0x852e, 0x00b0_0533), // c.mv a0, a1
+        (0x8f86, 0x0010_0fb3), // c.mv t6, ra
+        (0x9002, 0x0010_0073), // c.ebreak
+        (0x9502, 0x0005_00e7), // c.jalr a0
+        (0x9082, 0x0000_80e7), // c.jalr ra
+        (0x952e, 0x00b5_0533), // c.add a0, a1
+        (0x9f8a, 0x002f_8fb3), // c.add t6, sp
+        (0xc006, 0x0011_2023), // c.swsp ra, 0(sp)
+        (0xdffe, 0x0ff1_2e23), // c.swsp t6, 252(sp)
+    ]);
+
+    // Scaled immediates expand to byte offsets.
+    assert_eq!(
+        Op::parse_c(0x5ffe), // c.lwsp t6, 252(sp)
+        Some(Op::Lw {
+            rd: 31,
+            rs1: 2,
+            i_imm: 252
+        })
+    );
+    assert_eq!(
+        Op::parse_c(0x7101), // c.addi16sp sp, -512
+        Some(Op::Addi {
+            rd: 2,
+            rs1: 2,
+            i_imm: -512
+        })
+    );
+    assert_eq!(
+        Op::parse_c(0x1ffc), // c.addi4spn a5, sp, 1020
+        Some(Op::Addi {
+            rd: 15,
+            rs1: 2,
+            i_imm: 1020
+        })
+    );
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn base_expansion_float() {
+    // Columns: compressed instruction, base expansion.
+    check_expansions(&[
+        (0x2100, 0x0005_3407), // c.fld fs0, 0(a0)
+        (0x3cfc, 0x0f84_b787), // c.fld fa5, 248(s1)
+        (0x61c8, 0x0045_a507), // c.flw fa0, 4(a1)
+        (0x7fe4, 0x07c7_a487), // c.flw fs1, 124(a5)
+        (0xa500, 0x0085_3427), // c.fsd fs0, 8(a0)
+        (0xbcfc, 0x0ef4_bc27), // c.fsd fa5, 248(s1)
+        (0xe008, 0x00a4_2027), // c.fsw fa0, 0(s0)
+        (0xffe4, 0x0697_ae27), // c.fsw fs1, 124(a5)
+        (0x2002, 0x0001_3007), // c.fldsp ft0, 0(sp)
+        (0x3ffe, 0x1f81_3f87), // c.fldsp ft11, 504(sp)
+        (0x6512, 0x0041_2507), // c.flwsp fa0, 4(sp)
+        (0x7dfe, 0x0fc1_2d87), // c.flwsp fs11, 252(sp)
+        (0xa002, 0x0001_3027), // c.fsdsp ft0, 0(sp)
+        (0xbffe, 0x1ff1_3c27), // c.fsdsp ft11, 504(sp)
+        (0xe22a, 0x00a1_2227), // c.fswsp fa0, 4(sp)
+        (0xffee, 0x0fb1_2e27), // c.fswsp fs11, 252(sp)
+    ]);
+}
@@ -5,7 +5,7 @@ use rayon::prelude::*;
 use rvsim::*;
 use std::env;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::PathBuf;
 use std::process::{exit, Command};
 
@@ -27,7 +27,7 @@ impl TestMemory {
 }
 
 impl Memory for TestMemory {
-    fn access<T: Copy>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
         if addr >= Self::DRAM_BASE {
             Memory::access(&mut self.dram[..], addr - Self::DRAM_BASE, access)
         } else {
@@ -121,17 +121,13 @@ fn run_riscv_test(filename: &str) -> Result<(), String> {
         return Err("Unsupported executable format".to_string());
     }
 
+    // Memory starts out zeroed, so there is no need to clear bss.
     let mut mem = TestMemory::new();
-    for (i, ph) in elf.ph.iter().enumerate() {
-        if ph.typ == elf::ELF_PROGRAM_TYPE_LOADABLE {
-            let offset = (ph.vaddr - TestMemory::DRAM_BASE) as usize;
-            let mut dest = &mut mem.dram[offset..];
-            dest.write_all(elf.p[i])
-                .map_err(|e| format!("Failed to load executable image: {}", e))?;
-        }
-    }
+    let entry = elf
+        .load_into(&mut mem, 0, false)
+        .map_err(|e| format!("Failed to load executable image: {}", e))?;
 
-    let mut state = CpuState::new(elf.header.entry);
+    let mut state = CpuState::new(entry);
     let mut clock = SimpleClock::new();
     match Interp::new(&mut state, &mut mem, &mut clock).run() {
         (CpuError::Ecall, _) => {
@@ -0,0 +1,193 @@
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::dev::*;
+use rvsim::*;
+
+const ENTROPY_PROGRAM: &[u32] = &[
+    0x0200_02b7, // lui t0, 0x2000
+    0x0002_a303, // lw t1, 0(t0)
+    0x0002_a383, // lw t2, 0(t0)
+    0x0150_1473, // csrrw s0, seed, zero
+    0x0010_0073, // ebreak
+];
+
+fn run_entropy_program(seed: u64) -> CpuState {
+    let mut mem = TestMemory::with_device(
+        ENTROPY_PROGRAM,
+        EntropyDevice::new(SeededEntropy::new(seed)),
+    );
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.entropy = Some(Box::new(SeededEntropy::new(seed)));
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    state
+}
+
+#[test]
+fn entropy_device_replays_with_fixed_seed() {
+    let a = run_entropy_program(42);
+    let b = run_entropy_program(42);
+    assert_eq!(a.x, b.x);
+    assert_ne!(a.x[6], a.x[7]);
+
+    let c = run_entropy_program(43);
+    assert_ne!(a.x[6], c.x[6]);
+}
+
+#[test]
+fn entropy_device_ignores_writes_and_refuses_exec() {
+    let mut dev = EntropyDevice::new(SeededEntropy::new(1));
+    assert!(dev.access(0, MemoryAccess::Store(0xdead_beef_u32)));
+
+    let mut value: u32 = 0;
+    assert!(!dev.access(0, MemoryAccess::Exec(&mut value)));
+    assert!(!dev.access(2, MemoryAccess::Load(&mut value)));
+
+    let mut expect = SeededEntropy::new(1);
+    assert!(dev.access(0, MemoryAccess::Load(&mut value)));
+    assert_eq!(value, expect.next_u32());
+}
+
+#[test]
+fn seed_csr() {
+    let state = run_entropy_program(7);
+    assert_eq!(state.x[8] >> 30, 0b10);
+    assert_eq!(state.x[8] & 0x3fff_0000, 0);
+
+    let mut mem = TestMemory::new(ENTROPY_PROGRAM);
+    let mut state = CpuState::new(DRAM_BASE + 12);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run().0, CpuError::IllegalInstruction);
+}
+
+#[test]
+fn seed_csr_read_side_effects() {
+    // Reading `seed` consumes entropy, so only instructions that read the CSR may do so.
+    let program = &[
+        0x0150_9073, // csrrw zero, seed, ra
+        0x0150_5073, // csrrwi zero, seed, 0
+        0x0150_20f3, // csrrs ra, seed, zero
+        0x0150_30f3, // csrrc ra, seed, zero
+        0x0150_60f3, // csrrsi ra, seed, 0
+        0x0010_0073, // ebreak
+    ];
+    let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+    let counter = reads.clone();
+    let mut mem = TestMemory::new(program);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.entropy = Some(Box::new(move || {
+        counter.set(counter.get() + 1);
+        0
+    }));
+    for expected in [0, 0, 1, 2, 3] {
+        assert!(interp.step().is_ok());
+        assert_eq!(reads.get(), expected);
+    }
+    assert_eq!(interp.step(), Err((CpuError::Ebreak, Some(Op::Ebreak))));
+}
+
+#[test]
+fn uart_hello() {
+    let program = &[
+        0x0200_02b7, // lui t0, 0x2000
+        0x0480_0313, // li t1, 'H'
+        0x0062_a023, // sw t1, 0(t0)
+        0x0690_0313, // li t1, 'i'
+        0x0062_a023, // sw t1, 0(t0)
+        0x0142_a383, // 1: lw t2, 20(t0)
+        0x0013_f393, // andi t2, t2, 1
+        0xfe03_8ce3, // beqz t2, 1b
+        0x0002_a503, // lw a0, 0(t0)
+        0x0142_a583, // lw a1, 20(t0)
+        0x0010_0073, // ebreak
+    ];
+    let uart = Uart::new(&b"x"[..], Vec::new(), 4);
+    let mut mem = TestMemory::with_device(program, uart);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+
+    assert_eq!(mem.dev.tx, b"Hi");
+    assert_eq!(state.x[10], b'x' as u32);
+    assert_eq!(state.x[11], 0x60);
+}
+
+#[test]
+fn uart_register_stride() {
+    let mut uart = Uart::new(&b"ab"[..], Vec::new(), 1);
+    assert_eq!(uart.size(), 8);
+
+    let mut value: u8 = 0;
+    assert!(uart.access(5, MemoryAccess::Load(&mut value)));
+    assert_eq!(value, 0x61);
+    assert!(uart.access(0, MemoryAccess::Load(&mut value)));
+    assert_eq!(value, b'a');
+
+    // Scratch register holds its value.
+    assert!(uart.access(7, MemoryAccess::Store(0x5a_u8)));
+    assert!(uart.access(7, MemoryAccess::Load(&mut value)));
+    assert_eq!(value, 0x5a);
+
+    // Wider than the stride, or beyond the last register.
+    let mut word: u32 = 0;
+    assert!(!uart.access(0, MemoryAccess::Load(&mut word)));
+    assert!(!uart.access(8, MemoryAccess::Load(&mut value)));
+}
+
+#[test]
+fn clint_timer() {
+    let program = &[
+        0x0200_42b7, // lui t0, 0x2004
+        0x00a0_0313, // li t1, 10
+        0x0062_a023, // sw t1, 0(t0)
+        0x0002_a223, // sw zero, 4(t0)
+        0x0200_c2b7, // lui t0, 0x200c
+        0xff82_a503, // lw a0, -8(t0)
+        0xffc2_a583, // lw a1, -4(t0)
+        0x0010_0073, // ebreak
+    ];
+    let mut mem = TestMemory::with_device(program, Clint::new());
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock { instret: 5 };
+    mem.dev.sync(&clock);
+    assert_eq!(mem.dev.pending(), 0);
+
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    assert_eq!((state.x[10], state.x[11]), (5, 0));
+    assert_eq!(mem.dev.mtimecmp, 10);
+    assert!(!mem.dev.timer_pending());
+
+    mem.dev.sync(&clock);
+    assert_eq!(mem.dev.mtime(), 13);
+    assert_eq!(mem.dev.pending(), MachineCsrs::MIP_MTIP);
+}
+
+#[test]
+fn clint_software_interrupt_and_mtime_write() {
+    let mut clint = Clint::new();
+    assert!(clint.access(Clint::MSIP, MemoryAccess::Store(1_u32)));
+    assert_eq!(clint.pending(), MachineCsrs::MIP_MSIP);
+    assert!(clint.access(Clint::MSIP, MemoryAccess::Store(0_u32)));
+    assert_eq!(clint.pending(), 0);
+
+    // Writing `mtime` offsets it from the clock.
+    let mut clock = SimpleClock { instret: 100 };
+    clint.sync(&clock);
+    assert!(clint.access(Clint::MTIME, MemoryAccess::Store(1000_u64)));
+    clock.instret += 50;
+    clint.sync(&clock);
+    let mut mtime: u64 = 0;
+    assert!(clint.access(Clint::MTIME, MemoryAccess::Load(&mut mtime)));
+    assert_eq!(mtime, 1050);
+
+    assert!(!clint.access(0x8000, MemoryAccess::Load(&mut mtime)));
+}
@@ -0,0 +1,454 @@
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::*;
+
+const CODE: &[u32] = &[
+    0x0010_0073, // ebreak
+];
+
+fn code_bytes() -> Vec<u8> {
+    CODE.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+#[test]
+fn load_executable() {
+    let data = build_elf(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &code_bytes(),
+        4,
+    );
+    let elf = elf::Elf32::parse(&data).unwrap();
+    let mut mem = TestMemory::new(&[]);
+    assert_eq!(elf.load_into(&mut mem, 0, true), Ok(DRAM_BASE));
+    assert_eq!(mem.dram[..4], code_bytes()[..]);
+
+    // Executables cannot be relocated.
+    assert!(elf.load_into(&mut mem, 0x1000, true).is_err());
+}
+
+#[test]
+fn parse_unaligned() {
+    // Headers are read with unaligned loads, so the file may start at any offset.
+    let data = build_elf(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &code_bytes(),
+        4,
+    );
+    let mut buf = vec![0u8; data.len() + 1];
+    buf[1..].copy_from_slice(&data);
+    let elf = elf::Elf32::parse(&buf[1..]).unwrap();
+    let entry = elf.header.entry;
+    assert_eq!(entry, DRAM_BASE);
+    assert_eq!(elf.ph.len(), 1);
+    let vaddr = elf.ph[0].vaddr;
+    assert_eq!(vaddr, DRAM_BASE);
+    assert_eq!(
+        elf.ph[0].perms(),
+        Perms {
+            read: true,
+            write: false,
+            exec: true,
+        }
+    );
+    assert_eq!(elf.p[0], &code_bytes()[..]);
+}
+
+#[test]
+fn load_position_independent() {
+    let data = build_elf(elf::ELF_TYPE_DYNAMIC, 0, 0, &code_bytes(), 4);
+    let elf = elf::Elf32::parse(&data).unwrap();
+    let mut mem = TestMemory::new(&[]);
+    let entry = elf.load_into(&mut mem, DRAM_BASE + 0x1000, true).unwrap();
+    assert_eq!(entry, DRAM_BASE + 0x1000);
+    assert_eq!(mem.dram[0x1000..0x1004], code_bytes()[..]);
+
+    let mut state = CpuState::new(entry);
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+}
+
+#[test]
+fn load_outside_memory() {
+    let data = build_elf(elf::ELF_TYPE_DYNAMIC, 0, 0, &code_bytes(), 4);
+    let elf = elf::Elf32::parse(&data).unwrap();
+    let mut mem = TestMemory::new(&[]);
+    assert_eq!(
+        elf.load_into(&mut mem, 0x1000, true),
+        Err("failed to load segment at 0x1000".to_owned())
+    );
+}
+
+/// Encode a relocation entry.
+fn rela(offset: u32, sym: u32, typ: u8, addend: i32) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&(sym << 8 | typ as u32).to_le_bytes());
+    out.extend_from_slice(&addend.to_le_bytes());
+    out
+}
+
+/// Encode a symbol table entry.
+fn sym(name: u32, value: u32, shndx: u16) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&name.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+    out.extend_from_slice(&[0; 6]);
+    out.extend_from_slice(&shndx.to_le_bytes());
+    out
+}
+
+fn build_pie(relas: &[u8], symbols: &[u8]) -> Vec<u8> {
+    build_elf_with_sections(
+        elf::ELF_TYPE_DYNAMIC,
+        0,
+        0,
+        &code_bytes(),
+        0x200,
+        &[
+            // Index 1: `.dynsym`
+            TestSection {
+                typ: 11,
+                flags: elf::ELF_SECTION_FLAG_ALLOC,
+                link: 0,
+                data: symbols,
+            },
+            // Index 2: `.rela.dyn`
+            TestSection {
+                typ: elf::ELF_SECTION_TYPE_RELA,
+                flags: elf::ELF_SECTION_FLAG_ALLOC,
+                link: 1,
+                data: relas,
+            },
+        ],
+    )
+}
+
+#[test]
+fn apply_relocations() {
+    let bias = DRAM_BASE + 0x1000;
+    let relas = [
+        rela(0x100, 0, elf::R_RISCV_RELATIVE, 0x40),
+        rela(0x104, 1, elf::R_RISCV_32, 4),
+    ]
+    .concat();
+    let symbols = [sym(0, 0, 0), sym(0, 0x10, 1)].concat();
+    let data = build_pie(&relas, &symbols);
+    let elf = elf::Elf32::parse(&data).unwrap();
+
+    let mut mem = TestMemory::new(&[]);
+    elf.load_into(&mut mem, bias, true).unwrap();
+    elf::apply_relocations(&elf, &mut mem, bias).unwrap();
+    assert_eq!(mem.dram[0x1100..0x1104], (bias + 0x40).to_le_bytes());
+    assert_eq!(mem.dram[0x1104..0x1108], (bias + 0x14).to_le_bytes());
+}
+
+#[test]
+fn apply_relocations_errors() {
+    let cases = [
+        (rela(0x100, 0, 2, 0), "unsupported relocation type 2"),
+        (
+            rela(0x100, 0, elf::R_RISCV_32, 0),
+            "relocation references undefined symbol",
+        ),
+        (
+            rela(0x100, 5, elf::R_RISCV_32, 0),
+            "relocation references invalid symbol",
+        ),
+        (
+            rela(0x10_0000, 0, elf::R_RISCV_RELATIVE, 0),
+            "failed to apply relocation at 0x10101000",
+        ),
+    ];
+    for (relas, err) in &cases {
+        let data = build_pie(relas, &sym(0, 0, 0));
+        let elf = elf::Elf32::parse(&data).unwrap();
+        let mut mem = TestMemory::new(&[]);
+        assert_eq!(
+            elf::apply_relocations(&elf, &mut mem, DRAM_BASE + 0x1000),
+            Err(err.to_string())
+        );
+    }
+}
+
+#[test]
+fn load_streaming() {
+    use std::io::Cursor;
+
+    // A segment larger than the internal buffer, with a bss tail.
+    let data = (0..10_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let file = build_elf(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &data,
+        12_000,
+    );
+
+    let mut expect = TestMemory::new(&[]);
+    let entry = elf::Elf32::parse(&file)
+        .unwrap()
+        .load_into(&mut expect, 0, true)
+        .unwrap();
+
+    let mut mem = TestMemory::new(&[]);
+    assert_eq!(elf::load_streaming(Cursor::new(&file), &mut mem), Ok(entry));
+    assert!(mem.dram == expect.dram);
+
+    // Truncated files are detected.
+    let mut mem = TestMemory::new(&[]);
+    assert_eq!(
+        elf::load_streaming(Cursor::new(&file[..5000]), &mut mem),
+        Err("reference to data beyond end of file".to_owned())
+    );
+    assert_eq!(
+        elf::load_streaming(Cursor::new(&file[..20]), &mut mem),
+        Err("file too short to contain headers".to_owned())
+    );
+}
+
+#[test]
+fn load_zeroes_bss() {
+    use std::io::Cursor;
+
+    let file = build_elf(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &code_bytes(),
+        0x100,
+    );
+    let elf = elf::Elf32::parse(&file).unwrap();
+    let garbage_memory = || {
+        let mut mem = TestMemory::new(&[]);
+        mem.dram.iter_mut().for_each(|byte| *byte = 0xaa);
+        mem
+    };
+
+    let mut mem = garbage_memory();
+    elf.load_into(&mut mem, 0, true).unwrap();
+    assert_eq!(mem.dram[..4], code_bytes()[..]);
+    assert!(mem.dram[4..0x100].iter().all(|&byte| byte == 0));
+    assert_eq!(mem.dram[0x100], 0xaa);
+
+    // Zeroing can be skipped when memory is known to be zeroed.
+    let mut mem = garbage_memory();
+    elf.load_into(&mut mem, 0, false).unwrap();
+    assert!(mem.dram[4..0x100].iter().all(|&byte| byte == 0xaa));
+
+    // The streaming loader always zeroes.
+    let mut mem = garbage_memory();
+    elf::load_streaming(Cursor::new(&file), &mut mem).unwrap();
+    assert!(mem.dram[4..0x100].iter().all(|&byte| byte == 0));
+    assert_eq!(mem.dram[0x100], 0xaa);
+}
+
+/// Parse and load the given file, returning whether loading succeeded. Must never panic.
+fn try_load(file: &[u8]) -> bool {
+    use std::io::Cursor;
+
+    let mut mem = TestMemory::new(&[]);
+    let streamed = elf::load_streaming(Cursor::new(file), &mut mem).is_ok();
+    let parsed = match elf::Elf32::parse(file) {
+        Ok(elf) => {
+            let mut mem = TestMemory::new(&[]);
+            let _ = elf.load_into(&mut mem, 0, true);
+            let _ = elf::apply_relocations(&elf, &mut mem, 0);
+            true
+        }
+        Err(_) => false,
+    };
+    streamed || parsed
+}
+
+// A deterministic regression check. The `elf` fuzz target in `fuzz/` explores further.
+#[test]
+fn malformed_input_does_not_panic() {
+    use rvsim::dev::{EntropySource, SeededEntropy};
+
+    let mut rng = SeededEntropy::new(0x5eed);
+    let valid = build_elf(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &code_bytes(),
+        4,
+    );
+    assert!(try_load(&valid));
+
+    for _ in 0..5000 {
+        // Randomly corrupt a valid file, mostly in the headers.
+        let mut file = valid.clone();
+        for _ in 0..1 + rng.next_u32() % 8 {
+            let i = rng.next_u32() as usize % file.len();
+            file[i] = rng.next_u32() as u8;
+        }
+        file.truncate(rng.next_u32() as usize % (file.len() + 16));
+        try_load(&file);
+
+        // Completely random bytes, with a valid identity header half of the time.
+        let mut file = (0..rng.next_u32() % 256)
+            .map(|_| rng.next_u32() as u8)
+            .collect::<Vec<_>>();
+        if rng.next_u32() & 1 == 0 && file.len() >= 6 {
+            file[..6].copy_from_slice(&valid[..6]);
+        }
+        try_load(&file);
+    }
+
+    // Header fields that overflow address calculations.
+    let mut file = valid.clone();
+    file[28..32].copy_from_slice(&u32::MAX.to_le_bytes()); // phoff
+    assert!(!try_load(&file));
+    let mut file = valid.clone();
+    file[52 + 4..52 + 8].copy_from_slice(&u32::MAX.to_le_bytes()); // segment offset
+    assert!(!try_load(&file));
+    let mut file = valid;
+    file[44..46].copy_from_slice(&u16::MAX.to_le_bytes()); // phnum
+    assert!(!try_load(&file));
+}
+
+/// Build an executable with a counting loop, and symbols `loop` and `done`.
+fn count_loop_elf() -> Vec<u8> {
+    const COUNT_LOOP: &[u32] = &[
+        0x00a0_0513, // li a0, 10
+        0xfff5_0513, // loop: addi a0, a0, -1
+        0x0015_8593, // addi a1, a1, 1
+        0xfe05_1ce3, // bnez a0, loop
+        0x0010_0073, // done: ebreak
+    ];
+    let code = COUNT_LOOP
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    let strtab = b"\0loop\0done\0missing\0";
+    let symtab = [
+        sym(0, 0, 0),
+        sym(1, DRAM_BASE + 4, 1),
+        sym(6, DRAM_BASE + 16, 1),
+        sym(11, DRAM_BASE, 0),
+    ]
+    .concat();
+    build_elf_with_sections(
+        elf::ELF_TYPE_EXECUTABLE,
+        DRAM_BASE,
+        DRAM_BASE,
+        &code,
+        code.len() as u32,
+        &[
+            // Index 1: `.symtab`
+            TestSection {
+                typ: elf::ELF_SECTION_TYPE_SYMTAB,
+                flags: 0,
+                link: 2,
+                data: &symtab,
+            },
+            // Index 2: `.strtab`
+            TestSection {
+                typ: 3,
+                flags: 0,
+                link: 0,
+                data: strtab,
+            },
+        ],
+    )
+}
+
+#[test]
+fn run_to_symbol() {
+    let file = count_loop_elf();
+    let elf = elf::Elf32::parse(&file).unwrap();
+    assert_eq!(elf.symbol("loop"), Some(DRAM_BASE + 4));
+    assert_eq!(elf.symbol("done"), Some(DRAM_BASE + 16));
+    assert_eq!(elf.symbol("missing"), None);
+    assert_eq!(elf.symbol("do"), None);
+
+    let mut mem = TestMemory::new(&[]);
+    let entry = elf.load_into(&mut mem, 0, true).unwrap();
+    let mut state = CpuState::new(entry);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run_to_symbol(&elf, "done"), Ok(()));
+    assert_eq!(interp.state.pc, DRAM_BASE + 16);
+    assert_eq!(interp.state.x[11], 10);
+
+    assert_eq!(
+        interp.run_to_symbol(&elf, "missing"),
+        Err("symbol `missing` not found".to_owned())
+    );
+    assert_eq!(
+        interp.run_to_symbol(&elf, "loop"),
+        Err("ebreak at 0x10000010".to_owned())
+    );
+}
+
+#[test]
+fn trace_annotated() {
+    let file = count_loop_elf();
+    let elf = elf::Elf32::parse(&file).unwrap();
+    assert_eq!(elf.symbolize(DRAM_BASE + 12), Some(("loop", 8)));
+    assert_eq!(elf.symbolize(DRAM_BASE + 16), Some(("done", 0)));
+    assert_eq!(elf.symbolize(DRAM_BASE), None);
+
+    let mut mem = TestMemory::new(&[]);
+    let entry = elf.load_into(&mut mem, 0, true).unwrap();
+    let mut state = CpuState::new(entry);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    let mut out = Vec::new();
+    let res = interp.trace_annotated(&elf, &mut out).unwrap();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 32);
+    assert_eq!(lines[0], "0x10000000: addi a0, zero, 10");
+    assert_eq!(lines[1], "0x10000004 <loop>: addi a0, a0, -1");
+    assert_eq!(
+        lines[3],
+        "0x1000000c <loop+0x8>: bne a0, zero, 0x10000004 <loop>  # taken"
+    );
+    assert_eq!(
+        lines[30],
+        "0x1000000c <loop+0x8>: bne a0, zero, 0x10000004 <loop>  # not taken"
+    );
+    assert_eq!(lines[31], "0x10000010 <done>: ebreak");
+}
+
+#[test]
+fn setup_stack() {
+    let mut dram = vec![0xffu8; 0x100];
+    let mut mem = &mut dram[..];
+    let sp = elf::setup_stack(&mut mem, 0x100, &["prog", "-v"], &["HOME=/"]).unwrap();
+    assert_eq!(sp % 16, 0);
+
+    let word = |addr: u32| {
+        let addr = addr as usize;
+        u32::from_le_bytes([dram[addr], dram[addr + 1], dram[addr + 2], dram[addr + 3]])
+    };
+    let string = |addr: u32| {
+        let bytes = &dram[addr as usize..];
+        let len = bytes.iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(bytes[..len].to_vec()).unwrap()
+    };
+
+    // argc, argv, null, envp, null, `AT_NULL`.
+    assert_eq!(word(sp), 2);
+    assert_eq!(string(word(sp + 4)), "prog");
+    assert_eq!(string(word(sp + 8)), "-v");
+    assert_eq!(word(sp + 12), 0);
+    assert_eq!(string(word(sp + 16)), "HOME=/");
+    assert_eq!(word(sp + 20), 0);
+    assert_eq!(word(sp + 24), 0);
+    assert_eq!(word(sp + 28), 0);
+
+    // A stack that does not fit fails.
+    let mut small = [0u8; 16];
+    let mut mem = &mut small[..];
+    assert!(elf::setup_stack(&mut mem, 16, &["prog"], &[]).is_err());
+}
@@ -42,24 +42,42 @@ const ISA_TESTS: &[(&str, &str)] = &[
     ("rv32ui", "xor"),
     ("rv32ui", "xori"),
 
+    #[cfg(feature = "rv32m")]
     ("rv32um", "div"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "divu"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "mul"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "mulh"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "mulhsu"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "mulhu"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "rem"),
+    #[cfg(feature = "rv32m")]
     ("rv32um", "remu"),
 
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amoadd_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amoand_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amomax_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amomaxu_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amomin_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amominu_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amoor_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amoswap_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "amoxor_w"),
+    #[cfg(feature = "rv32a")]
     ("rv32ua", "lrsc"),
 
     #[cfg(feature = "rv32fd")]
@@ -0,0 +1,43 @@
+#![cfg(feature = "test-helpers")]
+
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::harness::*;
+use rvsim::*;
+
+#[test]
+fn riscv_test_result() {
+    // Columns: instruction setting `gp`, expected result.
+    let cases = [
+        (0x0010_0193, Ok(TestResult::Pass)),                 // li gp, 1
+        (0x00b0_0193, Ok(TestResult::Fail { test_num: 5 })), // li gp, 11
+        (0x0040_0193, Err((CpuError::Ecall, Some(Op::Ecall)))), // li gp, 4
+    ];
+    for &(instr, result) in &cases {
+        let mut mem = TestMemory::new(&[instr, 0x0000_0073]); // ecall
+        let mut state = CpuState::new(DRAM_BASE);
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        assert_eq!(RiscvTestHarness::new().run(&mut interp), result);
+    }
+
+    // Other stop reasons are passed through.
+    let mut mem = TestMemory::new(&[0x0010_0073]); // ebreak
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(
+        RiscvTestHarness::new().run(&mut interp),
+        Err((CpuError::Ebreak, Some(Op::Ebreak)))
+    );
+
+    // The same encoding is used for `tohost`.
+    assert_eq!(TestResult::from_value(0), None);
+    assert_eq!(
+        TestResult::from_value(0x1f),
+        Some(TestResult::Fail { test_num: 15 })
+    );
+}
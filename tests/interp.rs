@@ -0,0 +1,2492 @@
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::*;
+
+const MUL: u32 = 0x02c5_8533; // mul a0, a1, a2
+#[cfg(not(feature = "rv32m"))]
+const DIV: u32 = 0x02c5_c533; // div a0, a1, a2
+const AMOADD_W: u32 = 0x00c5_a52f; // amoadd.w a0, a2, (a1)
+#[cfg(not(feature = "rv32a"))]
+const LR_W: u32 = 0x1005_a52f; // lr.w a0, (a1)
+const EBREAK: u32 = 0x0010_0073;
+
+#[test]
+#[cfg(feature = "rv32m")]
+fn rv32m_enabled() {
+    let mut mem = TestMemory::new(&[MUL, EBREAK]);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = 6;
+    state.x[12] = 7;
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+    assert_eq!(state.x[10], 42);
+}
+
+#[test]
+#[cfg(feature = "rv32m")]
+fn mulh_signedness() {
+    const MULH: u32 = 0x02c5_9533; // mulh a0, a1, a2
+    const MULHSU: u32 = 0x02c5_a533; // mulhsu a0, a1, a2
+    const MULHU: u32 = 0x02c5_b533; // mulhu a0, a1, a2
+
+    // Operands where signed and unsigned interpretation diverge, with the upper 32 bits of the
+    // 64-bit product for each instruction. Columns: a1, a2, mulh, mulhsu, mulhu.
+    let cases: [(u32, u32, u32, u32, u32); 7] = [
+        // 2^62 signed, -2^62 signed by unsigned, 2^62 unsigned.
+        (
+            0x8000_0000,
+            0x8000_0000,
+            0x4000_0000,
+            0xc000_0000,
+            0x4000_0000,
+        ),
+        // -2 signed, -2 signed by unsigned, 2^33 - 2 unsigned.
+        (
+            0xffff_ffff,
+            0x0000_0002,
+            0xffff_ffff,
+            0xffff_ffff,
+            0x0000_0001,
+        ),
+        // -2 signed, 2^33 - 2 signed by unsigned and unsigned.
+        (
+            0x0000_0002,
+            0xffff_ffff,
+            0xffff_ffff,
+            0x0000_0001,
+            0x0000_0001,
+        ),
+        // 1 signed, -(2^32 - 1) signed by unsigned, (2^32 - 1)^2 unsigned.
+        (
+            0xffff_ffff,
+            0xffff_ffff,
+            0x0000_0000,
+            0xffff_ffff,
+            0xffff_fffe,
+        ),
+        (
+            0x7fff_ffff,
+            0x8000_0000,
+            0xc000_0000,
+            0x3fff_ffff,
+            0x3fff_ffff,
+        ),
+        (
+            0x8000_0000,
+            0x7fff_ffff,
+            0xc000_0000,
+            0xc000_0000,
+            0x3fff_ffff,
+        ),
+        (
+            0x8000_0000,
+            0xffff_ffff,
+            0x0000_0000,
+            0x8000_0000,
+            0x7fff_ffff,
+        ),
+    ];
+    for &(a, b, mulh, mulhsu, mulhu) in &cases {
+        for &(instr, expected) in &[(MULH, mulh), (MULHSU, mulhsu), (MULHU, mulhu)] {
+            let mut mem = TestMemory::new(&[instr, EBREAK]);
+            let mut state = CpuState::new(DRAM_BASE);
+            state.x[11] = a;
+            state.x[12] = b;
+            assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+            assert_eq!(
+                state.x[10], expected,
+                "instr {:#010x} with {:#x}, {:#x}",
+                instr, a, b
+            );
+        }
+    }
+}
+
+#[test]
+#[cfg(not(feature = "rv32m"))]
+fn rv32m_disabled() {
+    for &instr in &[MUL, DIV] {
+        assert_eq!(Op::parse(instr), None);
+
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        assert_eq!(
+            run(&mut state, &mut mem),
+            (CpuError::IllegalInstruction, None)
+        );
+        assert_eq!(state.pc, DRAM_BASE);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32a")]
+fn rv32a_enabled() {
+    let mut mem = TestMemory::new(&[AMOADD_W, EBREAK]);
+    mem.write(DRAM_BASE + 0x100, &5u32.to_le_bytes());
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = DRAM_BASE + 0x100;
+    state.x[12] = 3;
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+    assert_eq!(state.x[10], 5);
+    assert_eq!(mem.dram[0x100], 8);
+}
+
+#[test]
+#[cfg(feature = "rv32a")]
+fn amo_min_max_signedness() {
+    const AMOMIN_W: u32 = 0x80c5_a52f; // amomin.w a0, a2, (a1)
+    const AMOMAX_W: u32 = 0xa0c5_a52f; // amomax.w a0, a2, (a1)
+    const AMOMINU_W: u32 = 0xc0c5_a52f; // amominu.w a0, a2, (a1)
+    const AMOMAXU_W: u32 = 0xe0c5_a52f; // amomaxu.w a0, a2, (a1)
+
+    // Operand pairs where signed and unsigned ordering disagree, in both orders.
+    // Columns: instruction, memory value, register value, expected memory value after.
+    let cases: [(u32, u32, u32, u32); 16] = [
+        (AMOMIN_W, 0x8000_0000, 0x0000_0001, 0x8000_0000),
+        (AMOMIN_W, 0x0000_0001, 0x8000_0000, 0x8000_0000),
+        (AMOMIN_W, 0xffff_ffff, 0x0000_0000, 0xffff_ffff),
+        (AMOMIN_W, 0x0000_0000, 0xffff_ffff, 0xffff_ffff),
+        (AMOMAX_W, 0x8000_0000, 0x0000_0001, 0x0000_0001),
+        (AMOMAX_W, 0x0000_0001, 0x8000_0000, 0x0000_0001),
+        (AMOMAX_W, 0xffff_ffff, 0x0000_0000, 0x0000_0000),
+        (AMOMAX_W, 0x0000_0000, 0xffff_ffff, 0x0000_0000),
+        (AMOMINU_W, 0x8000_0000, 0x0000_0001, 0x0000_0001),
+        (AMOMINU_W, 0x0000_0001, 0x8000_0000, 0x0000_0001),
+        (AMOMINU_W, 0xffff_ffff, 0x0000_0000, 0x0000_0000),
+        (AMOMINU_W, 0x0000_0000, 0xffff_ffff, 0x0000_0000),
+        (AMOMAXU_W, 0x8000_0000, 0x0000_0001, 0x8000_0000),
+        (AMOMAXU_W, 0x0000_0001, 0x8000_0000, 0x8000_0000),
+        (AMOMAXU_W, 0xffff_ffff, 0x0000_0000, 0xffff_ffff),
+        (AMOMAXU_W, 0x0000_0000, 0xffff_ffff, 0xffff_ffff),
+    ];
+    for &(instr, old, operand, expected) in &cases {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        mem.write(DRAM_BASE + 0x100, &old.to_le_bytes());
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = DRAM_BASE + 0x100;
+        state.x[12] = operand;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.x[10], old, "{:?}", Op::parse(instr));
+        assert_eq!(
+            mem.dram[0x100..0x104],
+            expected.to_le_bytes(),
+            "{:?} with memory {:#x} and operand {:#x}",
+            Op::parse(instr),
+            old,
+            operand
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32a")]
+fn amo_destination_overlap() {
+    const AMOADD_W_X0: u32 = 0x00c5_a02f; // amoadd.w zero, a2, (a1)
+    const AMOSWAP_W_RD_RS2: u32 = 0x08a5_a52f; // amoswap.w a0, a0, (a1)
+    const AMOADD_W_RD_RS2: u32 = 0x00c5_a62f; // amoadd.w a2, a2, (a1)
+
+    // Columns: instruction, register value, expected register value and memory value after.
+    let cases: [(u32, u32, u32, u32); 3] = [
+        // With `rd` = `x0`, the loaded value is still used.
+        (AMOADD_W_X0, 3, 0, 8),
+        // With `rd` = `rs2`, the original register value is stored.
+        (AMOSWAP_W_RD_RS2, 3, 5, 3),
+        (AMOADD_W_RD_RS2, 3, 5, 8),
+    ];
+    for &(instr, reg, expect_reg, expect_mem) in &cases {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        mem.write(DRAM_BASE + 0x100, &5u32.to_le_bytes());
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = reg;
+        state.x[11] = DRAM_BASE + 0x100;
+        state.x[12] = reg;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        let rd = (instr >> 7 & 0x1f) as usize;
+        assert_eq!(state.x[0], 0);
+        assert_eq!(state.x[rd], expect_reg, "instr {:#010x}", instr);
+        assert_eq!(mem.dram[0x100] as u32, expect_mem, "instr {:#010x}", instr);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32a")]
+fn reservation_after_external_write() {
+    const LR_SC: &[u32] = &[
+        0x1005_a52f, // lr.w a0, (a1)
+        0x0010_0073, // ebreak
+        0x18d5_a62f, // sc.w a2, a3, (a1)
+        0x0010_0073, // ebreak
+    ];
+    let addr = DRAM_BASE + 0x100;
+
+    for &invalidate in &[false, true] {
+        let mut mem = TestMemory::new(LR_SC);
+        mem.write(addr, &1u32.to_le_bytes());
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = addr;
+        state.x[13] = 3;
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        assert_eq!(interp.state.x[10], 1);
+
+        // The host modifies the reserved word, e.g. emulating another hart.
+        interp.memory_mut().write(addr, &2u32.to_le_bytes());
+        if invalidate {
+            interp.invalidate_reservation();
+        }
+
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        if invalidate {
+            // The store conditional correctly fails.
+            assert_eq!(interp.state.x[12], 1);
+            assert_eq!(interp.memory().dram[0x100], 2);
+            assert_eq!(interp.last_sc_result(), Some(ScResult::Invalidated));
+        } else {
+            // Without invalidation, the store conditional wrongly succeeds, overwriting the
+            // external modification.
+            assert_eq!(interp.state.x[12], 0);
+            assert_eq!(interp.memory().dram[0x100], 3);
+            assert_eq!(interp.last_sc_result(), Some(ScResult::Success));
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32a")]
+fn sc_failure_reasons() {
+    const SC: &[u32] = &[
+        0x18d5_a62f, // sc.w a2, a3, (a1)
+        0x0010_0073, // ebreak
+    ];
+    let addr = DRAM_BASE + 0x100;
+
+    let cases = [
+        (None, ScResult::NoReservation),
+        (
+            Some(addr + 4),
+            ScResult::AddressMismatch { reserved: addr + 4 },
+        ),
+    ];
+    for &(reservation, expect) in &cases {
+        let mut mem = TestMemory::new(SC);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = addr;
+        state.reservation = reservation;
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        assert_eq!(interp.last_sc_result(), None);
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        assert_eq!(interp.state.x[12], 1);
+        assert_eq!(interp.last_sc_result(), Some(expect));
+    }
+}
+
+#[test]
+#[cfg(not(feature = "rv32a"))]
+fn rv32a_disabled() {
+    for &instr in &[AMOADD_W, LR_W] {
+        assert_eq!(Op::parse(instr), None);
+
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        assert_eq!(
+            run(&mut state, &mut mem),
+            (CpuError::IllegalInstruction, None)
+        );
+    }
+}
+
+/// A clock that counts instructions, and stops when reaching a quota.
+struct QuotaClock {
+    instret: u64,
+    quota: u64,
+}
+
+impl Clock for QuotaClock {
+    fn read_cycle(&self) -> u64 {
+        self.instret
+    }
+
+    fn read_time(&self) -> u64 {
+        self.instret
+    }
+
+    fn read_instret(&self) -> u64 {
+        self.instret
+    }
+
+    fn progress(&mut self, _op: &Op) {
+        self.instret += 1;
+    }
+
+    fn check_quota(&self) -> bool {
+        self.instret < self.quota
+    }
+}
+
+const COUNT_LOOP: &[u32] = &[
+    0x00a0_0513, // li a0, 10
+    0xfff5_0513, // 1: addi a0, a0, -1
+    0x0015_8593, // addi a1, a1, 1
+    0xfe05_1ce3, // bnez a0, 1b
+    0x0010_0073, // ebreak
+];
+
+#[test]
+fn resume_after_quota_exceeded() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut expect = CpuState::new(DRAM_BASE);
+    let mut clock = QuotaClock {
+        instret: 0,
+        quota: u64::MAX,
+    };
+    let res = Interp::new(&mut expect, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    let expect_instret = clock.instret;
+    assert_eq!(expect_instret, 32);
+
+    // Resume after every possible slice size, including a single instruction.
+    for slice in 1..=expect_instret {
+        let mut mem = TestMemory::new(COUNT_LOOP);
+        let mut state = CpuState::new(DRAM_BASE);
+        let mut clock = QuotaClock {
+            instret: 0,
+            quota: slice,
+        };
+        loop {
+            match Interp::new(&mut state, &mut mem, &mut clock).run() {
+                (CpuError::QuotaExceeded, None) => {
+                    assert_eq!(clock.instret, clock.quota);
+                    clock.quota += slice;
+                }
+                res => {
+                    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+                    break;
+                }
+            }
+        }
+        assert_eq!(clock.instret, expect_instret);
+        assert_eq!(state.x, expect.x);
+        assert_eq!(state.pc, expect.pc);
+    }
+}
+
+/// A clock modeling a simple pipeline, charging a penalty for every flush.
+struct PipelineClock {
+    cycle: u64,
+    instret: u64,
+    branches: u64,
+    traps: Vec<CpuError>,
+    taken: Vec<u32>,
+}
+
+impl PipelineClock {
+    const FLUSH_PENALTY: u64 = 3;
+
+    fn new() -> Self {
+        PipelineClock {
+            cycle: 0,
+            instret: 0,
+            branches: 0,
+            traps: Vec::new(),
+            taken: Vec::new(),
+        }
+    }
+}
+
+impl Clock for PipelineClock {
+    fn read_cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    fn read_time(&self) -> u64 {
+        self.cycle
+    }
+
+    fn read_instret(&self) -> u64 {
+        self.instret
+    }
+
+    fn progress(&mut self, _op: &Op) {
+        self.cycle += 1;
+        self.instret += 1;
+    }
+
+    fn on_branch_taken(&mut self) {
+        self.cycle += Self::FLUSH_PENALTY;
+        self.branches += 1;
+    }
+
+    fn on_trap(&mut self, cause: CpuError) {
+        self.cycle += Self::FLUSH_PENALTY;
+        self.traps.push(cause);
+    }
+
+    fn on_trap_taken(&mut self, mcause: u32) {
+        self.cycle += Self::FLUSH_PENALTY;
+        self.taken.push(mcause);
+    }
+}
+
+#[test]
+fn clock_flush_hooks() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = PipelineClock::new();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(clock.instret, 32);
+    assert_eq!(clock.branches, 9);
+    assert_eq!(clock.traps, [CpuError::Ebreak]);
+    assert_eq!(clock.cycle, 32 + 10 * PipelineClock::FLUSH_PENALTY);
+
+    // Fetch failures are also reported as traps.
+    let mut mem = TestMemory::new(&[]);
+    let mut state = CpuState::new(0);
+    clock.traps.clear();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(
+        res,
+        (
+            CpuError::IllegalFetch {
+                fell_through: false
+            },
+            None
+        )
+    );
+    assert_eq!(
+        clock.traps,
+        [CpuError::IllegalFetch {
+            fell_through: false
+        }]
+    );
+
+    // Traps delivered to the guest are reported separately.
+    let mut mem = TestMemory::new(&[0xffff_ffff, EBREAK]);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.machine.mtvec = DRAM_BASE + 4;
+    let mut clock = PipelineClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.trap_illegal = true;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(
+        interp.clock.taken,
+        [MachineCsrs::MCAUSE_ILLEGAL_INSTRUCTION]
+    );
+    assert_eq!(interp.clock.traps, [CpuError::Ebreak]);
+}
+
+#[test]
+fn fixed_frequency_clock() {
+    let code = [
+        0x0640_0513, // li a0, 100
+        0xfff5_0513, // 1: addi a0, a0, -1
+        0xfe05_1ee3, // bnez a0, 1b
+        0xc000_25f3, // rdcycle a1
+        0xc010_2673, // rdtime a2
+        EBREAK,
+    ];
+    let mut mem = TestMemory::new(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = FixedFreqClock::standard();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    // The loop retires 201 instructions, and the timer ticks once every 100 cycles.
+    assert_eq!(state.x[11], 201);
+    assert_eq!(state.x[12], 2);
+    assert_eq!(clock.cycle, 204);
+    assert_eq!(clock.instret, 204);
+}
+
+#[test]
+fn load_use_stall() {
+    let code = [
+        0x0005_a503, // lw a0, 0(a1)
+        0x0015_0613, // addi a2, a0, 1
+        0x0005_a683, // lw a3, 0(a1)
+        0x0000_0013, // nop
+        0x00d6_8733, // add a4, a3, a3
+        EBREAK,
+    ];
+    let mut mem = TestMemory::new(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = DRAM_BASE;
+    let mut clock = ScoreboardClock::load_use();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    // Only the `addi` directly after the load stalls.
+    assert_eq!(clock.stalls, 1);
+    assert_eq!(clock.instret, 6);
+    assert_eq!(clock.cycle, 7);
+}
+
+#[test]
+fn inspect_between_steps() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    for i in 1..=4 {
+        interp.step().unwrap();
+        assert_eq!(interp.clock().instret, i);
+    }
+    assert_eq!(interp.memory().dram[0..4], 0x00a0_0513u32.to_le_bytes());
+
+    // Patch the loop branch into an `ebreak`, and rewind the clock.
+    interp
+        .memory_mut()
+        .write(DRAM_BASE + 12, &0x0010_0073u32.to_le_bytes());
+    interp.clock_mut().instret = 0;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.clock().instret, 3);
+}
+
+#[test]
+fn try_run_errors() {
+    fn run_program(code: &[u32]) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut mem = TestMemory::new(code);
+        let mut state = CpuState::new(DRAM_BASE);
+        let mut clock = SimpleClock::new();
+        Interp::new(&mut state, &mut mem, &mut clock).try_run()?;
+        Ok(state.x[11])
+    }
+
+    assert_eq!(run_program(COUNT_LOOP).unwrap(), 10);
+
+    // Replace the loop branch with an invalid instruction.
+    let mut code = COUNT_LOOP.to_vec();
+    code[3] = 0;
+    let err = run_program(&code).unwrap_err();
+    assert_eq!(err.to_string(), "illegal instruction at 0x1000000c");
+    let err = err.downcast::<RunError>().unwrap();
+    assert_eq!(
+        *err,
+        RunError {
+            cause: CpuError::IllegalInstruction,
+            op: None,
+            pc: DRAM_BASE + 12,
+        }
+    );
+
+    // Custom finish conditions.
+    let mut mem = TestMemory::new(&[0x0000_0073]); // ecall
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(
+        interp.try_run().unwrap_err().to_string(),
+        "ecall at 0x10000000"
+    );
+    interp.state.pc = DRAM_BASE;
+    assert_eq!(
+        interp.try_run_with(|cause| cause == CpuError::Ecall),
+        Ok(())
+    );
+}
+
+#[test]
+fn op_names() {
+    assert_eq!(Op::parse(0x00a0_0513).unwrap().name(), "addi");
+    assert_eq!(Op::parse(EBREAK).unwrap().name(), "ebreak");
+    #[cfg(feature = "rv32a")]
+    assert_eq!(Op::parse(AMOADD_W).unwrap().name(), "amoadd.w");
+}
+
+#[test]
+fn op_kinds() {
+    // Operands are ignored.
+    let add = Op::parse(0x00c5_8533).unwrap(); // add a0, a1, a2
+    let other = Op::parse(0x0000_0033).unwrap(); // add zero, zero, zero
+    assert_ne!(add, other);
+    assert_eq!(add.kind(), OpKind::Add);
+    assert_eq!(other.kind(), OpKind::Add);
+    assert_eq!(Op::parse(0x00a0_0513).unwrap().kind(), OpKind::Addi);
+    assert_eq!(Op::parse(EBREAK).unwrap().kind(), OpKind::Ebreak);
+    #[cfg(feature = "rv32c")]
+    assert_eq!(Op::parse_c(0x852e).unwrap().kind(), OpKind::Add); // c.mv a0, a1
+}
+
+#[test]
+fn fence_fields() {
+    // Bits are `i`, `o`, `r`, `w`, from most to least significant.
+    assert_eq!(
+        Op::parse(0x0310_000f), // fence rw, w
+        Some(Op::Fence {
+            pred: 0b0011,
+            succ: 0b0001
+        })
+    );
+    assert_eq!(
+        Op::parse(0x0ff0_000f), // fence iorw, iorw
+        Some(Op::Fence {
+            pred: 0b1111,
+            succ: 0b1111
+        })
+    );
+    assert_eq!(
+        Op::parse(0x0820_000f), // fence i, r
+        Some(Op::Fence {
+            pred: 0b1000,
+            succ: 0b0010
+        })
+    );
+}
+
+#[test]
+fn disassemble() {
+    let cases = [
+        (0x00a5_0533, "add a0, a0, a0"),
+        (0x0081_2503, "lw a0, 8(sp)"),
+        (0xfe11_2e23, "sw ra, -4(sp)"),
+        (0x1234_5537, "lui a0, 0x12345"),
+        (0x0002_80e7, "jalr ra, 0(t0)"),
+        (0xfeb5_0ce3, "beq a0, a1, -8"),
+        (0x0310_000f, "fence rw, w"),
+        (0x3005_9573, "csrrw a0, 0x300, a1"),
+        (0x3002_d573, "csrrwi a0, 0x300, 5"),
+        (0x0010_0073, "ebreak"),
+    ];
+    for (instr, text) in cases {
+        assert_eq!(disasm(instr).as_deref(), Some(text));
+    }
+    assert_eq!(disasm(0), None);
+
+    #[cfg(feature = "rv32a")]
+    {
+        assert_eq!(
+            disasm(0x06b6_252f).as_deref(),
+            Some("amoadd.w.aqrl a0, a1, (a2)")
+        );
+        assert_eq!(disasm(0x1405_22af).as_deref(), Some("lr.w.aq t0, (a0)"));
+    }
+    #[cfg(feature = "rv32fd")]
+    {
+        assert_eq!(
+            disasm(0x68c5_8543).as_deref(),
+            Some("fmadd.s fa0, fa1, fa2, fa3, rne")
+        );
+        assert_eq!(disasm(0xc005_f553).as_deref(), Some("fcvt.w.s a0, fa1"));
+        assert_eq!(disasm(0x0081_2227).as_deref(), Some("fsw fs0, 4(sp)"));
+    }
+    #[cfg(feature = "rv32c")]
+    assert_eq!(disasm_c(0x0505).as_deref(), Some("addi a0, a0, 1"));
+}
+
+#[test]
+fn op_register_operands() {
+    let add = Op::parse(0x00c5_8533).unwrap(); // add a0, a1, a2
+    assert_eq!(add.reads().collect::<Vec<_>>(), [Reg::X(11), Reg::X(12)]);
+    assert_eq!(add.writes(), Some(Reg::X(10)));
+    let sw = Op::parse(0x00a1_2223).unwrap(); // sw a0, 4(sp)
+    assert_eq!(sw.reads().collect::<Vec<_>>(), [Reg::X(2), Reg::X(10)]);
+    assert_eq!(sw.writes(), None);
+    let ecall = Op::parse(0x0000_0073).unwrap();
+    assert_eq!(ecall.reads().collect::<Vec<_>>(), []);
+    assert_eq!(ecall.writes(), None);
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn op_register_files() {
+    let cases = [
+        (0xe005_8553, vec![Reg::F(11)], Some(Reg::X(10))), // fmv.x.w a0, fa1
+        (0xd005_f553, vec![Reg::X(11)], Some(Reg::F(10))), // fcvt.s.w fa0, a1
+        (
+            0x68c5_8543, // fmadd.s fa0, fa1, fa2, fa3, rne
+            vec![Reg::F(11), Reg::F(12), Reg::F(13)],
+            Some(Reg::F(10)),
+        ),
+        (0x0005_a507, vec![Reg::X(11)], Some(Reg::F(10))), // flw fa0, 0(a1)
+        (0x00a5_a027, vec![Reg::X(11), Reg::F(10)], None), // fsw fa0, 0(a1)
+        (0xa0c5_a553, vec![Reg::F(11), Reg::F(12)], Some(Reg::X(10))), // feq.s a0, fa1, fa2
+    ];
+    for (instr, reads, writes) in cases {
+        let op = Op::parse(instr).unwrap();
+        assert_eq!(op.reads().collect::<Vec<_>>(), reads, "{}", op.name());
+        assert_eq!(op.writes(), writes, "{}", op.name());
+    }
+}
+
+#[test]
+#[cfg(feature = "timing")]
+fn timing_histogram() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert!(interp.timing_histogram().is_empty());
+    interp.run();
+    let mut names = interp
+        .timing_histogram()
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    assert_eq!(names, ["addi", "bne", "ebreak"]);
+}
+
+#[test]
+fn cpu_state_visited_set() {
+    use std::collections::HashSet;
+
+    // Record the state after every step. The loop counter makes every state unique.
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut visited = HashSet::new();
+    while Interp::new(&mut state, &mut mem, &mut clock).step().is_ok() {
+        assert!(visited.insert(state.clone()));
+    }
+    assert_eq!(visited.len(), 31);
+
+    // Revisiting an identical state is detected.
+    let mut other = CpuState::new(DRAM_BASE);
+    assert!(visited.insert(other.clone()));
+    assert!(!visited.insert(other.clone()));
+
+    other.reservation = Some(DRAM_BASE);
+    assert!(visited.insert(other.clone()));
+    #[cfg(feature = "rv32fd")]
+    {
+        // Floating-point registers compare by bit pattern.
+        other.f[0] = softfloat::Sf64::NAN;
+        assert!(visited.insert(other.clone()));
+        assert!(!visited.insert(other.clone()));
+        other.f[0] = softfloat::Sf64(0x8000_0000_0000_0000);
+        assert_ne!(other.f[0], softfloat::Sf64(0));
+    }
+}
+
+#[test]
+fn last_fetch() {
+    let mut mem = TestMemory::new(&[0x00a0_0513, 0xffff_ffff]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.last_fetch(), None);
+
+    interp.step().unwrap();
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE, 0x00a0_0513)));
+
+    // The raw bits are available when the instruction cannot be parsed.
+    assert_eq!(interp.step(), Err((CpuError::IllegalInstruction, None)));
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE + 4, 0xffff_ffff)));
+
+    // Nothing was fetched when fetching fails.
+    interp.state.pc = 0;
+    assert_eq!(
+        interp.step(),
+        Err((
+            CpuError::IllegalFetch {
+                fell_through: false
+            },
+            None
+        ))
+    );
+    assert_eq!(interp.last_fetch(), None);
+}
+
+#[test]
+fn fell_through_fetch() {
+    let end = DRAM_BASE + DRAM_SIZE as u32;
+    let mut mem = TestMemory::new(&[]);
+    mem.write(end - 8, &0x0005_0067u32.to_le_bytes()); // jr a0
+    mem.write(end - 4, &0x0000_0013u32.to_le_bytes()); // nop
+    let mut clock = SimpleClock::new();
+
+    // Columns: starting pc, whether the failed fetch was reached sequentially.
+    let cases = [(end - 8, false), (end - 4, true)];
+    for &(pc, fell_through) in &cases {
+        let mut state = CpuState::new(pc);
+        state.x[10] = end + 0x100;
+        let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+        assert_eq!(res, (CpuError::IllegalFetch { fell_through }, None));
+    }
+    assert_eq!(
+        CpuError::IllegalFetch { fell_through: true }.to_string(),
+        "illegal fetch after falling through"
+    );
+}
+
+#[test]
+#[cfg(feature = "rv32c")]
+fn last_fetch_compressed() {
+    let mut mem = TestMemory::new(&[0x0000_9002]); // c.ebreak
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.step(), Err((CpuError::Ebreak, Some(Op::Ebreak))));
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE, 0x9002)));
+}
+
+#[test]
+fn compare_run_lock_step() {
+    let mut mem_a = TestMemory::new(COUNT_LOOP);
+    let mut state_a = CpuState::new(DRAM_BASE);
+    let mut clock_a = SimpleClock::new();
+    let mut mem_b = TestMemory::new(COUNT_LOOP);
+    let mut state_b = CpuState::new(DRAM_BASE);
+    let mut clock_b = SimpleClock::new();
+    let mut a = Interp::new(&mut state_a, &mut mem_a, &mut clock_a);
+    let mut b = Interp::new(&mut state_b, &mut mem_b, &mut clock_b);
+    assert!(compare_run(&mut a, &mut b).is_ok());
+
+    // Change the loop increment in the second program.
+    let mut code = COUNT_LOOP.to_vec();
+    code[2] = 0x0025_8593; // addi a1, a1, 2
+    let mut mem_b = TestMemory::new(&code);
+    a.state.pc = DRAM_BASE;
+    a.state.x = [0; 32];
+    let mut state_b = CpuState::new(DRAM_BASE);
+    let mut b = Interp::new(&mut state_b, &mut mem_b, &mut clock_b);
+    let div = compare_run(&mut a, &mut b).unwrap_err();
+    assert_eq!(div.pc, DRAM_BASE + 8);
+    assert_eq!((div.a.x[11], div.b.x[11]), (1, 2));
+    assert_eq!(
+        div.to_string(),
+        "divergence after instruction at 0x10000008, \
+         result Ok(Addi { rd: 11, rs1: 11, i_imm: 1 }) != Ok(Addi { rd: 11, rs1: 11, i_imm: 2 }), \
+         x11 0x1 != 0x2"
+    );
+
+    // Differences in machine CSRs are reported too.
+    let mut mem_b = TestMemory::new(COUNT_LOOP);
+    a.state.pc = DRAM_BASE;
+    a.state.x = [0; 32];
+    let mut state_b = CpuState::new(DRAM_BASE);
+    state_b.machine.mepc = 0x40;
+    state_b.machine.mhpmcounter[0] = 7;
+    let mut b = Interp::new(&mut state_b, &mut mem_b, &mut clock_b);
+    let div = compare_run(&mut a, &mut b).unwrap_err();
+    assert_eq!(div.pc, DRAM_BASE);
+    assert_eq!(
+        div.to_string(),
+        "divergence after instruction at 0x10000000, mepc 0x0 != 0x40, mhpmcounter3 0x0 != 0x7"
+    );
+}
+
+#[test]
+fn access_counts() {
+    let mut mem = TestMemory::new(&[
+        0x1005_2583, // lw a1, 256(a0)
+        0x10b5_2223, // sw a1, 260(a0)
+        EBREAK,
+    ]);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = DRAM_BASE;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.access_counts(), AccessCounts::default());
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+
+    // 32-bit instructions are fetched in halves when compressed instructions are supported.
+    let fetches = if cfg!(feature = "rv32c") { 6 } else { 3 };
+    assert_eq!(
+        interp.access_counts(),
+        AccessCounts {
+            loads: 1,
+            stores: 1,
+            fetches,
+        }
+    );
+}
+
+#[test]
+fn access_alignment_stats() {
+    let mut mem = TestMemory::new(&[
+        0x0005_a503, // lw a0, 0(a1)
+        0x03e5_9503, // lh a0, 62(a1)
+        0x03e5_a503, // lw a0, 62(a1)
+        0x00a5_80a3, // sb a0, 1(a1)
+        0x04a5_a023, // sw a0, 64(a1)
+        EBREAK,
+    ]);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = DRAM_BASE + 0x100;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.alignment_stats = Some(AccessAlignmentStats::new(64));
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+
+    let stats = interp.alignment_stats.unwrap();
+    assert_eq!(stats.total(), 5);
+    assert_eq!(
+        stats.histogram.into_iter().collect::<Vec<_>>(),
+        [((1, 1), 1), ((2, 62), 1), ((4, 0), 2), ((4, 62), 1)]
+    );
+    assert_eq!(stats.straddling, 1);
+    assert_eq!(stats.sub_word, 2);
+}
+
+#[test]
+#[cfg(feature = "big-endian")]
+fn big_endian_data() {
+    // Columns: instruction setting `MBE`, expected bytes in memory, `lbu` result, `lh` result.
+    let cases = [
+        (0x0000_0013, [0x44, 0x83, 0x22, 0x11], 0x44, 0x1122), // nop
+        (0x3102_a073, [0x11, 0x22, 0x83, 0x44], 0x11, 0xffff_8344), // csrs mstatush, t0
+    ];
+    for &(set_mbe, bytes, byte, half) in &cases {
+        // Instructions are little-endian in either mode.
+        let mut mem = TestMemory::new(&[
+            0x0200_0293, // li t0, 0x20
+            set_mbe,
+            0x00a5_a023, // sw a0, 0(a1)
+            0x0005_a603, // lw a2, 0(a1)
+            0x0005_c683, // lbu a3, 0(a1)
+            0x0025_9703, // lh a4, 2(a1)
+            EBREAK,
+        ]);
+        let addr = DRAM_BASE + 0x100;
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = 0x1122_8344;
+        state.x[11] = addr;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        let offset = (addr - DRAM_BASE) as usize;
+        assert_eq!(mem.dram[offset..offset + 4], bytes);
+        assert_eq!(state.x[12..15], [0x1122_8344, byte, half]);
+    }
+}
+
+#[test]
+fn stack_alignment_check() {
+    let code = [
+        0xff01_0113, // addi sp, sp, -16
+        0xffc1_0113, // addi sp, sp, -4
+        EBREAK,
+    ];
+    for &check in &[false, true] {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new_with_sp(DRAM_BASE, DRAM_BASE + 0x1000);
+        assert_eq!(state.x[2], DRAM_BASE + 0x1000);
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        interp.check_stack_alignment = check;
+        let res = interp.run();
+        if check {
+            // The aligned adjustment passes, the misaligned one trips the check.
+            assert_eq!(
+                res,
+                (
+                    CpuError::StackMisaligned,
+                    Some(Op::Addi {
+                        rd: 2,
+                        rs1: 2,
+                        i_imm: -4
+                    })
+                )
+            );
+            assert_eq!(state.pc, DRAM_BASE + 8);
+        } else {
+            assert_eq!(res.0, CpuError::Ebreak);
+        }
+        assert_eq!(state.x[2], DRAM_BASE + 0x1000 - 20);
+    }
+}
+
+#[test]
+fn max_stack_usage() {
+    let mut mem = TestMemory::new(&[
+        0xff01_0113, // addi sp, sp, -16
+        0xfe01_0113, // addi sp, sp, -32
+        0x0301_0113, // addi sp, sp, 48
+        0xff81_0113, // addi sp, sp, -8
+        EBREAK,
+    ]);
+    let top = DRAM_BASE + 0x1000;
+    let mut state = CpuState::new_with_sp(DRAM_BASE, top);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.max_stack_usage(top), 0);
+    interp.min_sp = Some(top);
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    assert_eq!(interp.min_sp, Some(top - 48));
+    assert_eq!(interp.max_stack_usage(top), 48);
+}
+
+/// Execute a single instruction at the given PC, returning the resulting state.
+fn step_at(pc: u32, instr: u32) -> CpuState {
+    let mut mem = rvsim::mem::ClosureMemory::new(|addr, bytes: &mut [u8], write| {
+        let word = instr.to_le_bytes();
+        let offset = addr.wrapping_sub(pc) as usize;
+        match word.get(offset..offset + bytes.len()) {
+            Some(src) if !write => {
+                bytes.copy_from_slice(src);
+                true
+            }
+            _ => false,
+        }
+    });
+    let mut state = CpuState::new(pc);
+    let mut clock = SimpleClock::new();
+    Interp::new(&mut state, &mut mem, &mut clock)
+        .step()
+        .unwrap();
+    state
+}
+
+#[test]
+fn lui_auipc_boundaries() {
+    // Immediates with the sign bit set are placed as-is in the upper bits.
+    assert_eq!(step_at(DRAM_BASE, 0x8000_00b7).x[1], 0x8000_0000); // lui ra, 0x80000
+    assert_eq!(step_at(DRAM_BASE, 0xffff_f0b7).x[1], 0xffff_f000); // lui ra, 0xfffff
+
+    // `auipc` adds to the PC, wrapping around the address space.
+    let auipc_80000 = 0x8000_0097; // auipc ra, 0x80000
+    let auipc_1 = 0x0000_1097; // auipc ra, 0x1
+    let auipc_fffff = 0xffff_f097; // auipc ra, 0xfffff
+    assert_eq!(step_at(DRAM_BASE, auipc_80000).x[1], 0x9000_0000);
+    assert_eq!(step_at(0x8000_0000, auipc_80000).x[1], 0);
+    assert_eq!(step_at(0xffff_f000, auipc_1).x[1], 0);
+    assert_eq!(step_at(0xffff_fff0, auipc_1).x[1], 0x0000_0ff0);
+    assert_eq!(step_at(DRAM_BASE, auipc_fffff).x[1], DRAM_BASE - 0x1000);
+    assert_eq!(step_at(0, auipc_fffff).x[1], 0xffff_f000);
+}
+
+#[test]
+fn survey_mode() {
+    let mut mem = TestMemory::new(&[
+        0xffff_ffff, // illegal encoding
+        0x0010_0513, // li a0, 1
+        0x7c00_25f3, // csrr a1, 0x7c0 (unimplemented CSR)
+        0x0020_0613, // li a2, 2
+        0x1020_0073, // sret (no supervisor mode)
+        EBREAK,
+    ]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.survey = Some(vec![]);
+    assert_eq!(interp.try_run(), Ok(()));
+    assert_eq!(
+        interp.survey,
+        Some(vec![
+            (DRAM_BASE, 0xffff_ffff),
+            (DRAM_BASE + 8, 0x7c00_25f3),
+            (DRAM_BASE + 16, 0x1020_0073)
+        ])
+    );
+    assert_eq!(interp.state.x[10], 1);
+    assert_eq!(interp.state.x[12], 2);
+}
+
+#[test]
+fn slti_sltiu_signedness() {
+    const SLTI_M1: u32 = 0xfff5_a513; // slti a0, a1, -1
+    const SLTI_1: u32 = 0x0015_a513; // slti a0, a1, 1
+    const SLTI_M2048: u32 = 0x8005_a513; // slti a0, a1, -2048
+    const SLTIU_M1: u32 = 0xfff5_b513; // sltiu a0, a1, -1
+    const SLTIU_1: u32 = 0x0015_b513; // seqz a0, a1
+    const SLTIU_M2048: u32 = 0x8005_b513; // sltiu a0, a1, -2048
+
+    // Columns: instruction, value of a1, expected value of a0.
+    let cases: [(u32, u32, u32); 22] = [
+        // `slti` compares signed, against the sign-extended immediate.
+        (SLTI_M1, 0xffff_fffe, 1),
+        (SLTI_M1, 0xffff_ffff, 0),
+        (SLTI_M1, 0, 0),
+        (SLTI_M1, 0x8000_0000, 1),
+        (SLTI_1, 0, 1),
+        (SLTI_1, 1, 0),
+        (SLTI_1, 0x7fff_ffff, 0),
+        (SLTI_1, 0x8000_0000, 1),
+        (SLTI_M2048, 0xffff_f7ff, 1),
+        (SLTI_M2048, 0xffff_f800, 0),
+        // `sltiu` compares unsigned, so -1 becomes the maximum value.
+        (SLTIU_M1, 0, 1),
+        (SLTIU_M1, 0x7fff_ffff, 1),
+        (SLTIU_M1, 0xffff_fffe, 1),
+        (SLTIU_M1, 0xffff_ffff, 0),
+        (SLTIU_M2048, 0xffff_f7ff, 1),
+        (SLTIU_M2048, 0xffff_f800, 0),
+        (SLTIU_M2048, 0x0000_0800, 1),
+        // `sltiu rd, rs1, 1` is the `seqz` idiom.
+        (SLTIU_1, 0, 1),
+        (SLTIU_1, 1, 0),
+        (SLTIU_1, 0x8000_0000, 0),
+        (SLTIU_1, 0xffff_ffff, 0),
+        (SLTIU_1, 0x7fff_ffff, 0),
+    ];
+    for &(instr, a1, expected) in &cases {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = 0xdead_beef;
+        state.x[11] = a1;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(
+            state.x[10], expected,
+            "instr {:#010x} with a1 = {:#x}",
+            instr, a1
+        );
+    }
+}
+
+/// A register file that logs every write.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+struct LoggingRegs {
+    x: [u32; 32],
+    log: Vec<(usize, u32)>,
+}
+
+impl RegFile for LoggingRegs {
+    fn read(&self, reg: usize) -> u32 {
+        self.x[reg]
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        self.log.push((reg, value));
+        self.x[reg] = value;
+    }
+}
+
+#[test]
+fn custom_register_file() {
+    let mut expect = CpuState::new(DRAM_BASE);
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    assert_eq!(run(&mut expect, &mut mem).0, CpuError::Ebreak);
+
+    let mut state = CpuState::with_regs(DRAM_BASE, LoggingRegs::default());
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut clock = SimpleClock::new();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res.0, CpuError::Ebreak);
+
+    // Execution matches the default register file.
+    assert_eq!(state.x.x, expect.x);
+    assert_eq!(state.pc, expect.pc);
+
+    // Every write was observed, and `x0` was never written.
+    assert_eq!(state.x.log.len(), 1 + 10 * 2);
+    assert_eq!(state.x.log[..3], [(10, 10), (10, 9), (11, 1)]);
+    assert_eq!(state.x.log.last(), Some(&(11, 10)));
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn fmv_x_w_to_x0() {
+    let mut mem = TestMemory::new(&[0xe005_0053, EBREAK]); // fmv.x.w zero, fa0
+    let mut state = CpuState::new(DRAM_BASE);
+    state.f[10] = softfloat::Sf64(0xffff_ffff_3f80_0000);
+    assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+    assert_eq!(state.x[0], 0);
+}
+
+#[test]
+fn write_log_undo() {
+    let mut mem = TestMemory::new(&[
+        0x0070_0513, // li a0, 7
+        0x00a5_a023, // sw a0, 0(a1)
+        0x0015_0513, // addi a0, a0, 1
+        0x00a5_80a3, // sb a0, 1(a1)
+        EBREAK,
+    ]);
+    mem.write(DRAM_BASE + 0x100, &0xaabb_ccddu32.to_le_bytes());
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = 3;
+    state.x[11] = DRAM_BASE + 0x100;
+    let initial = state.clone();
+    let initial_dram = mem.dram.clone();
+
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.write_log = Some(WriteLog::new(16));
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    let mut log = interp.write_log.take().unwrap();
+    let addr = DRAM_BASE + 0x100;
+    assert_eq!(
+        log.records().iter().copied().collect::<Vec<_>>(),
+        [
+            WriteRecord {
+                pc: DRAM_BASE,
+                location: WriteLocation::X(10),
+                old: 3,
+                new: 7,
+            },
+            WriteRecord {
+                pc: DRAM_BASE + 4,
+                location: WriteLocation::Mem { addr, size: 4 },
+                old: 0xaabb_ccdd,
+                new: 7,
+            },
+            WriteRecord {
+                pc: DRAM_BASE + 8,
+                location: WriteLocation::X(10),
+                old: 7,
+                new: 8,
+            },
+            WriteRecord {
+                pc: DRAM_BASE + 12,
+                location: WriteLocation::Mem {
+                    addr: addr + 1,
+                    size: 1,
+                },
+                old: 0,
+                new: 8,
+            },
+        ]
+    );
+
+    // Replaying the inverse restores the initial state.
+    while let Some(record) = log.pop() {
+        match record.location {
+            WriteLocation::X(reg) => state.x[reg] = record.old as u32,
+            WriteLocation::F(_) => unreachable!(),
+            WriteLocation::Mem { addr, size } => {
+                mem.write(addr, &record.old.to_le_bytes()[..size as usize])
+            }
+        }
+        state.pc = record.pc;
+    }
+    assert_eq!(state, initial);
+    assert_eq!(mem.dram, initial_dram);
+}
+
+#[test]
+fn write_log_capacity() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.write_log = Some(WriteLog::new(2));
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+
+    // Only the last writes are kept.
+    let log = interp.write_log.as_ref().unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log.records()[0].location, WriteLocation::X(10));
+    assert_eq!((log.records()[0].old, log.records()[0].new), (1, 0));
+    assert_eq!(log.records()[1].location, WriteLocation::X(11));
+    assert_eq!((log.records()[1].old, log.records()[1].new), (9, 10));
+}
+
+#[test]
+fn step_traced() {
+    let mut mem = TestMemory::new(&[
+        0x0015_0513, // addi a0, a0, 1
+        0x0005_a603, // lw a2, 0(a1)
+        0x00c5_a223, // sw a2, 4(a1)
+        EBREAK,
+    ]);
+    let addr = DRAM_BASE + 0x100;
+    mem.write(addr, &0x1234_5678u32.to_le_bytes());
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = addr;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.write_log = Some(WriteLog::new(16));
+
+    let info = interp.step_traced().unwrap();
+    assert_eq!(
+        info,
+        RetireInfo {
+            op: Op::Addi {
+                rd: 10,
+                rs1: 10,
+                i_imm: 1
+            },
+            pc: DRAM_BASE,
+            size: 4,
+            writes: vec![WriteRecord {
+                pc: DRAM_BASE,
+                location: WriteLocation::X(10),
+                old: 0,
+                new: 1,
+            }],
+            loads: vec![],
+        }
+    );
+
+    let info = interp.step_traced().unwrap();
+    assert_eq!(info.pc, DRAM_BASE + 4);
+    assert_eq!(info.loads, [(addr, 4, 0x1234_5678)]);
+    assert_eq!(info.writes[0].location, WriteLocation::X(12));
+    assert_eq!(info.writes[0].new, 0x1234_5678);
+
+    let info = interp.step_traced().unwrap();
+    assert_eq!(info.loads, []);
+    assert_eq!(
+        info.writes,
+        [WriteRecord {
+            pc: DRAM_BASE + 8,
+            location: WriteLocation::Mem {
+                addr: addr + 4,
+                size: 4
+            },
+            old: 0,
+            new: 0x1234_5678,
+        }]
+    );
+
+    assert_eq!(
+        interp.step_traced(),
+        Err((CpuError::Ebreak, Some(Op::Ebreak)))
+    );
+    // The writes are still added to the write log.
+    assert_eq!(interp.write_log.as_ref().unwrap().len(), 3);
+
+    // Without the write log, memory is not read to find the old value of a store.
+    interp.write_log = None;
+    interp.state.pc = DRAM_BASE + 8;
+    interp.state.x[12] = 0xabcd;
+    let info = interp.step_traced().unwrap();
+    assert_eq!((info.writes[0].old, info.writes[0].new), (0xabcd, 0xabcd));
+}
+
+#[test]
+fn loop_detection() {
+    let code = [
+        0x0030_0513, // li a0, 3
+        0xfff5_0513, // addi a0, a0, -1
+        0xfe05_1ee3, // bnez a0, -4
+        0x0006_2583, // lw a1, 0(a2)
+        0xfe05_8ee3, // beqz a1, -4
+        EBREAK,
+    ];
+    let flag = DRAM_BASE + 0x100;
+    for &value in &[1u32, 0] {
+        let mut mem = TestMemory::new(&code);
+        mem.write(flag, &value.to_le_bytes());
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[12] = flag;
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        interp.loop_detection = Some(8);
+        let (err, _) = interp.run();
+        if value != 0 {
+            // The counting loop makes progress, so is not reported.
+            assert_eq!(err, CpuError::Ebreak);
+        } else {
+            // Polling the flag changes nothing, so is reported when the load repeats.
+            let pc = DRAM_BASE + 12;
+            assert_eq!(err, CpuError::SuspectedInfiniteLoop { pc });
+            assert_eq!(err.to_string(), "suspected infinite loop at 0x1000000c");
+        }
+    }
+}
+
+#[test]
+fn fault_injection() {
+    let code = [
+        0x0010_0513, // li a0, 1
+        0x0020_0593, // li a1, 2
+        0x00b5_0633, // add a2, a0, a1
+        0x00b5_0633, // add a2, a0, a1
+        EBREAK,
+    ];
+    let mut mem = TestMemory::new(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // Flip a bit in the result of the third register write only.
+    let mut writes = 0;
+    interp.set_fault_injector(move |op, rd, value| {
+        writes += 1;
+        if writes == 3 {
+            assert_eq!((op.kind(), rd), (OpKind::Add, 12));
+            value ^ 0x10
+        } else {
+            value
+        }
+    });
+    interp.step().unwrap();
+    interp.step().unwrap();
+    interp.step().unwrap();
+    assert_eq!(interp.state.x[10..13], [1, 2, 0x13]);
+
+    // The next write is not corrupted.
+    interp.step().unwrap();
+    assert_eq!(interp.state.x[12], 3);
+    interp.clear_fault_injector();
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+}
+
+/// Memory made of 4 KiB pages, allocated on demand when faulting.
+struct PagedMemory {
+    pages: std::collections::HashMap<u32, Vec<u8>>,
+    allocate: bool,
+    faults: Vec<(u32, bool)>,
+}
+
+impl Memory for PagedMemory {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        match self.pages.get_mut(&(addr >> 12)) {
+            Some(page) => Memory::access(&mut page[..], addr & 0xfff, access),
+            None => false,
+        }
+    }
+
+    fn handle_fault(&mut self, addr: u32, is_write: bool) -> bool {
+        self.faults.push((addr, is_write));
+        if self.allocate {
+            self.pages.insert(addr >> 12, vec![0; 4096]);
+        }
+        self.allocate
+    }
+}
+
+#[test]
+fn fault_handler_retry() {
+    let code: &[u32] = &[
+        0x2000_05b7, // lui a1, 0x20000
+        0x0050_0513, // li a0, 5
+        0x00a5_a223, // sw a0, 4(a1)
+        0x0045_a603, // lw a2, 4(a1)
+        EBREAK,
+    ];
+    let mut page = vec![0u8; 4096];
+    for (i, word) in code.iter().enumerate() {
+        page[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+
+    for &allocate in &[true, false] {
+        let mut mem = PagedMemory {
+            pages: vec![(0, page.clone())].into_iter().collect(),
+            allocate,
+            faults: Vec::new(),
+        };
+        let mut state = CpuState::new(0);
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        let res = interp.run();
+        if allocate {
+            // The store faults once, allocating the page, and the load then succeeds.
+            assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+            assert_eq!(interp.state.x[12], 5);
+            assert_eq!(interp.access_counts().stores, 1);
+        } else {
+            assert_eq!(res.0, CpuError::IllegalAccess);
+        }
+        assert_eq!(mem.faults, [(0x2000_0004, true)]);
+    }
+}
+
+/// A writer to a shared buffer, so it can be inspected after handing it to the `Interp`.
+#[derive(Clone, Default)]
+struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedBuf {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn binary_trace_replay() {
+    let code = [
+        0x0070_0513, // li a0, 7
+        0x00a5_a023, // sw a0, 0(a1)
+        0x0015_0513, // addi a0, a0, 1
+        0x00a5_80a3, // sb a0, 1(a1)
+        0x0080_006f, // j 8
+        0x0000_0013, // nop
+        EBREAK,
+    ];
+    let mut mem = TestMemory::new(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = DRAM_BASE + 0x100;
+    let initial = state.clone();
+    let initial_dram = mem.dram.clone();
+
+    let buf = SharedBuf::default();
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.trace = Some(TraceWriter::new(Box::new(buf.clone())).unwrap());
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    assert!(interp.trace.as_ref().unwrap().error().is_none());
+
+    let data = buf.0.borrow();
+    assert_eq!(data[..5], *b"RVTR\x01");
+    let records = TraceReader::new(&data[..])
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(records.len(), 6);
+    assert_eq!(
+        records[1],
+        TraceRecord {
+            pc: DRAM_BASE + 4,
+            instr: 0x00a5_a023,
+            next_pc: DRAM_BASE + 8,
+            writes: vec![(
+                WriteLocation::Mem {
+                    addr: DRAM_BASE + 0x100,
+                    size: 4
+                },
+                7
+            )],
+        }
+    );
+    assert_eq!(records[4].next_pc, DRAM_BASE + 24);
+
+    // Replaying the trace reconstructs the final state without executing.
+    let mut replay = initial;
+    let mut replay_mem = TestMemory::new(&[]);
+    replay_mem.dram = initial_dram;
+    for record in &records {
+        assert!(record.apply(&mut replay, &mut replay_mem));
+    }
+    assert_eq!(replay, state);
+    assert_eq!(replay_mem.dram, mem.dram);
+
+    // Truncated traces and other formats are rejected.
+    let mut truncated = TraceReader::new(&data[..data.len() - 1]).unwrap();
+    assert!(truncated.nth(5).unwrap().is_err());
+    assert!(TraceReader::new(&b"\x7fELF\x01"[..]).is_err());
+}
+
+#[test]
+fn inject_instruction() {
+    let mut mem = TestMemory::new(&[]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // Arithmetic steps to the next instruction.
+    let op = interp.inject_instruction(0x0055_0513).unwrap(); // addi a0, a0, 5
+    assert_eq!(op.name(), "addi");
+    assert_eq!(interp.state.x[10], 5);
+    assert_eq!(interp.state.pc, DRAM_BASE + 4);
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE, 0x0055_0513)));
+
+    // Jumps redirect.
+    interp.inject_instruction(0x1000_00ef).unwrap(); // jal ra, 256
+    assert_eq!(interp.state.x[1], DRAM_BASE + 8);
+    assert_eq!(interp.state.pc, DRAM_BASE + 4 + 256);
+
+    // Errors are reported as for `step`.
+    assert_eq!(
+        interp.inject_instruction(0xffff_ffff),
+        Err((CpuError::IllegalInstruction, None))
+    );
+    assert_eq!(
+        interp.inject_instruction(EBREAK),
+        Err((CpuError::Ebreak, Some(Op::Ebreak)))
+    );
+
+    // Memory was never accessed.
+    assert_eq!(interp.access_counts(), AccessCounts::default());
+    assert_eq!(interp.clock.instret, 3);
+}
+
+#[test]
+#[cfg(feature = "rv32c")]
+fn inject_compressed_instruction() {
+    let mut mem = TestMemory::new(&[]);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // The upper bits of a compressed instruction are ignored.
+    interp.inject_instruction(0xffff_4515).unwrap(); // c.li a0, 5
+    assert_eq!(interp.state.x[10], 5);
+    assert_eq!(interp.state.pc, DRAM_BASE + 2);
+    assert_eq!(interp.last_fetch(), Some((DRAM_BASE, 0x4515)));
+}
+
+#[test]
+fn run_with_timeout() {
+    let mut mem = TestMemory::new(&[0x0000_006f]); // 1: j 1b
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.check_interval = 100;
+    let timeout = std::time::Duration::from_millis(10);
+    assert_eq!(interp.run_with_timeout(timeout), (CpuError::Timeout, None));
+    assert_eq!(interp.state.pc, DRAM_BASE);
+
+    // The clock is only checked between batches of instructions.
+    let instret = interp.clock.instret;
+    assert!(instret >= 100);
+    assert_eq!(instret % 100, 0);
+
+    // Other stop reasons are returned as usual.
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(
+        interp.run_with_timeout(timeout),
+        (CpuError::Ebreak, Some(Op::Ebreak))
+    );
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn f_register_bits() {
+    let mut state = CpuState::new(0);
+    let mut bits = [0u64; 32];
+    for (i, b) in bits.iter_mut().enumerate() {
+        *b = 0x3ff0_0000_0000_0000 + i as u64;
+    }
+    state.set_f_bits(bits);
+    assert_eq!(state.f[5], softfloat::Sf64(0x3ff0_0000_0000_0005));
+    assert_eq!(state.f_bits(), bits);
+
+    state.f[31] = softfloat::Sf64::from(1.0f32);
+    assert_eq!(state.f_bits()[31], 0xffff_ffff_3f80_0000);
+}
+
+#[test]
+fn fcsr_fields() {
+    let mut state = CpuState::new(0);
+    state.fcsr = 0b101_10011;
+    assert_eq!(state.fflags(), 0b10011);
+    assert_eq!(state.frm(), 0b101);
+
+    state.set_frm(0b1_010);
+    assert_eq!(state.fcsr, 0b010_10011);
+    state.set_fflags(0b11_00100);
+    assert_eq!(state.fcsr, 0b010_00100);
+    state.clear_fflags();
+    assert_eq!(state.fcsr, 0b010_00000);
+    assert_eq!(state.frm(), 0b010);
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn clean_fflags() {
+    const NX: u32 = 0b00001;
+    const UF: u32 = 0b00010;
+    let code = [
+        0xd005_7553, // fcvt.s.w fa0, a0
+        EBREAK,
+    ];
+    // Columns: integer to convert, flags expected to be raised.
+    let cases = [(3, 0), (0x0100_0001, NX)];
+    for &(value, expect) in &cases {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = value;
+        state.set_fflags(UF);
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        let (res, raised) = interp.with_clean_fflags(|interp| interp.run().0);
+        assert_eq!(res, CpuError::Ebreak);
+        assert_eq!(raised, expect, "value {:#x}", value);
+        assert_eq!(interp.state.fflags(), UF | expect);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn single_precision_nan_boxing() {
+    const FMV_W_X: u32 = 0xf005_8553; // fmv.w.x fa0, a1
+    const FMV_X_W: u32 = 0xe005_0553; // fmv.x.w a0, fa0
+    const FLW: u32 = 0x0005_a507; // flw fa0, 0(a1)
+
+    for &bits in &[0, 0x3f80_0000, 0x7fc0_0000, 0x8000_0000, 0xffff_ffff] {
+        // A single-precision value written by `fmv.w.x` is boxed, and round-trips exactly.
+        let mut mem = TestMemory::new(&[FMV_W_X, FMV_X_W, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.f[10] = softfloat::Sf64(0x1234_5678_9abc_def0);
+        state.x[11] = bits;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.f[10].0, 0xffff_ffff_0000_0000 | bits as u64);
+        assert_eq!(state.x[10], bits);
+
+        // Likewise when loaded from memory by `flw`.
+        let mut mem = TestMemory::new(&[FLW, EBREAK]);
+        mem.write(DRAM_BASE + 0x100, &bits.to_le_bytes());
+        let mut state = CpuState::new(DRAM_BASE);
+        state.f[10] = softfloat::Sf64(0x1234_5678_9abc_def0);
+        state.x[11] = DRAM_BASE + 0x100;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.f[10].0, 0xffff_ffff_0000_0000 | bits as u64);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn single_precision_sign_injection() {
+    const BOX: u64 = 0xffff_ffff_0000_0000;
+    // Columns: instruction, operand bits kept, operand bits flipped.
+    let cases: [(u32, u32, u32); 3] = [
+        (0x20b5_8553, 0xffff_ffff, 0),           // fmv.s fa0, fa1
+        (0x20b5_9553, 0xffff_ffff, 0x8000_0000), // fneg.s fa0, fa1
+        (0x20b5_a553, 0x7fff_ffff, 0),           // fabs.s fa0, fa1
+    ];
+    for &(instr, keep, flip) in &cases {
+        for &bits in &[
+            0x3f80_0000u32,
+            0xbf80_0000,
+            0x7fc0_0001,
+            0xff80_0000,
+            0x8000_0000,
+        ] {
+            let mut mem = TestMemory::new(&[instr, EBREAK]);
+            let mut state = CpuState::new(DRAM_BASE);
+            state.f[10] = softfloat::Sf64(0x1234_5678_9abc_def0);
+            state.f[11] = softfloat::Sf64(BOX | bits as u64);
+            assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+            // Only the sign bit changes, and the result is properly boxed.
+            assert_eq!(
+                state.f[10].0,
+                BOX | ((bits & keep) ^ flip) as u64,
+                "instr {:#010x}, operand {:#010x}",
+                instr,
+                bits
+            );
+        }
+    }
+
+    // Upper bits of a double-precision operand do not leak into the result.
+    for &instr in &[
+        0x20c5_8553, // fsgnj.s fa0, fa1, fa2
+        0x20c5_9553, // fsgnjn.s fa0, fa1, fa2
+        0x20c5_a553, // fsgnjx.s fa0, fa1, fa2
+    ] {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.f[11] = softfloat::Sf64(BOX | 0x3f80_0000);
+        state.f[12] = softfloat::Sf64(0xbff0_0000_0000_0000); // -1.0 in double precision
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.f[10].0 & BOX, BOX, "instr {:#010x}", instr);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn fmin_fmax_signaling_nan() {
+    const BOX: u64 = 0xffff_ffff_0000_0000;
+    let code = [
+        0x28c5_8553, // fmin.s fa0, fa1, fa2
+        0x2af7_16d3, // fmax.d fa3, fa4, fa5
+        0x28c8_9853, // fmax.s fa6, fa7, fa2
+        EBREAK,
+    ];
+    // Columns: legacy semantics, expected fa0, fa3 and fa6.
+    let cases = [
+        (
+            false,
+            BOX | 0x3f80_0000,
+            0x4000_0000_0000_0000,
+            BOX | 0x3f80_0000,
+        ),
+        (
+            true,
+            BOX | 0x7fc0_0000,
+            0x7ff8_0000_0000_0000,
+            BOX | 0x3f80_0000,
+        ),
+    ];
+    for &(legacy, fa0, fa3, fa6) in &cases {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.f[11] = softfloat::Sf64(BOX | 0x7fa0_0000); // signaling NaN
+        state.f[12] = softfloat::Sf64(BOX | 0x3f80_0000); // 1.0
+        state.f[14] = softfloat::Sf64(0x7ff4_0000_0000_0000); // signaling NaN
+        state.f[15] = softfloat::Sf64(0x4000_0000_0000_0000); // 2.0
+        state.f[17] = softfloat::Sf64(BOX | 0x7fc0_0000); // quiet NaN
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        interp.legacy_fmin_fmax = legacy;
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        assert_eq!(state.f[10].0, fa0, "legacy {}", legacy);
+        assert_eq!(state.f[13].0, fa3, "legacy {}", legacy);
+        // A quiet NaN input returns the other input with either semantics.
+        assert_eq!(state.f[16].0, fa6, "legacy {}", legacy);
+        assert_eq!(state.fflags(), 0x10);
+    }
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn fcvt_integer_flags() {
+    const BOX: u64 = 0xffff_ffff_0000_0000;
+    const NV: u32 = 0x10;
+    const NX: u32 = 0x01;
+    // Columns: instruction, operand bits, expected result, expected flags. Out-of-range values
+    // raise only invalid, even when they also have a fractional part.
+    let cases: [(u32, u64, u32, u32); 18] = [
+        (0xc005_7553, BOX | 0x4040_0000, 3, 0),  // fcvt.w.s 3.0
+        (0xc005_7553, BOX | 0x4020_0000, 2, NX), // fcvt.w.s 2.5
+        (0xc005_7553, BOX | 0x4f32_d05e, 0x7fff_ffff, NV), // fcvt.w.s 3e9
+        (0xc005_7553, BOX | 0xcf32_d05e, 0x8000_0000, NV), // fcvt.w.s -3e9
+        (0xc015_7553, BOX | 0x4040_0000, 3, 0),  // fcvt.wu.s 3.0
+        (0xc015_7553, BOX | 0x4020_0000, 2, NX), // fcvt.wu.s 2.5
+        (0xc015_7553, BOX | 0xbe80_0000, 0, NX), // fcvt.wu.s -0.25
+        (0xc015_7553, BOX | 0x4f95_02f9, 0xffff_ffff, NV), // fcvt.wu.s 5e9
+        (0xc015_7553, BOX | 0xbfc0_0000, 0, NV), // fcvt.wu.s -1.5
+        (0xc205_7553, 0x4008_0000_0000_0000, 3, 0), // fcvt.w.d 3.0
+        (0xc205_7553, 0x4004_0000_0000_0000, 2, NX), // fcvt.w.d 2.5
+        (0xc205_7553, 0x41df_ffff_ffe0_0000, 0x7fff_ffff, NV), // fcvt.w.d 2147483647.5
+        (0xc205_7553, 0xc1e0_0000_0018_0000, 0x8000_0000, NV), // fcvt.w.d -2147483648.75
+        (0xc215_7553, 0x4008_0000_0000_0000, 3, 0), // fcvt.wu.d 3.0
+        (0xc215_7553, 0x41ef_ffff_ffd0_0000, 0xffff_fffe, NX), // fcvt.wu.d 4294967294.5
+        (0xc215_7553, 0x41ef_ffff_fff0_0000, 0xffff_ffff, NV), // fcvt.wu.d 4294967295.5
+        (0xc215_7553, 0xbfe0_0000_0000_0000, 0, NX), // fcvt.wu.d -0.5
+        (0xc215_7553, 0xbff8_0000_0000_0000, 0, NV), // fcvt.wu.d -1.5
+    ];
+    for &(instr, bits, result, flags) in &cases {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.f[10] = softfloat::Sf64(bits);
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        let context = format!("{} of {:#x}", disasm(instr).unwrap(), bits);
+        assert_eq!(state.x[10], result, "{}", context);
+        assert_eq!(state.fflags(), flags, "{}", context);
+    }
+}
+
+#[test]
+#[cfg(feature = "zicond")]
+fn zicond_select() {
+    // a0 = a2 != 0 ? a1 : a4
+    let code = [
+        0x0ec5_d533, // czero.eqz a0, a1, a2
+        0x0ec7_76b3, // czero.nez a3, a4, a2
+        0x00d5_6533, // or a0, a0, a3
+        EBREAK,
+    ];
+    for &(cond, expected) in &[(0, 22), (1, 11), (0x8000_0000, 11)] {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = 11;
+        state.x[12] = cond;
+        state.x[14] = 22;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.x[10], expected, "condition {:#x}", cond);
+    }
+}
+
+#[test]
+fn hpm_counters() {
+    let code = [
+        0x0010_0293, // li t0, 1
+        0x3232_9073, // csrw mhpmevent3, t0
+        0x0020_0293, // li t0, 2
+        0x3242_9073, // csrw mhpmevent4, t0
+        0x0030_0293, // li t0, 3
+        0x3252_9073, // csrw mhpmevent5, t0
+        0x0040_0293, // li t0, 4
+        0x3262_9073, // csrw mhpmevent6, t0
+        0x0005_a503, // lw a0, 0(a1)
+        0x00a5_a223, // sw a0, 4(a1)
+        0x0000_0463, // beqz zero, 8
+        0x0000_0013, // nop
+        0xc030_2673, // csrr a2, hpmcounter3
+        0xc040_26f3, // csrr a3, hpmcounter4
+        0xb050_2773, // csrr a4, mhpmcounter5
+        0xb060_27f3, // csrr a5, mhpmcounter6
+        0xc870_2873, // csrr a6, hpmcounter7h
+        EBREAK,
+    ];
+    for &inhibit in &[false, true] {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = DRAM_BASE + 0x100;
+        state.x[16] = 0xdead;
+        if inhibit {
+            state.machine.mcountinhibit = 1 << 6;
+        }
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        // Instructions are counted from the one that selected the event.
+        assert_eq!(state.x[12], 10);
+        assert_eq!(state.x[13], 1);
+        assert_eq!(state.x[14], 1);
+        assert_eq!(state.x[15], if inhibit { 0 } else { 1 });
+        // Unconfigured counters read as zero.
+        assert_eq!(state.x[16], 0);
+        assert_eq!(state.machine.mhpmcounter[0], 16);
+    }
+}
+
+#[test]
+fn read_only_csr_writes() {
+    // Reading `cycle` succeeds, including with instructions that do not write.
+    for &instr in &[
+        0xc000_2573, // csrrs a0, cycle, zero
+        0xc000_6573, // csrrsi a0, cycle, 0
+        0xc000_7573, // csrrci a0, cycle, 0
+    ] {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = 0xdead_beef;
+        assert_eq!(run(&mut state, &mut mem).0, CpuError::Ebreak);
+        assert_eq!(state.x[10], 0, "instr {:#010x}", instr);
+    }
+
+    // Writing `cycle` is illegal, and leaves `rd` unaltered.
+    for &instr in &[
+        0xc005_9073, // csrrw zero, cycle, a1
+        0xc005_a573, // csrrs a0, cycle, a1
+        0xc000_f573, // csrrci a0, cycle, 1
+    ] {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = 0xdead_beef;
+        state.x[11] = 1;
+        let (err, op) = run(&mut state, &mut mem);
+        assert_eq!(err, CpuError::IllegalInstruction, "instr {:#010x}", instr);
+        assert!(op.is_some());
+        assert_eq!(state.x[10], 0xdead_beef);
+    }
+}
+
+#[test]
+fn counter_csr_writes() {
+    for &instr in &[
+        0xc005_9073, // csrrw zero, cycle, a1
+        0xc805_9073, // csrrw zero, cycleh, a1
+        0xc015_9073, // csrrw zero, time, a1
+        0xc815_9073, // csrrw zero, timeh, a1
+        0xc025_9073, // csrrw zero, instret, a1
+        0xc825_9073, // csrrw zero, instreth, a1
+        0xc025_b573, // csrrc a0, instret, a1
+        0xc810_5573, // csrrwi a0, timeh, 0
+    ] {
+        let mut mem = TestMemory::new(&[instr, EBREAK]);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[11] = 1;
+        assert_eq!(
+            run(&mut state, &mut mem).0,
+            CpuError::IllegalInstruction,
+            "instr {:#010x}",
+            instr
+        );
+    }
+}
+
+#[test]
+fn snapshot_restore() {
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    for _ in 0..5 {
+        interp.step().unwrap();
+    }
+    let snapshot = interp.snapshot();
+    assert_eq!(snapshot.clock.instret, 5);
+
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    let finished = interp.state.clone();
+    assert_eq!(interp.clock.instret, 32);
+
+    // Restoring repeatedly resumes from the same point.
+    for _ in 0..2 {
+        interp.restore(&snapshot);
+        assert_eq!(*interp.state, snapshot.state);
+        assert_eq!(interp.clock.instret, 5);
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        assert_eq!(*interp.state, finished);
+        assert_eq!(interp.clock.instret, 32);
+    }
+}
+
+#[test]
+fn execute_after_write() {
+    let code = [
+        0x00a5_a823, // sw a0, 16(a1)
+        0x00c0_006f, // j 16
+    ];
+    for &detect in &[false, true] {
+        let mut mem = TestMemory::new(&code);
+        let mut state = CpuState::new(DRAM_BASE);
+        state.x[10] = EBREAK;
+        state.x[11] = DRAM_BASE;
+        let mut clock = SimpleClock::new();
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        if detect {
+            interp.written = Some(Default::default());
+        }
+        let res = interp.try_run();
+        if detect {
+            let cause = CpuError::ExecuteAfterWrite {
+                addr: DRAM_BASE + 16,
+            };
+            assert_eq!(
+                res,
+                Err(RunError {
+                    cause,
+                    op: None,
+                    pc: DRAM_BASE + 16
+                })
+            );
+            assert_eq!(cause.to_string(), "execute after write to 0x10000010");
+            assert_eq!(interp.written.as_ref().unwrap().len(), 4);
+
+            // Removing the addresses allows execution.
+            interp.written.as_mut().unwrap().clear();
+            assert_eq!(interp.try_run(), Ok(()));
+        } else {
+            assert_eq!(res, Ok(()));
+        }
+    }
+}
+
+#[test]
+fn run_cancellable() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let setter = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            stop.store(true, Ordering::Relaxed);
+        })
+    };
+
+    let mut mem = TestMemory::new(&[0x0000_006f]); // 1: j 1b
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run_cancellable(&stop), (CpuError::Cancelled, None));
+    assert_eq!(interp.state.pc, DRAM_BASE);
+    assert_eq!(interp.clock.instret % 1000, 0);
+    setter.join().unwrap();
+}
+
+#[test]
+fn jump_link_register() {
+    const TARGET: u32 = DRAM_BASE + 0x100;
+
+    // Columns: instruction, expected pc, expected register written.
+    let cases = [
+        // With `rd` = `x0`, no register is written.
+        (0x0100_006f, DRAM_BASE + 16, None), // jal zero, 16
+        (0x0002_8067, TARGET, None),         // jalr zero, 0(t0)
+        (0x0002_80e7, TARGET, Some((1, DRAM_BASE + 4))), // jalr ra, 0(t0)
+        // With `rd` = `rs1`, the base is read before writing the link.
+        (0x0082_82e7, TARGET + 8, Some((5, DRAM_BASE + 4))), // jalr t0, 8(t0)
+    ];
+    for &(instr, pc, link) in &cases {
+        let mut mem = TestMemory::new(&[instr]);
+        let mut state = CpuState::new(DRAM_BASE);
+        for (i, x) in state.x.iter_mut().enumerate().skip(1) {
+            *x = 0x1111 * i as u32;
+        }
+        state.x[5] = TARGET;
+        let mut expect = state.clone();
+        expect.pc = pc;
+        if let Some((reg, value)) = link {
+            expect.x[reg] = value;
+        }
+
+        let mut clock = SimpleClock::new();
+        Interp::new(&mut state, &mut mem, &mut clock)
+            .step()
+            .unwrap();
+        assert_eq!(state, expect, "instr {:#010x}", instr);
+    }
+}
+
+#[test]
+fn periodic_callback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    let mut mem = TestMemory::new(COUNT_LOOP);
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    {
+        let calls = Rc::clone(&calls);
+        interp.set_periodic(10, move |state, clock: &SimpleClock| {
+            calls.borrow_mut().push((clock.instret, state.x[11]));
+        });
+    }
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    assert_eq!(*calls.borrow(), [(10, 3), (20, 6), (30, 10)]);
+
+    interp.clear_periodic();
+    interp.state.pc = DRAM_BASE;
+    assert_eq!(interp.run().0, CpuError::Ebreak);
+    assert_eq!(calls.borrow().len(), 3);
+}
+
+#[test]
+#[cfg(feature = "rv32fd")]
+fn softfloat_total_cmp() {
+    let values = [
+        f64::NAN,
+        -f64::NAN,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::MAX,
+        f64::MIN,
+        f64::MIN_POSITIVE,
+        -f64::MIN_POSITIVE,
+        5e-324,
+        -5e-324,
+        1.5,
+        -1.5,
+        0.0,
+        -0.0,
+    ];
+    for &a in &values {
+        for &b in &values {
+            assert_eq!(
+                softfloat::Sf64::from(a).total_cmp(&softfloat::Sf64::from(b)),
+                a.total_cmp(&b),
+                "{a} vs {b}"
+            );
+            let (a, b) = (a as f32, b as f32);
+            assert_eq!(
+                softfloat::Sf32::from(a).total_cmp(&softfloat::Sf32::from(b)),
+                a.total_cmp(&b),
+                "{a} vs {b}"
+            );
+        }
+    }
+}
+
+#[test]
+fn run_to_syscall() {
+    let mut asm = rvsim::asm::AsmBuilder::new();
+    asm.li(17, 64) // a7 = write
+        .li(10, 1)
+        .li(11, 0x1234)
+        .li(12, 5)
+        .ecall()
+        .addi(13, 10, 1) // a3 = a0 + 1
+        .ebreak();
+    let mut mem = TestMemory::new(&[]);
+    mem.write(DRAM_BASE, &asm.build().unwrap());
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    let syscall = interp.run_to_syscall().unwrap();
+    assert_eq!(syscall.number, 64);
+    assert_eq!(syscall.args, [1, 0x1234, 5, 0, 0, 0]);
+    interp.state.x[10] = 41;
+    assert_eq!(
+        interp.run_to_syscall(),
+        Err((CpuError::Ebreak, Some(Op::Ebreak)))
+    );
+    assert_eq!(state.x[13], 42);
+}
+
+#[test]
+fn interrupt_priority() {
+    const HANDLER: u32 = DRAM_BASE + 0x100;
+    let mut mem = TestMemory::new(&[
+        0x1000_02b7, // lui t0, 0x10000
+        0x1002_8293, // addi t0, t0, 0x100
+        0x3052_9073, // csrw mtvec, t0
+        0x7ff0_0313, // li t1, 0x7ff
+        0x0813_0313, // addi t1, t1, 0x81
+        0x3043_1073, // csrw mie, t1
+        0x3004_6073, // csrsi mstatus, 8
+        0x0000_0013, // nop
+        0x0010_0073, // ebreak
+    ]);
+    let handler: &[u32] = &[
+        0x3420_2573, // csrr a0, mcause
+        0x0010_0073, // ebreak
+        0x3020_0073, // mret
+    ];
+    for (i, word) in handler.iter().enumerate() {
+        mem.write(HANDLER + 4 * i as u32, &word.to_le_bytes());
+    }
+    let mut state = CpuState::new(DRAM_BASE);
+    state.machine.mip = MachineCsrs::MIP_MTIP | MachineCsrs::MIP_MEIP;
+    let mut clock = PipelineClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // Both interrupts are pending once enabled, and the external interrupt is taken first.
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.clock.taken, [MachineCsrs::MCAUSE_INTERRUPT | 11]);
+    let nop = DRAM_BASE + 0x1c;
+    let m = &interp.state.machine;
+    assert_eq!(interp.state.x[10], MachineCsrs::MCAUSE_INTERRUPT | 11);
+    assert_eq!(m.mepc, nop);
+    assert_eq!(m.mstatus & MachineCsrs::MSTATUS_MIE, 0);
+    assert_ne!(m.mstatus & MachineCsrs::MSTATUS_MPIE, 0);
+
+    // After returning, the timer interrupt is still pending, and is taken next.
+    interp.state.machine.mip &= !MachineCsrs::MIP_MEIP;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.state.x[10], MachineCsrs::MCAUSE_INTERRUPT | 7);
+    assert_eq!(interp.state.machine.mepc, nop);
+    assert_eq!(interp.clock.taken.len(), 2);
+
+    // With no pending interrupts, execution continues after the interrupted instruction.
+    interp.state.machine.mip = 0;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.state.pc, nop + 8);
+    assert_ne!(interp.state.machine.mstatus & MachineCsrs::MSTATUS_MIE, 0);
+}
+
+#[test]
+fn ecall_trap() {
+    const HANDLER: u32 = DRAM_BASE + 0x100;
+    let mut mem = TestMemory::new(&[
+        0x1000_02b7, // lui t0, 0x10000
+        0x1002_8293, // addi t0, t0, 0x100
+        0x3052_9073, // csrw mtvec, t0
+        0x0050_0513, // li a0, 5
+        0x0000_0073, // ecall
+        0x0010_0073, // ebreak
+    ]);
+    let handler: &[u32] = &[
+        0x3410_22f3, // csrr t0, mepc
+        0x0042_8293, // addi t0, t0, 4
+        0x3412_9073, // csrw mepc, t0
+        0x0015_0513, // addi a0, a0, 1
+        0x3020_0073, // mret
+    ];
+    for (i, word) in handler.iter().enumerate() {
+        mem.write(HANDLER + 4 * i as u32, &word.to_le_bytes());
+    }
+    let ecall = DRAM_BASE + 0x10;
+
+    // By default, the host handles the call.
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = PipelineClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    assert_eq!(interp.run(), (CpuError::Ecall, Some(Op::Ecall)));
+    assert_eq!(interp.state.pc, ecall + 4);
+    assert_eq!(interp.state.x[10], 5);
+    assert_eq!(interp.clock.traps, [CpuError::Ecall]);
+    assert!(interp.clock.taken.is_empty());
+
+    // In trap mode, the guest handler services the call and returns after it.
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = PipelineClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.trap_ecall = true;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(interp.state.x[10], 6);
+    assert_eq!(interp.state.pc, ecall + 8);
+    assert_eq!(interp.state.machine.mcause, MachineCsrs::MCAUSE_ECALL_M);
+    assert_eq!(interp.state.machine.mepc, ecall + 4);
+    // The clock is notified of the delivered trap, and the flush is charged once.
+    assert_eq!(interp.clock.taken, [MachineCsrs::MCAUSE_ECALL_M]);
+    assert_eq!(interp.clock.traps, [CpuError::Ebreak]);
+    assert_eq!(
+        interp.clock.cycle,
+        interp.clock.instret + 3 * PipelineClock::FLUSH_PENALTY
+    );
+}
+
+#[test]
+fn supported_csrs() {
+    let mut mem = TestMemory::new(&[]);
+    let mut clock = SimpleClock::new();
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    let without_seed = interp.supported_csrs();
+    interp.entropy = Some(Box::new(|| 0));
+    let csrs = interp.supported_csrs();
+    assert_eq!(csrs.len(), without_seed.len() + 1);
+    assert!(csrs.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    let find = |id| csrs.iter().find(|csr| csr.id == id);
+    assert_eq!(find(0x300).unwrap().name, "mstatus");
+    assert_eq!(find(0x015).unwrap().name, "seed");
+    assert_eq!(find(0xB1F).unwrap().name, "mhpmcounter31");
+    assert_eq!(find(0xC83).unwrap().name, "hpmcounter3h");
+    assert!(!find(0xC00).unwrap().writable);
+    assert!(find(0xB00).is_none());
+
+    // The list matches what instructions can actually access.
+    for id in 0..4096 {
+        let mut state = CpuState::new(DRAM_BASE);
+        let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+        interp.entropy = Some(Box::new(|| 0));
+        let csr = find(id);
+        let read = id << 20 | 2 << 12 | 10 << 7 | 0x73; // csrrs a0, id, x0
+        assert_eq!(interp.inject_instruction(read).is_ok(), csr.is_some());
+        let write = id << 20 | 1 << 12 | 0x73; // csrrw x0, id, x0
+        let writable = csr.is_some_and(|csr| csr.writable);
+        assert_eq!(interp.inject_instruction(write).is_ok(), writable);
+    }
+}
+
+/// A device that can be read, counting loads, but rejects stores.
+struct ReadOnlyDevice {
+    data: Vec<u8>,
+    loads: usize,
+}
+
+impl Memory for ReadOnlyDevice {
+    fn access<T: MemoryValue>(&mut self, addr: u32, access: MemoryAccess<T>) -> bool {
+        match access {
+            MemoryAccess::Store(_) => false,
+            _ => {
+                self.loads += 1;
+                Memory::access(&mut self.data[..], addr, access)
+            }
+        }
+    }
+}
+
+#[test]
+fn step_atomic() {
+    const LW: u32 = 0x0005_a503; // lw a0, 0(a1)
+    let dev = ReadOnlyDevice {
+        data: vec![7, 0, 0, 0],
+        loads: 0,
+    };
+    let mut mem = TestMemory::with_device(&[LW, AMOADD_W, EBREAK], dev);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = 0x55;
+    state.x[11] = 0x100;
+    let before = state.clone();
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+
+    // A load from a bad address leaves the PC at the instruction.
+    assert_eq!(
+        interp.step_atomic(),
+        Err((
+            CpuError::IllegalAccess,
+            Some(Op::Lw {
+                rd: 10,
+                rs1: 11,
+                i_imm: 0
+            })
+        ))
+    );
+    assert_eq!(*interp.state, before);
+
+    // The loaded value of an atomic instruction whose store fails is not written, but the load
+    // itself is not undone.
+    interp.state.pc = DRAM_BASE + 4;
+    interp.state.x[11] = DEVICE_BASE;
+    let before = interp.state.clone();
+    let res = interp.step_atomic();
+    #[cfg(feature = "rv32a")]
+    assert_eq!(res.unwrap_err().0, CpuError::IllegalAccess);
+    #[cfg(not(feature = "rv32a"))]
+    assert_eq!(res, Err((CpuError::IllegalInstruction, None)));
+    assert_eq!(*interp.state, before);
+    #[cfg(feature = "rv32a")]
+    {
+        assert_eq!(interp.mem.dev.loads, 1);
+
+        // Writes are also kept out of the write log.
+        interp.write_log = Some(WriteLog::new(16));
+        assert_eq!(interp.step_atomic().unwrap_err().0, CpuError::IllegalAccess);
+        assert_eq!(*interp.state, before);
+        assert!(interp.write_log.as_ref().unwrap().is_empty());
+
+        // Whereas `step` leaves the partial write.
+        assert_eq!(interp.step().unwrap_err().0, CpuError::IllegalAccess);
+        assert_eq!(interp.state.x[10], 7);
+        assert_eq!(interp.write_log.as_ref().unwrap().len(), 1);
+        interp.state.pc = DRAM_BASE + 4;
+    }
+
+    // Other errors and successful steps are kept.
+    interp.state.pc = DRAM_BASE + 8;
+    assert_eq!(
+        interp.step_atomic(),
+        Err((CpuError::Ebreak, Some(Op::Ebreak)))
+    );
+    assert_eq!(interp.state.pc, DRAM_BASE + 12);
+
+    // Performance counters still count the attempt.
+    interp.state.machine.mhpmevent[0] = MachineCsrs::HPM_EVENT_INSTRET;
+    interp.state.pc = DRAM_BASE;
+    interp.state.x[11] = 0x100;
+    assert_eq!(interp.step_atomic().unwrap_err().0, CpuError::IllegalAccess);
+    assert_eq!(interp.state.pc, DRAM_BASE);
+    assert_eq!(interp.state.machine.mhpmcounter[0], 1);
+
+    // Rolled back steps are also kept out of the trace.
+    #[cfg(feature = "rv32a")]
+    {
+        let buf = SharedBuf::default();
+        interp.trace = Some(TraceWriter::new(Box::new(buf.clone())).unwrap());
+        interp.state.pc = DRAM_BASE + 4;
+        interp.state.x[11] = DEVICE_BASE;
+        assert_eq!(interp.step_atomic().unwrap_err().0, CpuError::IllegalAccess);
+        assert_eq!(buf.0.borrow().len(), 5);
+        interp.state.pc = DRAM_BASE + 8;
+        assert_eq!(interp.step_atomic().unwrap_err().0, CpuError::Ebreak);
+        let data = buf.0.borrow();
+        assert_eq!(TraceReader::new(&data[..]).unwrap().count(), 1);
+    }
+}
+
+#[test]
+fn system_decode() {
+    let cases = [
+        (0x0000_0073, Op::Ecall),  // ecall
+        (0x0010_0073, Op::Ebreak), // ebreak
+        (0x1020_0073, Op::Sret),   // sret
+        (0x1050_0073, Op::Wfi),    // wfi
+        (0x3020_0073, Op::Mret),   // mret
+    ];
+    for &(instr, op) in &cases {
+        assert_eq!(Op::parse(instr), Some(op), "instr {:#010x}", instr);
+        // Operand fields must be zero.
+        assert_eq!(Op::parse(instr | 10 << 7), None, "instr {:#010x}", instr);
+        assert_eq!(Op::parse(instr | 10 << 15), None, "instr {:#010x}", instr);
+    }
+    // Unassigned `funct12` values are illegal.
+    assert_eq!(Op::parse(0x0020_0073), None);
+    assert_eq!(Op::parse(0x1030_0073), None);
+    assert_eq!(Op::parse(0x7020_0073), None);
+
+    // `wfi` does not wait, and `sret` is illegal without supervisor mode.
+    let mut mem = TestMemory::new(&[0x1050_0073, 0x1020_0073]);
+    let mut state = CpuState::new(DRAM_BASE);
+    assert_eq!(
+        run(&mut state, &mut mem),
+        (CpuError::IllegalInstruction, Some(Op::Sret))
+    );
+    // Like other decoded instructions found illegal, it advances the PC.
+    assert_eq!(state.pc, DRAM_BASE + 8);
+}
+
+#[test]
+fn sfence_vma() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let code = [
+        0x1200_0073, // sfence.vma
+        0x1205_0073, // sfence.vma a0
+        0x12b5_0073, // sfence.vma a0, a1
+        EBREAK,
+    ];
+    assert_eq!(Op::parse(code[2]), Some(Op::SfenceVma { rs1: 10, rs2: 11 }));
+    assert_eq!(Op::parse(code[2]).unwrap().to_string(), "sfence.vma a0, a1");
+    assert_eq!(Op::parse(code[0] | 10 << 7), None);
+
+    // Without a callback, it executes as a no-op.
+    let mut mem = TestMemory::new(&code);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = 0x8000_1000;
+    state.x[11] = 5;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    interp.step().unwrap();
+    assert_eq!(interp.state.pc, DRAM_BASE + 4);
+
+    // Operands in `x0` mean all addresses or all address spaces.
+    let flushes = Rc::new(RefCell::new(Vec::new()));
+    let recorded = Rc::clone(&flushes);
+    interp.state.pc = DRAM_BASE;
+    interp.set_on_sfence(move |vaddr, asid| recorded.borrow_mut().push((vaddr, asid)));
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(
+        *flushes.borrow(),
+        [
+            (None, None),
+            (Some(0x8000_1000), None),
+            (Some(0x8000_1000), Some(5))
+        ]
+    );
+
+    interp.clear_on_sfence();
+    interp.state.pc = DRAM_BASE;
+    assert_eq!(interp.run(), (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(flushes.borrow().len(), 3);
+}
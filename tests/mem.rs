@@ -0,0 +1,384 @@
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::mem::*;
+use rvsim::*;
+
+#[test]
+fn closure_memory() {
+    let code: &[u32] = &[
+        0x02a0_0513, // li a0, 42
+        0x10a0_2023, // sw a0, 256(zero)
+        0x1000_1583, // lh a1, 256(zero)
+        0x0010_0073, // ebreak
+    ];
+    let mut ram = code
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    ram.resize(0x200, 0);
+
+    let mut log = Vec::new();
+    let mut mem = ClosureMemory::new(|addr, bytes: &mut [u8], write| {
+        log.push((addr, bytes.len(), write));
+        let range = match ram.get_mut(addr as usize..addr as usize + bytes.len()) {
+            Some(range) => range,
+            None => return false,
+        };
+        if write {
+            range.copy_from_slice(bytes);
+        } else {
+            bytes.copy_from_slice(range);
+        }
+        true
+    });
+
+    let mut state = CpuState::new(0);
+    let mut clock = SimpleClock::new();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(state.x[11], 42);
+
+    // Running past the end of the closure-backed memory fails.
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res.0, CpuError::IllegalInstruction);
+    state.pc = 0x1000;
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(
+        res,
+        (
+            CpuError::IllegalFetch {
+                fell_through: false
+            },
+            None
+        )
+    );
+
+    assert_eq!(ram[0x100..0x104], 42u32.to_le_bytes());
+    assert!(log.contains(&(0x100, 4, true)));
+    assert!(log.contains(&(0x100, 2, false)));
+}
+
+#[test]
+fn no_execute() {
+    let code: &[u32] = &[
+        0x1000_0513, // li a0, 256
+        0x0005_2583, // lw a1, 0(a0)
+        0x0005_0067, // jr a0
+    ];
+    let mut dram = vec![0u8; 0x200];
+    for (i, word) in code.iter().enumerate() {
+        dram[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    dram[0x100..0x104].copy_from_slice(&0x0010_0073u32.to_le_bytes()); // ebreak
+    let mut mem = NoExecute::new(&mut dram[..], 0x100..0x200);
+
+    // Data can be loaded, but not executed.
+    let mut state = CpuState::new(0);
+    let mut clock = SimpleClock::new();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(
+        res,
+        (
+            CpuError::IllegalFetch {
+                fell_through: false
+            },
+            None
+        )
+    );
+    assert_eq!(state.pc, 0x100);
+    assert_eq!(state.x[11], 0x0010_0073);
+
+    // Without protection, the data executes.
+    mem.range = 0..0;
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+}
+
+#[test]
+fn poison_memory() {
+    let code: &[u32] = &[
+        0x1000_0513, // li a0, 256
+        0x00b5_10a3, // sh a1, 1(a0)
+        0x0005_2603, // lw a2, 0(a0)
+        0x0015_1683, // lh a3, 1(a0)
+        0x0010_0073, // ebreak
+    ];
+    let mut dram = vec![0u8; 0x200];
+    for (i, word) in code.iter().enumerate() {
+        dram[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let mut mem = PoisonMemory::new(&mut dram[..]);
+    mem.mark_initialized(0..0x100);
+
+    // The word load spans the stored halfword, flagging the bytes around it.
+    let mut state = CpuState::new(0);
+    state.x[11] = 0x1234;
+    let mut clock = SimpleClock::new();
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res, (CpuError::Ebreak, Some(Op::Ebreak)));
+    assert_eq!(state.x[12], 0x0012_3400);
+    assert_eq!(state.x[13], 0x1234);
+    assert_eq!(
+        mem.poisoned,
+        [PoisonedLoad {
+            addr: 0x100,
+            size: 4,
+            uninit: 0b1001,
+        }]
+    );
+
+    // When trapping, the load fails instead.
+    mem.reset();
+    mem.mark_initialized(0..0x100);
+    mem.trap = true;
+    let mut state = CpuState::new(0);
+    let res = Interp::new(&mut state, &mut mem, &mut clock).run();
+    assert_eq!(res.0, CpuError::IllegalAccess);
+    assert_eq!(state.pc, 12);
+    assert_eq!(mem.poisoned.len(), 1);
+}
+
+#[test]
+fn permissions() {
+    let data_perms = Perms {
+        exec: false,
+        ..Perms::ALL
+    };
+    let mut dram = vec![0u8; 0x200];
+    assert_eq!(dram[..].permissions(0x1ff), Some(Perms::ALL));
+    assert_eq!(dram[..].permissions(0x200), Some(Perms::NONE));
+
+    let mem = NoExecute::new(&mut dram[..], 0x100..0x200);
+    assert_eq!(mem.permissions(0xff), Some(Perms::ALL));
+    assert_eq!(mem.permissions(0x100), Some(data_perms));
+    assert_eq!(mem.permissions(0x200), Some(Perms::NONE));
+
+    let mem = CowMemory::new(ToHost::new(mem, 0x100));
+    assert_eq!(mem.permissions(0x100), Some(data_perms));
+
+    // Permissions are unknown by default.
+    let mem = ClosureMemory::new(|_, _, _| true);
+    assert_eq!(mem.permissions(0), None);
+}
+
+#[test]
+fn signature_dump() {
+    let code = [
+        0x0110_0293, // li t0, 0x11
+        0x1055_2023, // sw t0, 0x100(a0)
+        0xfff0_0293, // li t0, -1
+        0x1055_2223, // sw t0, 0x104(a0)
+        0x0010_0293, // li t0, 1
+        0x2055_2023, // sw t0, 0x200(a0)
+        0x0000_006f, // 1: j 1b
+    ];
+    let tohost = DRAM_BASE + 0x200;
+    let mut mem = ToHost::new(TestMemory::new(&code), tohost);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[10] = DRAM_BASE;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    while interp.memory().value.is_none() {
+        interp.step().unwrap();
+    }
+    assert_eq!(interp.memory().value, Some(1));
+    assert_eq!(interp.state.pc, DRAM_BASE + 24);
+
+    let mut out = Vec::new();
+    dump_signature(&mut mem, DRAM_BASE + 0x100..DRAM_BASE + 0x10c, &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "00000011\nffffffff\n00000000\n"
+    );
+
+    // Reading outside memory fails.
+    let res = dump_signature(&mut mem, 0..4, &mut Vec::new());
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        "failed to read signature at 0x0"
+    );
+}
+
+/// Check loading and storing `value` at an unaligned offset in a `[u8]` uses exactly `bytes`.
+fn check_byte_access<T: MemoryValue + PartialEq + std::fmt::Debug>(value: T, bytes: &[u8]) {
+    let mut mem = [0xaau8; 12];
+    mem[3..3 + bytes.len()].copy_from_slice(bytes);
+    for exec in [false, true] {
+        let mut loaded = T::from_le_slice(&[0; 8][..T::SIZE]);
+        let access = if exec {
+            MemoryAccess::Exec(&mut loaded)
+        } else {
+            MemoryAccess::Load(&mut loaded)
+        };
+        let expected = mem;
+        assert!(Memory::access(&mut mem[..], 3, access));
+        assert_eq!(loaded, value);
+        assert_eq!(mem, expected);
+    }
+
+    let mut mem = [0xaau8; 12];
+    assert!(Memory::access(&mut mem[..], 3, MemoryAccess::Store(value)));
+    assert_eq!(&mem[3..3 + bytes.len()], bytes);
+    assert!(mem[..3]
+        .iter()
+        .chain(&mem[3 + bytes.len()..])
+        .all(|&b| b == 0xaa));
+
+    // Accesses that do not fit are rejected without modifying memory.
+    let end = (mem.len() - bytes.len() + 1) as u32;
+    let before = mem;
+    assert!(!Memory::access(
+        &mut mem[..],
+        end,
+        MemoryAccess::Store(value)
+    ));
+    assert!(!Memory::access(
+        &mut mem[..],
+        u32::MAX,
+        MemoryAccess::Store(value)
+    ));
+    assert_eq!(mem, before);
+}
+
+#[test]
+fn byte_array_access_widths() {
+    check_byte_access(0x81u8, &[0x81]);
+    check_byte_access(-2i8, &[0xfe]);
+    check_byte_access(0x8102u16, &[0x02, 0x81]);
+    check_byte_access(-2i16, &[0xfe, 0xff]);
+    check_byte_access(0x8102_0304u32, &[0x04, 0x03, 0x02, 0x81]);
+    check_byte_access(-2i32, &[0xfe, 0xff, 0xff, 0xff]);
+    check_byte_access(
+        0x8102_0304_0506_0708u64,
+        &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x81],
+    );
+    check_byte_access(-2i64, &[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+}
+
+#[test]
+fn block_access() {
+    let mut dram = vec![0u8; 0x100];
+    dram[0x10..0x18].copy_from_slice(b"dma data");
+
+    // Copy between two regions, as a DMA engine would.
+    let mut buf = [0u8; 8];
+    assert!(dram[..].access_block(0x10, &mut buf, false));
+    assert!(dram[..].access_block(0x80, &mut buf, true));
+    assert_eq!(&dram[0x80..0x88], b"dma data");
+    assert!(!dram[..].access_block(0xfc, &mut buf, false));
+
+    // The default implementation goes through byte accesses, so the overlay sees the write.
+    let mut mem = CowMemory::with_page_size(&mut dram[..], 16);
+    let mut buf = *b"overlay!";
+    assert!(mem.access_block(0x10, &mut buf, true));
+    let mut read = [0u8; 8];
+    assert!(mem.access_block(0x10, &mut read, false));
+    assert_eq!(&read, b"overlay!");
+    assert!(!mem.access_block(0xfc, &mut read, true));
+    mem.rollback();
+    assert_eq!(&dram[0x10..0x18], b"dma data");
+
+    // Closure memory receives the whole block at once.
+    let mut calls = Vec::new();
+    let mut mem = ClosureMemory::new(|addr, bytes: &mut [u8], write| {
+        calls.push((addr, bytes.len(), write));
+        true
+    });
+    assert!(mem.access_block(0x1000, &mut [0u8; 64], true));
+    assert_eq!(calls, [(0x1000, 64, true)]);
+}
+
+#[test]
+fn cow_memory() {
+    for &page_size in &[1, 16, CowMemory::<&mut [u8]>::DEFAULT_PAGE_SIZE] {
+        let mut dram = vec![0u8; 0x2000];
+        dram[0x100..0x104].copy_from_slice(&0x1122_3344u32.to_le_bytes());
+        let mut mem = CowMemory::with_page_size(&mut dram[..], page_size);
+        assert_eq!(mem.page_size(), page_size);
+
+        // Stores are visible through the wrapper, including partially overlapping loads.
+        assert!(mem.access(0x101, MemoryAccess::Store(0xaau8)));
+        assert!(mem.access(0x1ffe, MemoryAccess::Store(0xbbccu16)));
+        let mut word = 0u32;
+        assert!(mem.access(0x100, MemoryAccess::Load(&mut word)));
+        assert_eq!(word, 0x1122_aa44);
+        let mut half = 0u16;
+        assert!(mem.access(0x1ffe, MemoryAccess::Exec(&mut half)));
+        assert_eq!(half, 0xbbcc);
+        assert!(mem.dirty_pages() >= 2);
+
+        // Stores that do not fit in the inner memory fail.
+        assert!(!mem.access(0x1fff, MemoryAccess::Store(0u16)));
+
+        // Rolling back discards the stores.
+        mem.rollback();
+        assert_eq!(mem.dirty_pages(), 0);
+        assert!(mem.access(0x100, MemoryAccess::Load(&mut word)));
+        assert_eq!(word, 0x1122_3344);
+
+        // A checkpoint commits the stores to the inner memory.
+        assert!(mem.access(0x102, MemoryAccess::Store(0x55u8)));
+        assert!(mem.checkpoint());
+        assert!(mem.access(0x103, MemoryAccess::Store(0x66u8)));
+        mem.rollback();
+        assert_eq!(dram[0x100..0x104], 0x1155_3344u32.to_le_bytes());
+    }
+}
+
+#[test]
+fn cow_memory_dirty_page_data() {
+    let mut dram = vec![0u8; 0x400];
+    let mut mem = CowMemory::with_page_size(&mut dram[..], 0x100);
+    assert!(mem.access(0x304, MemoryAccess::Store(0x11u8)));
+    assert!(mem.access(0x1ff, MemoryAccess::Store(0x2233u16)));
+
+    // Pages are listed by address, including both pages of a straddling store.
+    let saved = mem
+        .dirty_page_data()
+        .map(|(base, data)| (base, data.to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        saved.iter().map(|&(base, _)| base).collect::<Vec<_>>(),
+        [0x100, 0x200, 0x300]
+    );
+    assert_eq!(saved[0].1[0xff], 0x33);
+    assert_eq!(saved[1].1[0], 0x22);
+    assert_eq!(saved[2].1[4], 0x11);
+
+    // Reloading the pages after a rollback restores the contents.
+    mem.rollback();
+    mem.load_pages(saved.iter().map(|(base, data)| (*base, &data[..])));
+    assert_eq!(mem.dirty_pages(), 3);
+    let mut half = 0u16;
+    assert!(mem.access(0x1ff, MemoryAccess::Load(&mut half)));
+    assert_eq!(half, 0x2233);
+    assert!(mem.checkpoint());
+    assert_eq!(dram[0x304], 0x11);
+}
+
+#[test]
+fn cow_memory_rerun() {
+    // Rerun a program that modifies memory, restoring memory and state each time.
+    let mut base = TestMemory::new(&[
+        0x0005_a503, // lw a0, 0(a1)
+        0x0015_0513, // addi a0, a0, 1
+        0x00a5_a023, // sw a0, 0(a1)
+        0x0010_0073, // ebreak
+    ]);
+    let mut mem = CowMemory::new(&mut base);
+    let mut state = CpuState::new(DRAM_BASE);
+    state.x[11] = DRAM_BASE + 0x100;
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    let snapshot = interp.snapshot();
+    for _ in 0..3 {
+        assert_eq!(interp.run().0, CpuError::Ebreak);
+        assert_eq!(interp.state.x[10], 1);
+        interp.memory_mut().rollback();
+        interp.restore(&snapshot);
+    }
+    assert_eq!(base.dram[0x100], 0);
+}
@@ -0,0 +1,79 @@
+extern crate rvsim;
+
+mod common;
+
+use common::*;
+use rvsim::asm::AsmBuilder;
+use rvsim::proxy::*;
+use rvsim::*;
+
+const MESSAGE_ADDR: u32 = DRAM_BASE + 0x100;
+
+/// Run a program under the proxy kernel, returning the exit code and output.
+fn run_proxied(abi: AbiKind, asm: &AsmBuilder) -> (Result<i32, CpuError>, Vec<u8>) {
+    let mut mem = TestMemory::new(&[]);
+    mem.write(DRAM_BASE, &asm.build().unwrap());
+    mem.write(MESSAGE_ADDR, b"hello\n");
+    let mut state = CpuState::new(DRAM_BASE);
+    let mut clock = SimpleClock::new();
+    let mut interp = Interp::new(&mut state, &mut mem, &mut clock);
+    let mut kernel = ProxyKernel::new(abi, Vec::new());
+    let res = kernel.run(&mut interp).map_err(|(err, _)| err);
+    (res, kernel.out)
+}
+
+/// A program that writes the message, then exits with the result of the write plus one.
+fn write_exit(exit: i32) -> AsmBuilder {
+    let mut asm = AsmBuilder::new();
+    asm.li(10, 1) // stdout
+        .li(11, MESSAGE_ADDR as i32)
+        .li(12, 6)
+        .li(17, 64) // write
+        .ecall()
+        .addi(10, 10, 1)
+        .li(17, exit)
+        .ecall()
+        .ebreak();
+    asm
+}
+
+#[test]
+fn write_exit_newlib() {
+    let (res, out) = run_proxied(AbiKind::Newlib, &write_exit(93));
+    assert_eq!(res, Ok(7));
+    assert_eq!(out, b"hello\n");
+}
+
+#[test]
+fn write_exit_linux() {
+    for exit in [93, 94] {
+        let (res, out) = run_proxied(AbiKind::Linux, &write_exit(exit));
+        assert_eq!(res, Ok(7));
+        assert_eq!(out, b"hello\n");
+    }
+}
+
+#[test]
+fn unsupported_syscalls() {
+    // Newlib has no `exit_group`, so the program continues to the `ebreak`.
+    let (res, _) = run_proxied(AbiKind::Newlib, &write_exit(94));
+    assert_eq!(res, Err(CpuError::Ebreak));
+
+    let mut asm = AsmBuilder::new();
+    asm.li(10, 3) // bad file descriptor
+        .li(17, 64)
+        .ecall()
+        .li(17, 93) // exit with the result
+        .ecall();
+    let (res, out) = run_proxied(AbiKind::Linux, &asm);
+    assert_eq!(res, Ok(-EBADF));
+    assert!(out.is_empty());
+
+    let mut asm = AsmBuilder::new();
+    asm.li(17, 1024) // open
+        .ecall()
+        .li(17, 93) // exit with the result
+        .ecall();
+    let (res, _) = run_proxied(AbiKind::Linux, &asm);
+    assert_eq!(res, Ok(-ENOSYS));
+}